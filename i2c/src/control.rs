@@ -0,0 +1,430 @@
+// Runtime control socket
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use log::{info, warn};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error as ThisError;
+use virtio_i2c::i2c::{I2cDevice, I2cMap};
+
+use crate::fault::{self, FaultConfig};
+use crate::peer::{peer_credentials, PeerPolicy};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the control socket
+pub enum Error {
+    #[error("Failed to create control socket at {0}")]
+    SocketCreateFailed(String),
+}
+
+/// Mode of the control socket, only its owner can connect.
+const SOCKET_MODE: u32 = 0o600;
+
+/// Requests accepted on the control socket, one JSON object per line, e.g.:
+///
+///   {"cmd": "add-client", "adapter": 1, "addr": 32}
+///
+/// Every request is answered with a single line JSON object, with "ok" set
+/// to true on success, or set to false with a description in "error".
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "cmd", rename_all = "kebab-case", deny_unknown_fields)]
+enum Request {
+    List,
+    AddClient { adapter: u32, addr: u16 },
+    RemoveClient { addr: u16 },
+    EnableClient { addr: u16 },
+    DisableClient { addr: u16 },
+    Counters,
+    SetFaults { addr: u16, faults: String },
+    ClearFaults { addr: u16 },
+    EnableFaults { addr: u16 },
+    DisableFaults { addr: u16 },
+}
+
+/// Serves requests from the control socket, to reconfigure the I2C map
+/// without restarting the daemon.
+pub struct ControlServer<D: I2cDevice> {
+    listener: UnixListener,
+    i2c_map: Arc<I2cMap<D>>,
+    // Peers allowed to send requests.
+    peers: PeerPolicy,
+    // Whether the devices inject faults, i.e. are wrapped in FaultDevice.
+    faults: bool,
+}
+
+impl<D: I2cDevice> ControlServer<D> {
+    /// Creates the control socket at `path`. Only the peers matching `peers` are served, or
+    /// the user of the daemon and root if there are no rules.
+    pub fn new(
+        path: &str,
+        i2c_map: Arc<I2cMap<D>>,
+        peers: PeerPolicy,
+        faults: bool,
+    ) -> Result<Self> {
+        // Remove a stale socket, left behind by a previous instance.
+        let _ = fs::remove_file(path);
+        let listener =
+            UnixListener::bind(path).map_err(|_| Error::SocketCreateFailed(path.to_string()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE))
+            .map_err(|_| Error::SocketCreateFailed(path.to_string()))?;
+
+        let peers = if peers.is_empty() {
+            let mut peers = PeerPolicy::default();
+
+            // SAFETY: Safe as geteuid() doesn't access memory.
+            for uid in [unsafe { libc::geteuid() }, 0].iter() {
+                peers.push(&format!("uid={}", uid)).unwrap();
+            }
+            peers
+        } else {
+            peers
+        };
+
+        info!("Listening for control requests at {}", path);
+        Ok(ControlServer {
+            listener,
+            i2c_map,
+            peers,
+            faults,
+        })
+    }
+
+    /// Serves the connections one by one, forever.
+    pub fn run(&self) {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => match peer_credentials(&stream) {
+                    Ok(cred) if self.peers.allows(&cred) => {
+                        if let Err(e) = self.serve(stream) {
+                            warn!("Control connection failed: {}", e);
+                        }
+                    }
+                    Ok(cred) => warn!(
+                        "Rejected control peer: pid {}, uid {}, gid {}",
+                        cred.pid, cred.uid, cred.gid
+                    ),
+                    Err(e) => warn!("Rejected control peer, no credentials: {}", e),
+                },
+                Err(e) => warn!("Failed to accept control connection: {}", e),
+            }
+        }
+    }
+
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        for line in reader.lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            writeln!(writer, "{}", self.handle(&line))?;
+        }
+        Ok(())
+    }
+
+    /// Handles a single request and returns the response for it.
+    fn handle(&self, request: &str) -> Value {
+        let result = match serde_json::from_str::<Request>(request) {
+            Ok(request) => self.execute(request),
+            Err(e) => Err(format!("Invalid request: {}", e)),
+        };
+
+        match result {
+            Ok(Value::Object(mut response)) => {
+                response.insert("ok".to_string(), Value::Bool(true));
+                Value::Object(response)
+            }
+            Ok(_) => json!({ "ok": true }),
+            Err(e) => {
+                warn!("Control request failed: {}", e);
+                json!({ "ok": false, "error": e })
+            }
+        }
+    }
+
+    fn execute(&self, request: Request) -> std::result::Result<Value, String> {
+        info!("Control request: {:?}", request);
+
+        match request {
+            Request::List => {
+                let clients = self.i2c_map.clients();
+                let adapters: Vec<Value> = self
+                    .i2c_map
+                    .adapters()
+                    .iter()
+                    .map(|(adapter_no, smbus)| {
+                        let clients: Vec<Value> = clients
+                            .iter()
+                            .filter(|client| client.adapter_no == *adapter_no)
+                            .map(|client| json!({ "addr": client.addr, "enabled": client.enabled }))
+                            .collect();
+
+                        json!({ "adapter": adapter_no, "smbus": smbus, "clients": clients })
+                    })
+                    .collect();
+
+                Ok(json!({ "adapters": adapters }))
+            }
+
+            Request::AddClient { adapter, addr } => self
+                .i2c_map
+                .add_client(adapter, addr)
+                .map(|_| Value::Null)
+                .map_err(|e| e.to_string()),
+
+            Request::RemoveClient { addr } => self
+                .i2c_map
+                .remove_client(addr)
+                .map(|_| Value::Null)
+                .map_err(|e| e.to_string()),
+
+            Request::EnableClient { addr } | Request::DisableClient { addr } => {
+                let enable = matches!(request, Request::EnableClient { .. });
+
+                self.i2c_map
+                    .enable_client(addr, enable)
+                    .map(|_| Value::Null)
+                    .map_err(|e| e.to_string())
+            }
+
+            Request::Counters => {
+                let counters: Vec<Value> = self
+                    .i2c_map
+                    .clients()
+                    .iter()
+                    .map(|client| {
+                        let mut counters = json!({
+                            "addr": client.addr,
+                            "adapter": client.adapter_no,
+                            "transfers": client.transfers,
+                            "errors": client.errors,
+                        });
+
+                        if let Some(stats) = fault::fault_stats(client.addr) {
+                            counters["faults_injected"] = json!(stats.injected);
+                        }
+                        counters
+                    })
+                    .collect();
+
+                Ok(json!({ "counters": counters }))
+            }
+
+            // The faults are only injected by the devices wrapped at startup.
+            Request::SetFaults { .. }
+            | Request::EnableFaults { .. }
+            | Request::DisableFaults { .. }
+                if !self.faults =>
+            {
+                Err("Fault injection requires --fault-list".to_string())
+            }
+
+            Request::SetFaults { addr, faults } => {
+                let config = FaultConfig::try_from(faults.as_str()).map_err(|e| e.to_string())?;
+
+                fault::set_faults(addr, config);
+                Ok(Value::Null)
+            }
+
+            Request::ClearFaults { addr } => {
+                if fault::clear_faults(addr) {
+                    Ok(Value::Null)
+                } else {
+                    Err(format!("No faults configured for client: {}", addr))
+                }
+            }
+
+            Request::EnableFaults { addr } | Request::DisableFaults { addr } => {
+                let enable = matches!(request, Request::EnableFaults { .. });
+
+                if fault::enable_faults(addr, enable) {
+                    Ok(Value::Null)
+                } else {
+                    Err(format!("No faults configured for client: {}", addr))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use vmm_sys_util::tempdir::TempDir;
+
+    use virtio_i2c::config::AdapterConfig;
+    use virtio_i2c::i2c::mock::DummyDevice;
+
+    fn control_server(path: &str, peers: PeerPolicy, faults: bool) -> ControlServer<DummyDevice> {
+        let adapter_config = AdapterConfig::try_from("1:4,2:32:21").unwrap();
+        let i2c_map = I2cMap::new(&adapter_config).unwrap();
+
+        ControlServer::new(path, Arc::new(i2c_map), peers, faults).unwrap()
+    }
+
+    #[test]
+    fn test_control_requests() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-control").unwrap();
+        let path = dir.as_path().join("control.sock");
+        let server = control_server(path.to_str().unwrap(), PeerPolicy::default(), true);
+
+        let response = server.handle(r#"{"cmd": "list"}"#);
+        assert_eq!(
+            response,
+            json!({
+                "ok": true,
+                "adapters": [
+                    { "adapter": 1, "smbus": false, "clients": [{ "addr": 4, "enabled": true }] },
+                    {
+                        "adapter": 2,
+                        "smbus": false,
+                        "clients": [
+                            { "addr": 21, "enabled": true },
+                            { "addr": 32, "enabled": true },
+                        ]
+                    },
+                ]
+            })
+        );
+
+        let response = server.handle(r#"{"cmd": "add-client", "adapter": 1, "addr": 5}"#);
+        assert_eq!(response, json!({ "ok": true }));
+
+        // Same validation as the device list
+        let response = server.handle(r#"{"cmd": "add-client", "adapter": 2, "addr": 5}"#);
+        assert_eq!(response["ok"], json!(false));
+        let response = server.handle(r#"{"cmd": "add-client", "adapter": 1, "addr": 200}"#);
+        assert_eq!(response["ok"], json!(false));
+        let response = server.handle(r#"{"cmd": "add-client", "adapter": 3, "addr": 6}"#);
+        assert_eq!(response["ok"], json!(false));
+
+        let response = server.handle(r#"{"cmd": "disable-client", "addr": 5}"#);
+        assert_eq!(response, json!({ "ok": true }));
+        let response = server.handle(r#"{"cmd": "enable-client", "addr": 5}"#);
+        assert_eq!(response, json!({ "ok": true }));
+        let response = server.handle(r#"{"cmd": "remove-client", "addr": 5}"#);
+        assert_eq!(response, json!({ "ok": true }));
+        let response = server.handle(r#"{"cmd": "remove-client", "addr": 5}"#);
+        assert_eq!(response["ok"], json!(false));
+
+        let response = server.handle(r#"{"cmd": "counters"}"#);
+        assert_eq!(response["counters"][0]["addr"], json!(4));
+        assert_eq!(response["counters"][0]["transfers"], json!(0));
+
+        let response = server.handle(r#"{"cmd": "set-faults", "addr": 21, "faults": "nth=1"}"#);
+        assert_eq!(response, json!({ "ok": true }));
+        let response = server.handle(r#"{"cmd": "counters"}"#);
+        assert_eq!(response["counters"][1]["faults_injected"], json!(0));
+        let response = server.handle(r#"{"cmd": "set-faults", "addr": 21, "faults": "nth"}"#);
+        assert_eq!(response["ok"], json!(false));
+        let response = server.handle(r#"{"cmd": "disable-faults", "addr": 21}"#);
+        assert_eq!(response, json!({ "ok": true }));
+        let response = server.handle(r#"{"cmd": "enable-faults", "addr": 21}"#);
+        assert_eq!(response, json!({ "ok": true }));
+        let response = server.handle(r#"{"cmd": "clear-faults", "addr": 21}"#);
+        assert_eq!(response, json!({ "ok": true }));
+        let response = server.handle(r#"{"cmd": "clear-faults", "addr": 21}"#);
+        assert_eq!(response["ok"], json!(false));
+        let response = server.handle(r#"{"cmd": "enable-faults", "addr": 21}"#);
+        assert_eq!(response["ok"], json!(false));
+
+        // The devices don't inject faults without --fault-list.
+        let path = dir.as_path().join("control-nofaults.sock");
+        let server = control_server(path.to_str().unwrap(), PeerPolicy::default(), false);
+        let response = server.handle(r#"{"cmd": "set-faults", "addr": 21, "faults": "nth=1"}"#);
+        assert_eq!(
+            response,
+            json!({ "ok": false, "error": "Fault injection requires --fault-list" })
+        );
+        let response = server.handle(r#"{"cmd": "disable-faults", "addr": 21}"#);
+        assert_eq!(response["ok"], json!(false));
+
+        // Invalid requests
+        let response = server.handle(r#"{"cmd": "reboot"}"#);
+        assert_eq!(response["ok"], json!(false));
+        let response = server.handle("list");
+        assert_eq!(response["ok"], json!(false));
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-control").unwrap();
+        let path = dir.as_path().join("control.sock");
+        let server = control_server(path.to_str().unwrap(), PeerPolicy::default(), false);
+
+        // Only the owner can connect.
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SOCKET_MODE);
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let client = stream.try_clone().unwrap();
+        std::thread::spawn(move || server.run());
+
+        let mut writer = stream;
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+
+        writeln!(writer, r#"{{"cmd": "disable-client", "addr": 4}}"#).unwrap();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&line).unwrap(),
+            json!({ "ok": true })
+        );
+
+        line.clear();
+        // Empty lines are ignored
+        writeln!(writer).unwrap();
+        writeln!(writer, r#"{{"cmd": "list"}}"#).unwrap();
+        reader.read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            response["adapters"][0]["clients"][0]["enabled"],
+            json!(false)
+        );
+
+        // Socket creation failure
+        let adapter_config = AdapterConfig::try_from("1:4").unwrap();
+        let i2c_map = I2cMap::<DummyDevice>::new(&adapter_config).unwrap();
+        let path = "/path/not/present/control.sock";
+        assert_eq!(
+            ControlServer::new(path, Arc::new(i2c_map), PeerPolicy::default(), false).err(),
+            Some(Error::SocketCreateFailed(path.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_control_peers() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-control").unwrap();
+        let path = dir.as_path().join("control.sock");
+        // SAFETY: Safe as getuid() doesn't access memory.
+        let other = unsafe { libc::getuid() } + 1;
+        let policy = PeerPolicy::try_from(vec![format!("uid={}", other).as_str()]).unwrap();
+        let server = control_server(path.to_str().unwrap(), policy, false);
+
+        std::thread::spawn(move || server.run());
+
+        // The connection is closed, or reset, without serving the request.
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let _ = writeln!(stream, r#"{{"cmd": "disable-client", "addr": 4}}"#);
+        let mut line = String::new();
+        assert_eq!(BufReader::new(stream).read_line(&mut line).unwrap_or(0), 0);
+    }
+}
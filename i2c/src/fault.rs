@@ -0,0 +1,598 @@
+// Fault injection for I2C clients
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use log::info;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+use thiserror::Error as ThisError;
+use virtio_i2c::i2c::{Error as I2cError, I2cDevice, I2cReq, SmbusMsg, I2C_M_RD, MAX_I2C_VDEV};
+use vmm_sys_util::errno::Error as IoError;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to fault injection configuration
+pub enum Error {
+    #[error("Invalid fault specification: {0}")]
+    FaultInvalid(String),
+    #[error("Invalid client address: {0}")]
+    ClientAddressInvalid(u16),
+    #[error("Duplicate client address detected: {0}")]
+    ClientAddressDuplicate(u16),
+    #[error("Failed while parsing fault list: {0}")]
+    ParseFailure(String),
+}
+
+/// Faults injected in the transfers to a single client.
+///
+/// The faults are described in the format <fault>[:<fault>], where each fault
+/// is one of:
+///
+///   nth=N          Fail the Nth transfer (counted from 1) to the client.
+///   prob=P         Fail transfers randomly, with probability P (0.0 - 1.0).
+///   seed=S         Seed for the random generator, for reproducible runs.
+///   delay=MS       Add MS milliseconds of latency to every transfer.
+///   flip=P         Flip a random bit of the read data, with probability P.
+///   errno=E        Error returned for failed transfers: enxio (no ACK for
+///                  the address), eagain (arbitration lost), etimedout,
+///                  eremoteio (no ACK for data, default) or a number.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultConfig {
+    nth: Option<u64>,
+    probability: f64,
+    seed: u64,
+    delay: Duration,
+    flip: f64,
+    errno: i32,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            nth: None,
+            probability: 0.0,
+            seed: 1,
+            delay: Duration::from_millis(0),
+            flip: 0.0,
+            errno: libc::EREMOTEIO,
+        }
+    }
+}
+
+fn parse_probability(val: &str) -> Result<f64> {
+    let prob = val
+        .parse::<f64>()
+        .map_err(|e| Error::ParseFailure(e.to_string()))?;
+
+    if !(0.0..=1.0).contains(&prob) {
+        return Err(Error::FaultInvalid(format!("probability {}", val)));
+    }
+    Ok(prob)
+}
+
+fn parse_u64(val: &str) -> Result<u64> {
+    val.parse::<u64>()
+        .map_err(|e| Error::ParseFailure(e.to_string()))
+}
+
+fn parse_errno(val: &str) -> Result<i32> {
+    match val.to_lowercase().as_str() {
+        "enxio" => Ok(libc::ENXIO),
+        "eagain" => Ok(libc::EAGAIN),
+        "etimedout" => Ok(libc::ETIMEDOUT),
+        "eremoteio" => Ok(libc::EREMOTEIO),
+        _ => match val.parse::<i32>() {
+            Ok(errno) if errno > 0 => Ok(errno),
+            _ => Err(Error::FaultInvalid(format!("errno={}", val))),
+        },
+    }
+}
+
+impl FaultConfig {
+    fn set(&mut self, fault: &str) -> Result<()> {
+        let (key, val) = match fault.find('=') {
+            Some(pos) => (&fault[..pos], &fault[pos + 1..]),
+            None => return Err(Error::FaultInvalid(fault.to_string())),
+        };
+
+        match key {
+            "nth" => match parse_u64(val)? {
+                0 => return Err(Error::FaultInvalid(fault.to_string())),
+                nth => self.nth = Some(nth),
+            },
+            "prob" => self.probability = parse_probability(val)?,
+            "seed" => self.seed = parse_u64(val)?,
+            "delay" => self.delay = Duration::from_millis(parse_u64(val)?),
+            "flip" => self.flip = parse_probability(val)?,
+            "errno" => self.errno = parse_errno(val)?,
+            _ => return Err(Error::FaultInvalid(fault.to_string())),
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&str> for FaultConfig {
+    type Error = Error;
+
+    fn try_from(faults: &str) -> Result<Self> {
+        let mut config = FaultConfig::default();
+
+        for fault in faults.split(':') {
+            config.set(fault)?;
+        }
+        Ok(config)
+    }
+}
+
+/// List of clients and the faults to inject in their transfers, in format
+/// <client_addr>:<fault>[:<fault>][,<client_addr>:<fault>[:<fault>]]
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct FaultList {
+    inner: Vec<(u16, FaultConfig)>,
+}
+
+impl TryFrom<&str> for FaultList {
+    type Error = Error;
+
+    fn try_from(list: &str) -> Result<Self> {
+        let mut faults = FaultList::default();
+
+        for client in list.split(',') {
+            let (addr, config) = match client.find(':') {
+                Some(pos) => (&client[..pos], &client[pos + 1..]),
+                None => return Err(Error::FaultInvalid(client.to_string())),
+            };
+
+            let addr = addr
+                .parse::<u16>()
+                .map_err(|e| Error::ParseFailure(e.to_string()))?;
+
+            if addr as usize > MAX_I2C_VDEV {
+                return Err(Error::ClientAddressInvalid(addr));
+            }
+
+            if faults.inner.iter().any(|(a, _)| *a == addr) {
+                return Err(Error::ClientAddressDuplicate(addr));
+            }
+
+            faults.inner.push((addr, FaultConfig::try_from(config)?));
+        }
+        Ok(faults)
+    }
+}
+
+impl FaultList {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Installs all the faults of the list.
+    pub(crate) fn register(&self) {
+        for (addr, config) in self.inner.iter() {
+            set_faults(*addr, config.clone());
+        }
+    }
+}
+
+/// Small xorshift64* generator, good enough to pick faults reproducibly.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift never leaves the all zero state.
+        Rng(if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Returns true with the given probability.
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+/// Runtime state of the faults configured for a client.
+#[derive(Debug)]
+struct ClientFaults {
+    config: FaultConfig,
+    enabled: bool,
+    transfers: u64,
+    injected: u64,
+    rng: Rng,
+}
+
+impl ClientFaults {
+    fn new(config: FaultConfig) -> Self {
+        ClientFaults {
+            rng: Rng::new(config.seed),
+            config,
+            enabled: true,
+            transfers: 0,
+            injected: 0,
+        }
+    }
+
+    // Accounts for a new transfer, returns the latency to add and the errno
+    // to fail the transfer with.
+    fn start_transfer(&mut self) -> (Duration, Option<i32>) {
+        if !self.enabled {
+            return (Duration::from_millis(0), None);
+        }
+
+        self.transfers += 1;
+
+        let fail =
+            self.config.nth == Some(self.transfers) || self.rng.chance(self.config.probability);
+
+        if fail {
+            self.injected += 1;
+            (self.config.delay, Some(self.config.errno))
+        } else {
+            (self.config.delay, None)
+        }
+    }
+
+    // Returns the bit to flip in read data of `bits` bits, if any.
+    fn corrupt(&mut self, bits: usize) -> Option<usize> {
+        if !self.enabled || bits == 0 || !self.rng.chance(self.config.flip) {
+            return None;
+        }
+
+        self.injected += 1;
+        Some((self.rng.next() % bits as u64) as usize)
+    }
+}
+
+/// Statistics of the faults configured for a client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultStats {
+    pub config: FaultConfig,
+    pub enabled: bool,
+    pub transfers: u64,
+    pub injected: u64,
+}
+
+// Faults are configured per client address, which are unique across all the
+// adapters. They are kept outside of the devices, so they can be updated at
+// runtime without having access to the I2cMap.
+static CLIENTS: Mutex<BTreeMap<u16, ClientFaults>> = Mutex::new(BTreeMap::new());
+
+/// Installs (or replaces) the faults injected in the transfers to `addr`.
+pub fn set_faults(addr: u16, config: FaultConfig) {
+    info!("Injecting faults for client {}: {:?}", addr, config);
+    CLIENTS
+        .lock()
+        .unwrap()
+        .insert(addr, ClientFaults::new(config));
+}
+
+/// Removes the faults configured for `addr`, returns false if there were
+/// none.
+pub fn clear_faults(addr: u16) -> bool {
+    CLIENTS.lock().unwrap().remove(&addr).is_some()
+}
+
+/// Enables or disables the faults configured for `addr`, returns false if
+/// there are none.
+pub fn enable_faults(addr: u16, enable: bool) -> bool {
+    match CLIENTS.lock().unwrap().get_mut(&addr) {
+        Some(client) => {
+            client.enabled = enable;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the statistics of the faults configured for `addr`.
+pub fn fault_stats(addr: u16) -> Option<FaultStats> {
+    CLIENTS.lock().unwrap().get(&addr).map(|client| FaultStats {
+        config: client.config.clone(),
+        enabled: client.enabled,
+        transfers: client.transfers,
+        injected: client.injected,
+    })
+}
+
+// Accounts for a new transfer to `addr` and fails it if required.
+fn start_transfer(addr: u16, op: &'static str) -> std::result::Result<(), I2cError> {
+    let (delay, errno) = match CLIENTS.lock().unwrap().get_mut(&addr) {
+        Some(client) => client.start_transfer(),
+        None => return Ok(()),
+    };
+
+    // Don't hold the lock while sleeping, other clients shouldn't be delayed.
+    if delay.as_millis() != 0 {
+        sleep(delay);
+    }
+
+    match errno {
+        Some(errno) => Err(I2cError::IoctlFailure(op, IoError::new(errno))),
+        None => Ok(()),
+    }
+}
+
+fn corrupt(addr: u16, bits: usize) -> Option<usize> {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .get_mut(&addr)
+        .and_then(|client| client.corrupt(bits))
+}
+
+/// An I2C device wrapper, which injects faults in the transfers to the
+/// clients configured with `set_faults()`. Transfers to other clients are
+/// passed unmodified to the underlying device.
+#[derive(Debug)]
+pub struct FaultDevice<D: I2cDevice> {
+    device: D,
+    // Client address set by the last I2C_SLAVE call, used by SMBus transfers.
+    addr: AtomicU16,
+}
+
+impl<D: I2cDevice> I2cDevice for FaultDevice<D> {
+    fn open(device_path: &str, adapter_no: u32) -> std::result::Result<Self, I2cError> {
+        Ok(FaultDevice {
+            device: D::open(device_path, adapter_no)?,
+            addr: AtomicU16::new(0),
+        })
+    }
+
+    fn from_file(file: File, adapter_no: u32) -> std::result::Result<Self, I2cError> {
+        Ok(FaultDevice {
+            device: D::from_file(file, adapter_no)?,
+            addr: AtomicU16::new(0),
+        })
+    }
+
+    fn funcs(&mut self) -> std::result::Result<u64, I2cError> {
+        self.device.funcs()
+    }
+
+    fn rdwr(&self, reqs: &mut [I2cReq]) -> std::result::Result<(), I2cError> {
+        let addr = match reqs.first() {
+            Some(req) => req.addr,
+            None => return self.device.rdwr(reqs),
+        };
+
+        start_transfer(addr, "rdwr")?;
+        self.device.rdwr(reqs)?;
+
+        for req in reqs.iter_mut().filter(|req| (req.flags & I2C_M_RD) != 0) {
+            if let Some(bit) = corrupt(addr, req.buf.len() * 8) {
+                let byte = req.buf.read_byte(bit / 8);
+                req.buf.write_byte(bit / 8, byte ^ (1 << (bit % 8)));
+            }
+        }
+        Ok(())
+    }
+
+    fn smbus(&self, msg: &mut SmbusMsg) -> std::result::Result<(), I2cError> {
+        let addr = self.addr.load(Ordering::Relaxed);
+
+        start_transfer(addr, "smbus")?;
+        self.device.smbus(msg)?;
+
+        if msg.is_read() {
+            if let Some(bit) = corrupt(addr, msg.data_len() * 8) {
+                msg.flip_data_bit(bit);
+            }
+        }
+        Ok(())
+    }
+
+    fn slave(&self, addr: u64) -> std::result::Result<(), I2cError> {
+        self.device.slave(addr)?;
+        self.addr.store(addr as u16, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn adapter_no(&self) -> u32 {
+        self.device.adapter_no()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio_i2c::i2c::mock::{update_rdwr_buf, DummyDevice};
+
+    // Every test uses its own client addresses, as the faults are global.
+    fn read_req(addr: u16) -> Vec<I2cReq> {
+        vec![I2cReq {
+            addr,
+            flags: I2C_M_RD,
+            len: 4,
+            buf: vec![0; 4].into(),
+        }]
+    }
+
+    fn write_req(addr: u16) -> Vec<I2cReq> {
+        let mut buf = vec![0; 4];
+        update_rdwr_buf(&mut buf);
+
+        vec![I2cReq {
+            addr,
+            flags: 0,
+            len: 4,
+            buf: buf.into(),
+        }]
+    }
+
+    #[test]
+    fn test_fault_parse() {
+        let config = FaultConfig::try_from("nth=3:errno=enxio:delay=5").unwrap();
+        assert_eq!(config.nth, Some(3));
+        assert_eq!(config.errno, libc::ENXIO);
+        assert_eq!(config.delay, Duration::from_millis(5));
+
+        let config = FaultConfig::try_from("prob=0.5:seed=7:flip=1").unwrap();
+        assert_eq!(config.probability, 0.5);
+        assert_eq!(config.seed, 7);
+        assert_eq!(config.flip, 1.0);
+        assert_eq!(config.errno, libc::EREMOTEIO);
+
+        assert_eq!(
+            FaultConfig::try_from("nth=0").unwrap_err(),
+            Error::FaultInvalid("nth=0".to_string())
+        );
+        assert_eq!(
+            FaultConfig::try_from("prob=2").unwrap_err(),
+            Error::FaultInvalid("probability 2".to_string())
+        );
+        assert_eq!(
+            FaultConfig::try_from("errno=enodev").unwrap_err(),
+            Error::FaultInvalid("errno=enodev".to_string())
+        );
+        assert_eq!(
+            FaultConfig::try_from("latency=4").unwrap_err(),
+            Error::FaultInvalid("latency=4".to_string())
+        );
+
+        let list = FaultList::try_from("4:nth=1,32:prob=0.1:errno=110").unwrap();
+        assert_eq!(list.inner.len(), 2);
+        assert_eq!(list.inner[1].0, 32);
+        assert_eq!(list.inner[1].1.errno, libc::ETIMEDOUT);
+
+        assert_eq!(
+            FaultList::try_from("4:nth=1,4:nth=2").unwrap_err(),
+            Error::ClientAddressDuplicate(4)
+        );
+        assert_eq!(
+            FaultList::try_from("4").unwrap_err(),
+            Error::FaultInvalid("4".to_string())
+        );
+        assert_eq!(
+            FaultList::try_from("200:nth=1").unwrap_err(),
+            Error::ClientAddressInvalid(200)
+        );
+    }
+
+    #[test]
+    fn test_fault_nth() {
+        let dev: FaultDevice<DummyDevice> = FaultDevice::open("/dev/i2c-1", 1).unwrap();
+        set_faults(0x10, FaultConfig::try_from("nth=2:errno=eagain").unwrap());
+
+        dev.rdwr(&mut write_req(0x10)).unwrap();
+        assert_eq!(
+            dev.rdwr(&mut write_req(0x10)).unwrap_err(),
+            I2cError::IoctlFailure("rdwr", IoError::new(libc::EAGAIN))
+        );
+        dev.rdwr(&mut write_req(0x10)).unwrap();
+
+        // Other clients aren't affected
+        dev.rdwr(&mut write_req(0x11)).unwrap();
+        dev.rdwr(&mut write_req(0x11)).unwrap();
+
+        let stats = fault_stats(0x10).unwrap();
+        assert_eq!(stats.transfers, 3);
+        assert_eq!(stats.injected, 1);
+
+        assert!(clear_faults(0x10));
+        assert!(!clear_faults(0x10));
+        assert_eq!(fault_stats(0x10), None);
+    }
+
+    #[test]
+    fn test_fault_probability() {
+        let dev: FaultDevice<DummyDevice> = FaultDevice::open("/dev/i2c-1", 1).unwrap();
+        let config = FaultConfig::try_from("prob=0.5:seed=42").unwrap();
+
+        // The same seed must fail the same transfers.
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            set_faults(0x12, config.clone());
+            results.push(
+                (0..64)
+                    .map(|_| dev.rdwr(&mut write_req(0x12)).is_err())
+                    .collect::<Vec<bool>>(),
+            );
+        }
+
+        assert_eq!(results[0], results[1]);
+        assert!(results[0].iter().any(|failed| *failed));
+        assert!(results[0].iter().any(|failed| !*failed));
+
+        // Faults can be disabled at runtime
+        assert!(enable_faults(0x12, false));
+        for _ in 0..64 {
+            dev.rdwr(&mut write_req(0x12)).unwrap();
+        }
+        assert!(!enable_faults(0x13, false));
+        clear_faults(0x12);
+    }
+
+    #[test]
+    fn test_fault_flip() {
+        let mut dev: FaultDevice<DummyDevice> = FaultDevice::open("/dev/i2c-1", 1).unwrap();
+        dev.funcs().unwrap();
+        assert_eq!(dev.adapter_no(), 1);
+        set_faults(0x14, FaultConfig::try_from("flip=1").unwrap());
+
+        let mut reqs = read_req(0x14);
+        dev.rdwr(&mut reqs).unwrap();
+
+        let mut expected = vec![0; 4];
+        update_rdwr_buf(&mut expected);
+        let flipped: u32 = reqs[0]
+            .buf
+            .to_vec()
+            .iter()
+            .zip(expected.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(flipped, 1);
+
+        // SMBus transfers use the address set with I2C_SLAVE
+        dev.slave(0x14).unwrap();
+        let mut reqs = vec![
+            I2cReq {
+                addr: 0x14,
+                flags: 0,
+                len: 1,
+                buf: vec![0].into(),
+            },
+            I2cReq {
+                addr: 0x14,
+                flags: I2C_M_RD,
+                len: 2,
+                buf: vec![0; 2].into(),
+            },
+        ];
+        let mut msg = SmbusMsg::new(&mut reqs).unwrap();
+        dev.smbus(&mut msg).unwrap();
+        assert_eq!(fault_stats(0x14).unwrap().injected, 2);
+        clear_faults(0x14);
+    }
+
+    #[test]
+    fn test_fault_delay() {
+        let dev: FaultDevice<DummyDevice> = FaultDevice::open("/dev/i2c-1", 1).unwrap();
+        set_faults(0x16, FaultConfig::try_from("delay=20").unwrap());
+
+        let start = std::time::Instant::now();
+        dev.rdwr(&mut write_req(0x16)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        clear_faults(0x16);
+    }
+}
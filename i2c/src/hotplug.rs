@@ -0,0 +1,315 @@
+// Reopening of the adapters unplugged and plugged back
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use log::{info, warn};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use thiserror::Error as ThisError;
+use virtio_i2c::i2c::{I2cDevice, I2cMap};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Directory of the adapter nodes.
+pub(crate) const DEV_DIR: &str = "/dev";
+
+/// Prefix of the adapter nodes.
+const NODE_PREFIX: &str = "i2c-";
+
+/// Longest file name, see include/uapi/linux/limits.h
+const NAME_MAX: usize = 255;
+
+/// Room for a few events with the longest names.
+const EVENTS_SIZE: usize = 16 * (size_of::<libc::inotify_event>() + NAME_MAX + 1);
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the adapter hotplug
+pub enum Error {
+    #[error("Failed to create inotify instance: {0}")]
+    InotifyUnavailable(i32),
+    #[error("Failed to watch {0}: {1}")]
+    WatchFailed(String, i32),
+    #[error("Failed to read inotify events: {0}")]
+    ReadFailed(i32),
+}
+
+/// Returns the adapter number of a node name, e.g. 3 for i2c-3.
+fn node_adapter_no(name: &str) -> Option<u32> {
+    name.strip_prefix(NODE_PREFIX)?.parse::<u32>().ok()
+}
+
+/// Parses the inotify events, and returns their masks and names.
+fn parse_events(buf: &[u8]) -> Vec<(u32, String)> {
+    let header = size_of::<libc::inotify_event>();
+    let mut events = Vec::new();
+    let mut offset = 0;
+
+    while offset + header <= buf.len() {
+        // SAFETY: Safe as the buffer holds a complete event header at the offset, read
+        // unaligned.
+        let event = unsafe {
+            std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+        };
+        let end = (offset + header + event.len as usize).min(buf.len());
+
+        // The name is padded with null bytes.
+        let name = &buf[offset + header..end];
+        let name = name.split(|byte| *byte == 0).next().unwrap_or(&[]);
+
+        events.push((event.mask, String::from_utf8_lossy(name).into_owned()));
+        offset = end;
+    }
+
+    events
+}
+
+/// Watches the adapter nodes, and reopens the adapters of the map once they reappear, e.g.
+/// when a USB adapter is plugged back or its driver reloaded.
+pub(crate) struct HotplugWatcher<D: I2cDevice> {
+    inotify: File,
+    dir: PathBuf,
+    i2c_map: Arc<I2cMap<D>>,
+}
+
+impl<D: I2cDevice> HotplugWatcher<D> {
+    pub fn new<P: AsRef<Path>>(dir: P, i2c_map: Arc<I2cMap<D>>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| Error::WatchFailed(dir.display().to_string(), libc::EINVAL))?;
+
+        // SAFETY: Safe as inotify_init1() doesn't access memory, and the returned fd is owned
+        // by the file.
+        let inotify = unsafe {
+            let fd = libc::inotify_init1(libc::IN_CLOEXEC);
+
+            if fd < 0 {
+                return Err(Error::InotifyUnavailable(errno()));
+            }
+            File::from_raw_fd(fd)
+        };
+
+        // The nodes are created before udev sets their ownership and mode, the attributes
+        // changes are watched as well to retry.
+        let mask = libc::IN_CREATE | libc::IN_DELETE | libc::IN_ATTRIB;
+
+        // SAFETY: Safe as the path is a valid C string.
+        let wd = unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(Error::WatchFailed(dir.display().to_string(), errno()));
+        }
+
+        Ok(HotplugWatcher {
+            inotify,
+            dir,
+            i2c_map,
+        })
+    }
+
+    /// Handles the events, forever.
+    pub fn run(mut self) {
+        loop {
+            if let Err(e) = self.process() {
+                warn!("Stopped watching adapters: {}", e);
+                return;
+            }
+        }
+    }
+
+    /// Waits for events, and handles them.
+    fn process(&mut self) -> Result<()> {
+        let mut buf = vec![0u8; EVENTS_SIZE];
+        let len = self
+            .inotify
+            .read(&mut buf)
+            .map_err(|e| Error::ReadFailed(e.raw_os_error().unwrap_or(0)))?;
+
+        for (mask, name) in parse_events(&buf[..len]) {
+            self.handle_event(mask, &name);
+        }
+        Ok(())
+    }
+
+    fn handle_event(&self, mask: u32, name: &str) {
+        let adapter_no = match node_adapter_no(name) {
+            Some(adapter_no) => adapter_no,
+            None => return,
+        };
+
+        // Only the adapters of the map are of interest.
+        let present = match self.i2c_map.adapter_present(adapter_no) {
+            Some(present) => present,
+            None => return,
+        };
+
+        if mask & libc::IN_DELETE != 0 {
+            let _ = self.i2c_map.remove_adapter(adapter_no);
+        } else if !present {
+            let path = self.dir.join(name);
+
+            match self
+                .i2c_map
+                .reopen_adapter(adapter_no, &path.to_string_lossy())
+            {
+                Ok(()) => info!("Adapter {} is back at {}", adapter_no, path.display()),
+                Err(e) => warn!("Failed to reopen adapter {}: {}", adapter_no, e),
+            }
+        }
+    }
+}
+
+fn errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use vmm_sys_util::tempdir::TempDir;
+
+    use virtio_i2c::config::AdapterConfig;
+    use virtio_i2c::i2c::mock::{toggle_adapter, DummyDevice};
+    use virtio_i2c::i2c::{Error as I2cError, I2cReq, I2C_M_RD};
+
+    use crate::sandbox::tests::{run_in_child, TestDir};
+    use crate::sandbox::Sandbox;
+
+    #[test]
+    fn test_parse_events() {
+        let header = size_of::<libc::inotify_event>();
+        let mut buf = Vec::new();
+
+        for (mask, name, len) in [(libc::IN_CREATE, "i2c-3", 16), (libc::IN_DELETE, "tty1", 8)] {
+            let event = libc::inotify_event {
+                wd: 1,
+                mask,
+                cookie: 0,
+                len,
+            };
+            // SAFETY: Safe as inotify_event is a plain C structure.
+            let bytes =
+                unsafe { std::slice::from_raw_parts(&event as *const _ as *const u8, header) };
+            let mut name = name.as_bytes().to_vec();
+
+            name.resize(len as usize, 0);
+            buf.extend_from_slice(bytes);
+            buf.extend_from_slice(&name);
+        }
+
+        assert_eq!(
+            parse_events(&buf),
+            vec![
+                (libc::IN_CREATE, "i2c-3".to_string()),
+                (libc::IN_DELETE, "tty1".to_string())
+            ]
+        );
+        assert_eq!(parse_events(&buf[..header - 1]), vec![]);
+
+        assert_eq!(node_adapter_no("i2c-3"), Some(3));
+        assert_eq!(node_adapter_no("i2c-x"), None);
+        assert_eq!(node_adapter_no("tty1"), None);
+    }
+
+    #[test]
+    fn test_hotplug() {
+        let dir = TempDir::new().unwrap();
+        let adapter_config = AdapterConfig::try_from("50:4,51:5").unwrap();
+        let i2c_map = Arc::new(I2cMap::<DummyDevice>::new(&adapter_config).unwrap());
+        let mut watcher = HotplugWatcher::new(dir.as_path(), i2c_map.clone()).unwrap();
+        let node = dir.as_path().join("i2c-50");
+        let mut reqs = vec![I2cReq {
+            addr: 0x4,
+            flags: I2C_M_RD,
+            len: 1,
+            buf: vec![0].into(),
+        }];
+
+        fs::write(&node, "").unwrap();
+        watcher.process().unwrap();
+        assert_eq!(i2c_map.adapter_present(50), Some(true));
+
+        // Unplugged
+        fs::remove_file(&node).unwrap();
+        watcher.process().unwrap();
+        assert_eq!(i2c_map.adapter_present(50), Some(false));
+        assert_eq!(i2c_map.adapter_present(51), Some(true));
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            I2cError::AdapterUnavailable(50)
+        );
+
+        // Plugged back, but not accessible until udev is done.
+        toggle_adapter(50, Err(I2cError::DeviceOpenFailed(50)), 1);
+        fs::write(&node, "").unwrap();
+        watcher.process().unwrap();
+        assert_eq!(i2c_map.adapter_present(50), Some(false));
+
+        toggle_adapter(50, Ok(()), 1);
+        fs::set_permissions(&node, fs::Permissions::from_mode(0o600)).unwrap();
+        watcher.process().unwrap();
+        assert_eq!(i2c_map.adapter_present(50), Some(true));
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        // Other nodes and adapters are ignored.
+        fs::write(dir.as_path().join("i2c-52"), "").unwrap();
+        fs::write(dir.as_path().join("tty1"), "").unwrap();
+        watcher.process().unwrap();
+
+        assert_eq!(
+            HotplugWatcher::new("/path/not/present", i2c_map).err(),
+            Some(Error::WatchFailed(
+                "/path/not/present".to_string(),
+                libc::ENOENT
+            ))
+        );
+    }
+
+    #[test]
+    fn test_hotplug_sandbox() {
+        let dir = TestDir::new();
+
+        // Set up in the child only, which must see the adapter node created.
+        let status = run_in_child("hotplug::tests::test_hotplug_sandbox", &dir, || {
+            let adapter_config = AdapterConfig::try_from("60:4").unwrap();
+            let i2c_map = Arc::new(I2cMap::<DummyDevice>::new(&adapter_config).unwrap());
+            let node = dir.as_path().join("i2c-60");
+            let path = CString::new(node.to_str().unwrap()).unwrap();
+
+            // The watcher is set up before the sandbox, as by the daemon.
+            i2c_map.remove_adapter(60).unwrap();
+            let mut watcher = HotplugWatcher::new(dir.as_path(), i2c_map.clone()).unwrap();
+            let mut sandbox = Sandbox::new();
+            sandbox.allow_adapters(dir.as_path());
+            fs::write(&node, "").unwrap();
+
+            if sandbox.apply().is_err() {
+                return 1;
+            }
+
+            // The process is killed on a system call the sandbox doesn't allow.
+            if watcher.process().is_err() || i2c_map.adapter_present(60) != Some(true) {
+                return 2;
+            }
+
+            // SAFETY: Safe as the path is a valid C string.
+            if unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) } < 0 {
+                return 3;
+            }
+            0
+        });
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
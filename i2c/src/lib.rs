@@ -0,0 +1,1705 @@
+// VIRTIO I2C Emulation via vhost-user
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod control;
+mod fault;
+mod hotplug;
+mod metrics;
+mod peer;
+mod privsep;
+mod probe;
+mod sandbox;
+mod shutdown;
+mod socket;
+mod systemd;
+mod unbind;
+mod vhu_i2c;
+
+use log::{info, warn};
+use std::convert::TryFrom;
+use std::num::ParseIntError;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock};
+use std::thread::spawn;
+
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use thiserror::Error as ThisError;
+use vhost::{vhost_user, vhost_user::Listener};
+use vhost_user_backend::VhostUserDaemon;
+use virtio_i2c::config::{self, AdapterConfig};
+use virtio_i2c::i2c::{self, I2cDevice, I2cMap, PhysDevice};
+use virtio_i2c::policy::{self, Policy};
+use virtio_i2c::quirks::{self, QuirksConfig};
+use virtio_i2c::ratelimit::{self, RateLimitConfig, RateLimiter, Scope};
+use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
+
+use control::ControlServer;
+use fault::{FaultDevice, FaultList};
+use hotplug::{HotplugWatcher, DEV_DIR};
+use metrics::{Metrics, MetricsEndpoint, MetricsServer};
+use peer::{PeerGate, PeerPolicy};
+use privsep::Credentials;
+use probe::{AdapterReport, ProbeMode};
+use sandbox::Sandbox;
+use shutdown::{Event, ExitGuard, Socket, STOP_TIMEOUT};
+use socket::SocketConfig;
+use systemd::ListenFds;
+use unbind::{Unbinder, SYSFS_ROOT};
+use vhu_i2c::{VhostUserI2cBackend, QUEUE_SIZE};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, PartialEq, ThisError)]
+/// Errors related to low level i2c helpers
+pub enum Error {
+    #[error("Invalid socket path")]
+    SocketPathInvalid,
+    #[error("Invalid socket count: {0}")]
+    SocketCountInvalid(usize),
+    #[error("Invalid socket configuration: {0}")]
+    SocketConfigInvalid(socket::Error),
+    #[error("Socket failure: {0}")]
+    SocketFailure(socket::Error),
+    #[error("Duplicate socket path detected: {0}")]
+    SocketPathDuplicate(String),
+    #[error("Socket client address not in the device list: {0}")]
+    SocketClientInvalid(u16),
+    #[error("Invalid device list")]
+    DeviceListInvalid,
+    #[error("Duplicate adapter detected: {0}")]
+    AdapterDuplicate(u32),
+    #[error("Invalid client address: {0}")]
+    ClientAddressInvalid(u16),
+    #[error("Duplicate client address detected: {0}")]
+    ClientAddressDuplicate(u16),
+    #[error("Low level I2c failure: {0:?}")]
+    I2cFailure(i2c::Error),
+    #[error("Failed while parsing to integer: {0:?}")]
+    ParseFailure(ParseIntError),
+    #[error("Failed to join threads")]
+    FailedJoiningThreads,
+    #[error("Invalid fault list: {0}")]
+    FaultListInvalid(fault::Error),
+    #[error("Control socket failure: {0}")]
+    ControlFailure(control::Error),
+    #[error("Metrics endpoint failure: {0}")]
+    MetricsFailure(metrics::Error),
+    #[error("Invalid peer rule: {0}")]
+    PeerRuleInvalid(peer::Error),
+    #[error("Peer gate failure: {0}")]
+    PeerGateFailure(peer::Error),
+    #[error("Systemd failure: {0}")]
+    SystemdFailure(systemd::Error),
+    #[error("Signal handling failure: {0}")]
+    SignalFailure(shutdown::Error),
+    #[error("Privilege separation failure: {0}")]
+    PrivsepFailure(privsep::Error),
+    #[error("Failed to create adapter helper socket: {0}")]
+    HelperSocketFailed(socket::Error),
+    #[error("The adapter helper requires --allow-peer")]
+    HelperPeersMissing,
+    #[error("Sandbox failure: {0}")]
+    SandboxFailure(sandbox::Error),
+    #[error("Invalid access control policy: {0}")]
+    PolicyInvalid(policy::Error),
+    #[error("Invalid rate limit: {0}")]
+    RateLimitInvalid(ratelimit::Error),
+    #[error("Rate limit of socket not present: {0}")]
+    RateLimitSocketInvalid(usize),
+    #[error("Invalid adapter quirks: {0}")]
+    QuirksInvalid(quirks::Error),
+    #[error("Adapter of the quirks not in the device list: {0}")]
+    QuirksAdapterInvalid(u32),
+    #[error("Auto-increment client address not in the device list: {0}")]
+    AutoIncrementClientInvalid(u16),
+    #[error("Probe failure: {0}")]
+    ProbeFailure(probe::Error),
+    #[error("Configuration check failed with {0} problem(s)")]
+    CheckFailed(usize),
+    #[error("Unbind client address not in the device list: {0}")]
+    UnbindClientInvalid(u16),
+    #[error("Failed to unbind kernel driver: {0}")]
+    UnbindFailure(unbind::Error),
+    #[error("Adapter hotplug failure: {0}")]
+    HotplugFailure(hotplug::Error),
+}
+
+impl From<config::Error> for Error {
+    fn from(e: config::Error) -> Self {
+        match e {
+            config::Error::AdapterDuplicate(adapter_no) => Error::AdapterDuplicate(adapter_no),
+            config::Error::ClientAddressInvalid(addr) => Error::ClientAddressInvalid(addr),
+            config::Error::ClientAddressDuplicate(addr) => Error::ClientAddressDuplicate(addr),
+            config::Error::ParseFailure(e) => Error::ParseFailure(e),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+struct ProbeConfiguration {
+    adapters: Vec<u32>,
+    range: (u16, u16),
+    mode: ProbeMode,
+}
+
+impl TryFrom<&ArgMatches> for ProbeConfiguration {
+    type Error = Error;
+
+    fn try_from(cmd_args: &ArgMatches) -> Result<Self> {
+        let mut adapters = Vec::new();
+
+        for adapter in cmd_args.values_of("adapters").into_iter().flatten() {
+            let adapter_no = adapter.parse::<u32>().map_err(Error::ParseFailure)?;
+
+            if adapters.contains(&adapter_no) {
+                return Err(Error::AdapterDuplicate(adapter_no));
+            }
+            adapters.push(adapter_no);
+        }
+
+        let range = match cmd_args.value_of("range") {
+            Some(range) => probe::parse_range(range).map_err(Error::ProbeFailure)?,
+            None => probe::DEFAULT_RANGE,
+        };
+
+        let mode = match cmd_args.value_of("mode") {
+            Some(mode) => ProbeMode::try_from(mode).map_err(Error::ProbeFailure)?,
+            None => ProbeMode::Auto,
+        };
+
+        Ok(ProbeConfiguration {
+            adapters,
+            range,
+            mode,
+        })
+    }
+}
+
+#[derive(PartialEq, Debug)]
+struct I2cConfiguration {
+    sockets: Vec<SocketConfig>,
+    peers: PeerPolicy,
+    devices: AdapterConfig,
+    faults: FaultList,
+    unbind: Vec<(u32, u16)>,
+    sysfs_root: String,
+    policy: Policy,
+    rate_limits: Vec<RateLimitConfig>,
+    quirks: Vec<QuirksConfig>,
+    auto_increment: Vec<(u16, Option<usize>)>,
+    control_socket: Option<String>,
+    control_peers: PeerPolicy,
+    metrics: Option<MetricsEndpoint>,
+    adapter_socket: Option<String>,
+    credentials: Credentials,
+    sandbox: bool,
+    hotplug: bool,
+}
+
+impl I2cConfiguration {
+    /// Parses and validates the options, carrying on after an invalid one to report all the
+    /// problems of the command line at once.
+    fn parse(cmd_args: ArgMatches) -> std::result::Result<Self, Vec<Error>> {
+        let mut errors = Vec::new();
+
+        let sockets = match cmd_args.values_of("sockets") {
+            Some(list) => {
+                let mut sockets: Vec<SocketConfig> = Vec::new();
+
+                for options in list {
+                    match SocketConfig::try_from(options) {
+                        Ok(socket) if sockets.iter().any(|elem| elem.path == socket.path) => {
+                            errors.push(Error::SocketPathDuplicate(socket.path))
+                        }
+                        Ok(socket) => sockets.push(socket),
+                        Err(e) => errors.push(Error::SocketConfigInvalid(e)),
+                    }
+                }
+                sockets
+            }
+
+            None => {
+                let socket_count = cmd_args
+                    .value_of("socket_count")
+                    .unwrap_or("1")
+                    .parse::<usize>()
+                    .map_err(Error::ParseFailure)
+                    .and_then(|count| match count {
+                        0 => Err(Error::SocketCountInvalid(0)),
+                        count => Ok(count),
+                    });
+
+                match (cmd_args.value_of("socket_path"), socket_count) {
+                    (Some(socket_path), Ok(count)) => (0..count)
+                        .map(|i| SocketConfig::new(socket_path_at(socket_path, i)))
+                        .collect(),
+                    (socket_path, count) => {
+                        if socket_path.is_none() {
+                            errors.push(Error::SocketPathInvalid);
+                        }
+                        if let Err(e) = count {
+                            errors.push(e);
+                        }
+                        Vec::new()
+                    }
+                }
+            }
+        };
+
+        let peers = match cmd_args.values_of("allow_peers") {
+            Some(rules) => PeerPolicy::try_from(rules.collect::<Vec<&str>>())
+                .map_err(|e| errors.push(Error::PeerRuleInvalid(e)))
+                .unwrap_or_default(),
+            None => PeerPolicy::default(),
+        };
+
+        // The checks of the clients against the device list are skipped without one.
+        let devices = match cmd_args.value_of("devices") {
+            Some(list) => AdapterConfig::try_from(list)
+                .map_err(|e| errors.push(e.into()))
+                .ok(),
+            None => {
+                errors.push(Error::DeviceListInvalid);
+                None
+            }
+        };
+
+        if let Some(devices) = &devices {
+            for socket in sockets.iter() {
+                for addr in socket.devices.iter().flatten() {
+                    if !devices.contains_addr(*addr) {
+                        errors.push(Error::SocketClientInvalid(*addr));
+                    }
+                }
+            }
+        }
+
+        let faults = match cmd_args.value_of("faults") {
+            Some(list) => FaultList::try_from(list)
+                .map_err(|e| errors.push(Error::FaultListInvalid(e)))
+                .unwrap_or_default(),
+            None => FaultList::default(),
+        };
+
+        let unbind = match &devices {
+            Some(devices) => collect_unbind(&cmd_args, devices, &mut errors),
+            None => Vec::new(),
+        };
+        let sysfs_root = cmd_args
+            .value_of("sysfs_root")
+            .unwrap_or(SYSFS_ROOT)
+            .to_string();
+
+        let policy = match cmd_args.value_of("policy_file") {
+            Some(path) => Policy::from_file(path)
+                .map_err(|e| errors.push(Error::PolicyInvalid(e)))
+                .unwrap_or_default(),
+            None => Policy::default(),
+        };
+
+        let mut rate_limits = Vec::new();
+        for options in cmd_args.values_of("rate_limits").into_iter().flatten() {
+            match RateLimitConfig::try_from(options) {
+                Ok(limit) => match limit.scope {
+                    Scope::Socket(Some(index)) if index >= sockets.len() => {
+                        errors.push(Error::RateLimitSocketInvalid(index))
+                    }
+                    _ => rate_limits.push(limit),
+                },
+                Err(e) => errors.push(Error::RateLimitInvalid(e)),
+            }
+        }
+
+        let mut quirks = Vec::new();
+        for options in cmd_args.values_of("adapter_quirks").into_iter().flatten() {
+            match QuirksConfig::try_from(options) {
+                Ok(config) => match &devices {
+                    Some(devices) if !devices.contains_adapter_no(config.adapter_no) => {
+                        errors.push(Error::QuirksAdapterInvalid(config.adapter_no))
+                    }
+                    _ => quirks.push(config),
+                },
+                Err(e) => errors.push(Error::QuirksInvalid(e)),
+            }
+        }
+
+        let mut auto_increment = Vec::new();
+        if let Some(list) = cmd_args.value_of("auto_increment") {
+            for client in list.split(',') {
+                let mut fields = client.splitn(2, ':');
+                let addr = fields.next().unwrap().parse::<u16>();
+                let page_size = fields.next().map(|size| size.parse::<usize>());
+                let client = addr.and_then(|addr| Ok((addr, page_size.transpose()?)));
+
+                match client {
+                    Ok((addr, page_size)) => match &devices {
+                        Some(devices) if !devices.contains_addr(addr) => {
+                            errors.push(Error::AutoIncrementClientInvalid(addr))
+                        }
+                        _ => auto_increment.push((addr, page_size)),
+                    },
+                    Err(e) => errors.push(Error::ParseFailure(e)),
+                }
+            }
+        }
+
+        let control_socket = cmd_args.value_of("control_socket").map(String::from);
+        let control_peers = match cmd_args.values_of("control_peers") {
+            Some(rules) => PeerPolicy::try_from(rules.collect::<Vec<&str>>())
+                .map_err(|e| errors.push(Error::PeerRuleInvalid(e)))
+                .unwrap_or_default(),
+            None => PeerPolicy::default(),
+        };
+
+        let metrics = match (
+            cmd_args.value_of("metrics_socket"),
+            cmd_args.value_of("metrics_port"),
+        ) {
+            (Some(path), _) => Some(MetricsEndpoint::Unix(path.to_string())),
+            (None, Some(port)) => port
+                .parse::<u16>()
+                .map_err(|e| errors.push(Error::ParseFailure(e)))
+                .ok()
+                .map(MetricsEndpoint::Tcp),
+            (None, None) => None,
+        };
+
+        let adapter_socket = cmd_args.value_of("adapter_socket").map(String::from);
+        let credentials = Credentials::new(cmd_args.value_of("user"), cmd_args.value_of("group"))
+            .map_err(|e| errors.push(Error::PrivsepFailure(e)))
+            .unwrap_or_default();
+
+        let sandbox = cmd_args.is_present("sandbox");
+        let hotplug = cmd_args.is_present("hotplug");
+
+        match devices {
+            Some(devices) if errors.is_empty() => Ok(I2cConfiguration {
+                sockets,
+                peers,
+                devices,
+                faults,
+                unbind,
+                sysfs_root,
+                policy,
+                rate_limits,
+                quirks,
+                auto_increment,
+                control_socket,
+                control_peers,
+                metrics,
+                adapter_socket,
+                credentials,
+                sandbox,
+                hotplug,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+impl TryFrom<ArgMatches> for I2cConfiguration {
+    type Error = Error;
+
+    /// Fails with the first problem found.
+    fn try_from(cmd_args: ArgMatches) -> Result<Self> {
+        Self::parse(cmd_args).map_err(|mut errors| errors.remove(0))
+    }
+}
+
+/// Returns the adapters and addresses of the clients to unbind from their kernel drivers.
+fn parse_unbind(cmd_args: &ArgMatches, devices: &AdapterConfig) -> Result<Vec<(u32, u16)>> {
+    let mut errors = Vec::new();
+    let clients = collect_unbind(cmd_args, devices, &mut errors);
+
+    match errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(clients),
+    }
+}
+
+/// Same as parse_unbind(), but records all the invalid clients in `errors` and skips them.
+fn collect_unbind(
+    cmd_args: &ArgMatches,
+    devices: &AdapterConfig,
+    errors: &mut Vec<Error>,
+) -> Vec<(u32, u16)> {
+    let mut clients = Vec::new();
+
+    for addr in cmd_args
+        .value_of("unbind")
+        .into_iter()
+        .flat_map(|list| list.split(','))
+    {
+        match addr.parse::<u16>() {
+            Ok(addr) => match devices.adapter_of(addr) {
+                Some(adapter_no) => clients.push((adapter_no, addr)),
+                None => errors.push(Error::UnbindClientInvalid(addr)),
+            },
+            Err(e) => errors.push(Error::ParseFailure(e)),
+        }
+    }
+    clients
+}
+
+/// Unbinds the clients from their kernel drivers, which are rebound once the returned
+/// unbinder is dropped.
+fn unbind_clients(clients: &[(u32, u16)], sysfs_root: &str) -> Result<Unbinder> {
+    let mut unbinder = Unbinder::new(sysfs_root);
+
+    for (adapter_no, addr) in clients.iter() {
+        unbinder
+            .unbind(*adapter_no, *addr)
+            .map_err(Error::UnbindFailure)?;
+    }
+    Ok(unbinder)
+}
+
+/// Switches to the user and group of the command line, if any.
+fn drop_privileges(credentials: &Credentials) -> Result<()> {
+    if !credentials.is_empty() {
+        credentials
+            .drop_privileges()
+            .map_err(Error::PrivsepFailure)?;
+    }
+    Ok(())
+}
+
+/// Returns the path of the socket at `index`, replacing the "{}" placeholder of the template, or
+/// suffixing it otherwise.
+fn socket_path_at(template: &str, index: usize) -> String {
+    if template.contains("{}") {
+        template.replace("{}", &index.to_string())
+    } else {
+        template.to_owned() + &index.to_string()
+    }
+}
+
+/// Serves the guests on the vhost-user sockets of the command line, parsed with cli.yaml,
+/// until terminated. Fails if the configuration is invalid. The devices are only emulated by
+/// the tests.
+pub fn start_backend<D: 'static + I2cDevice + Send + Sync>(cmd_args: ArgMatches) -> Result<()> {
+    let config = I2cConfiguration::try_from(cmd_args)?;
+
+    // The sockets are owned by systemd when socket activated, otherwise created here. The
+    // environment is cleaned up before any thread is spawned.
+    let listen_fds = ListenFds::from_env().map_err(Error::SystemdFailure)?;
+
+    // Faults are only injected when the devices are wrapped with FaultDevice.
+    config.faults.register();
+
+    // The kernel drivers are rebound on shutdown, once the unbinder is dropped.
+    let _unbinder = unbind_clients(&config.unbind, &config.sysfs_root)?;
+
+    // The same i2c_map structure instance is shared between all the guests. The adapters are
+    // opened by the privileged helper instead, if any, which doesn't require any privileges.
+    // They are dropped beforehand, for the helper to check the user of the daemon.
+    let i2c_map = match &config.adapter_socket {
+        Some(path) => {
+            drop_privileges(&config.credentials)?;
+
+            I2cMap::<D>::from_files(
+                &config.devices,
+                privsep::receive_adapters(path).map_err(Error::PrivsepFailure)?,
+            )
+        }
+        None => I2cMap::<D>::new(&config.devices),
+    };
+    let mut i2c_map = i2c_map.map_err(Error::I2cFailure)?;
+
+    if !config.policy.is_empty() {
+        i2c_map.set_policy(config.policy.clone());
+    }
+
+    for config in config.quirks.iter() {
+        i2c_map
+            .set_quirks(config.adapter_no, config.quirks)
+            .map_err(Error::I2cFailure)?;
+    }
+
+    for (addr, page_size) in config.auto_increment.iter() {
+        i2c_map
+            .set_auto_increment(*addr, true, *page_size)
+            .map_err(Error::I2cFailure)?;
+    }
+
+    // Metrics are shared between all the guests, and labelled with the socket index.
+    let metrics = Arc::new(Metrics::new());
+
+    // The adapter limits are shared by all the guests, the socket ones are per guest.
+    for limit in config.rate_limits.iter() {
+        if let Scope::Adapter(adapter_no) = limit.scope {
+            let limiter = Arc::new(RateLimiter::new(limit, limit.scope));
+
+            i2c_map
+                .set_rate_limiter(adapter_no, limiter.clone())
+                .map_err(Error::I2cFailure)?;
+            metrics.add_rate_limiter(limiter);
+        }
+    }
+    let i2c_map = Arc::new(i2c_map);
+
+    // The adapters are open, the privileges aren't required anymore. This must happen before
+    // spawning any threads.
+    if config.adapter_socket.is_none() {
+        drop_privileges(&config.credentials)?;
+    }
+
+    // Clients can be reconfigured at runtime through the control socket.
+    let control = match &config.control_socket {
+        Some(path) => Some(
+            ControlServer::new(
+                path,
+                i2c_map.clone(),
+                config.control_peers.clone(),
+                !config.faults.is_empty(),
+            )
+            .map_err(Error::ControlFailure)?,
+        ),
+        None => None,
+    };
+
+    // The sockets, and the private directories of the peer gates, are created by the user
+    // serving the guests, before the sandbox is applied and any thread is spawned. The sockets
+    // are kept for the next guests, and created once.
+    let mut listeners = Vec::new();
+    let mut gate_dirs = Vec::new();
+    for socket in config.sockets.iter() {
+        listeners.push(if listen_fds.is_empty() {
+            Some(socket.bind().map_err(Error::SocketFailure)?)
+        } else {
+            None
+        });
+
+        gate_dirs.push(if config.peers.is_empty() {
+            None
+        } else {
+            Some(PeerGate::private_dir(&socket.path).map_err(Error::PeerGateFailure)?)
+        });
+    }
+
+    // The adapters unplugged are reopened once back. The watch is set up before the sandbox,
+    // which doesn't allow it.
+    let watcher = if config.hotplug {
+        Some(HotplugWatcher::new(DEV_DIR, i2c_map.clone()).map_err(Error::HotplugFailure)?)
+    } else {
+        None
+    };
+
+    // Only the system calls and paths needed to serve the guests are allowed from here on,
+    // which is inherited by all the threads.
+    if config.sandbox {
+        let mut sandbox = Sandbox::new();
+
+        for socket in config.sockets.iter() {
+            sandbox.allow_socket(&socket.path);
+        }
+
+        if let Some(path) = &config.control_socket {
+            sandbox.allow_socket(path);
+        }
+
+        if let Some(MetricsEndpoint::Unix(path)) = &config.metrics {
+            sandbox.allow_socket(path);
+        }
+
+        if watcher.is_some() {
+            sandbox.allow_adapters(DEV_DIR);
+        }
+
+        sandbox.apply().map_err(Error::SandboxFailure)?;
+    }
+
+    // Termination signals and thread exits are reported to this thread, which must happen
+    // before spawning any threads.
+    let (events_tx, events_rx) = channel();
+    shutdown::watch_signals(events_tx.clone()).map_err(Error::SignalFailure)?;
+
+    if let Some(server) = control {
+        spawn(move || server.run());
+    }
+
+    if let Some(watcher) = watcher {
+        spawn(move || watcher.run());
+    }
+
+    if let Some(endpoint) = &config.metrics {
+        let server =
+            MetricsServer::new(endpoint, metrics.clone()).map_err(Error::MetricsFailure)?;
+        spawn(move || server.run());
+    }
+
+    // The peers are checked before their connections are handed over to the daemons.
+    let peers = if config.peers.is_empty() {
+        None
+    } else {
+        Some(Arc::new(config.peers.clone()))
+    };
+
+    // Each thread reports once its listener is ready, to notify systemd.
+    let (ready_tx, ready_rx) = channel();
+    let mut handles = Vec::new();
+    let mut sockets = Vec::new();
+
+    for (i, socket_config) in config.sockets.iter().enumerate() {
+        let socket = socket_config.path.clone();
+        let socket_config = socket_config.clone();
+        let i2c_map = i2c_map.clone();
+        let metrics = metrics.clone();
+        let peers = peers.clone();
+        let gate_dir = gate_dirs[i].take();
+        let mut ready = Some(ready_tx.clone());
+        let guard = ExitGuard(events_tx.clone());
+
+        // The limit of the socket takes precedence over the one of all the sockets.
+        let limiter = config
+            .rate_limits
+            .iter()
+            .find(|limit| limit.scope == Scope::Socket(Some(i)))
+            .or_else(|| {
+                config
+                    .rate_limits
+                    .iter()
+                    .find(|limit| limit.scope == Scope::Socket(None))
+            })
+            .map(|limit| Arc::new(RateLimiter::new(limit, Scope::Socket(Some(i)))));
+
+        if let Some(limiter) = &limiter {
+            metrics.add_rate_limiter(limiter.clone());
+        }
+
+        let listener = listeners[i].take();
+        let listen_fd = match &listener {
+            Some(listener) => listener.as_raw_fd(),
+            None => listen_fds.find(&socket, i).map_err(Error::SystemdFailure)?,
+        };
+
+        // Sockets passed by systemd aren't removed on shutdown.
+        let state = Arc::new(Socket::<D>::new(listener.as_ref().map(|_| socket.clone())));
+        sockets.push(state.clone());
+
+        let handle = spawn(move || {
+            // Reports the exit of the thread, even on panic.
+            let _guard = guard;
+
+            // The daemon closes its listener once a guest connects, the socket is kept open
+            // for the next guest.
+            let _listener = listener;
+
+            // The gate accepts the connections on the socket, and relays those of the allowed
+            // peers to the daemon listening on a private socket. The private socket is kept
+            // open as well, a peer relayed before the daemon listens again waits for it.
+            let private = peers.zip(gate_dir).map(|(policy, dir)| {
+                // SAFETY: Safe as the fd is a valid listening socket, which is duplicated and
+                // owned by the listener.
+                let listener = unsafe { UnixListener::from_raw_fd(libc::dup(listen_fd)) };
+                let gate = Arc::new(PeerGate::new(listener, policy, dir));
+                let private = Listener::new(gate.private_path(), true).unwrap();
+                let server = gate.clone();
+
+                state.set_gate(gate.clone());
+
+                spawn(move || server.run());
+                private
+            });
+            let daemon_fd = private
+                .as_ref()
+                .map_or(listen_fd, |private| private.as_raw_fd());
+
+            loop {
+                // A separate thread is spawned for each socket and can connect to a separate
+                // guest. These are run in an infinite loop to not require the daemon to be
+                // restarted once a guest exits.
+                //
+                // There isn't much value in complicating code here to return an error from the
+                // threads, and so the code uses unwrap() instead. The panic on a thread won't
+                // cause trouble to other threads/guests or the main() function and should be safe
+                // for the daemon.
+                //
+                // Every VMM connection is served by a new backend, only the rate limit of the
+                // socket, the adapters and their clients outlive it.
+                let backend = Arc::new(RwLock::new(
+                    VhostUserI2cBackend::new(
+                        i2c_map.clone(),
+                        metrics.clone(),
+                        i,
+                        socket_config.devices.clone(),
+                        socket_config.queue_size.unwrap_or(QUEUE_SIZE),
+                        limiter.clone(),
+                    )
+                    .unwrap(),
+                ));
+                // SAFETY: Safe as the fd is a valid listening socket, which is duplicated and
+                // owned by the listener.
+                let listener = unsafe { Listener::from_raw_fd(libc::dup(daemon_fd)) };
+
+                if !state.serve(backend.clone(), &listener) {
+                    break;
+                }
+
+                if let Some(ready) = ready.take() {
+                    ready.send(()).unwrap();
+                }
+
+                let mut daemon = VhostUserDaemon::new(
+                    String::from("vhost-device-i2c-backend"),
+                    backend.clone(),
+                    GuestMemoryAtomic::new(GuestMemoryMmap::new()),
+                )
+                .unwrap();
+
+                if let Err(e) = daemon.start(listener) {
+                    // The listener is shut down while waiting for a guest.
+                    if state.stopping() {
+                        break;
+                    }
+                    panic!("Failed to start daemon: {:?}", e);
+                }
+
+                match daemon.wait() {
+                    Ok(()) => {
+                        info!("Stopping cleanly.");
+                    }
+                    Err(vhost_user_backend::Error::HandleRequest(
+                        vhost_user::Error::PartialMessage,
+                    )) => {
+                        info!("vhost-user connection closed with partial message. If the VM is shutting down, this is expected behavior; otherwise, it might be a bug.");
+                    }
+                    Err(e) => {
+                        warn!("Error running daemon: {:?}", e);
+                    }
+                }
+
+                // No matter the result, we need to shut down the worker thread.
+                backend.read().unwrap().exit_event.write(1).unwrap();
+                state.done();
+            }
+        });
+
+        handles.push(handle);
+    }
+
+    // A thread failing to create its listener drops its sender without reporting.
+    drop(ready_tx);
+    if ready_rx.iter().take(config.sockets.len()).count() == config.sockets.len() {
+        systemd::notify(&format!(
+            "READY=1\nSTATUS=Serving {} socket(s) with {} adapter(s)",
+            config.sockets.len(),
+            i2c_map.adapters().len()
+        ))
+        .map_err(Error::SystemdFailure)?;
+    }
+
+    // The threads only exit on failure, unless the daemon is terminated.
+    let mut exited = 0;
+    for event in events_rx.iter() {
+        match event {
+            Event::Signal(signal) => {
+                info!("Received signal {}, shutting down", signal);
+                let _ = systemd::notify("STOPPING=1");
+
+                for socket in sockets.iter() {
+                    socket.stop();
+                }
+
+                if let Some(path) = &config.control_socket {
+                    shutdown::remove_socket(path);
+                }
+
+                if let Some(MetricsEndpoint::Unix(path)) = &config.metrics {
+                    shutdown::remove_socket(path);
+                }
+
+                let running = config.sockets.len() - exited;
+                let left = shutdown::join_threads(&events_rx, running, handles, STOP_TIMEOUT);
+                if left != 0 {
+                    warn!("Exiting with {} guest(s) still connected", left);
+                }
+                return Ok(());
+            }
+
+            Event::ThreadExited => {
+                exited += 1;
+                if exited == config.sockets.len() {
+                    break;
+                }
+            }
+        }
+    }
+
+    for handle in handles {
+        handle.join().map_err(|_| Error::FailedJoiningThreads)?;
+    }
+
+    Ok(())
+}
+
+/// Runs the privileged helper, which opens the adapters and passes them to the daemons
+/// connecting to its socket, until terminated.
+fn start_helper(cmd_args: ArgMatches) -> Result<()> {
+    let socket = cmd_args
+        .value_of("adapter_helper")
+        .ok_or(Error::SocketPathInvalid)
+        .and_then(helper_socket)?;
+
+    let list = cmd_args
+        .value_of("devices")
+        .ok_or(Error::DeviceListInvalid)?;
+    let devices = AdapterConfig::try_from(list)?;
+
+    let peers = match cmd_args.values_of("allow_peers") {
+        Some(rules) => {
+            PeerPolicy::try_from(rules.collect::<Vec<&str>>()).map_err(Error::PeerRuleInvalid)?
+        }
+        None => PeerPolicy::default(),
+    };
+
+    // The adapters would be passed to anyone otherwise.
+    if peers.is_empty() {
+        return Err(Error::HelperPeersMissing);
+    }
+
+    let unbind = parse_unbind(&cmd_args, &devices)?;
+    let sysfs_root = cmd_args.value_of("sysfs_root").unwrap_or(SYSFS_ROOT);
+    let _unbinder = unbind_clients(&unbind, sysfs_root)?;
+
+    // The clients are checked with I2C_SLAVE before passing the adapters.
+    let i2c_map = I2cMap::<PhysDevice>::new(&devices).map_err(Error::I2cFailure)?;
+
+    // The socket is created before spawning any thread, with its ownership and mode.
+    let listener = socket.bind().map_err(Error::HelperSocketFailed)?;
+    info!(
+        "Passing {} adapter(s) at {}",
+        i2c_map.adapters().len(),
+        socket.path
+    );
+
+    let (events_tx, events_rx) = channel();
+    shutdown::watch_signals(events_tx).map_err(Error::SignalFailure)?;
+
+    spawn(move || privsep::serve_adapters(&listener, &i2c_map, &peers));
+
+    if let Some(Event::Signal(signal)) = events_rx.iter().next() {
+        info!("Received signal {}, shutting down", signal);
+    }
+
+    shutdown::remove_socket(&socket.path);
+    Ok(())
+}
+
+/// Parses the socket of the privileged helper, a path or the path and the ownership and mode
+/// of the socket, in the format of --socket.
+fn helper_socket(value: &str) -> Result<SocketConfig> {
+    if !value.contains('=') {
+        return Ok(SocketConfig::new(value.to_string()));
+    }
+
+    let socket = SocketConfig::try_from(value).map_err(Error::SocketConfigInvalid)?;
+
+    // The guest options don't apply to the helper.
+    if socket.devices.is_some() || socket.queue_size.is_some() {
+        return Err(Error::SocketConfigInvalid(socket::Error::OptionInvalid(
+            value.to_string(),
+        )));
+    }
+    Ok(socket)
+}
+
+/// Checks the configuration, the adapters and their clients without starting the daemon.
+/// Returns the JSON report, with all the problems found.
+fn check_configuration<D: I2cDevice>(cmd_args: ArgMatches) -> Value {
+    let mut errors = Vec::new();
+    let list = cmd_args.value_of("devices").map(String::from);
+
+    let (devices, adapter_socket) = match I2cConfiguration::parse(cmd_args) {
+        Ok(config) => (Some(config.devices), config.adapter_socket),
+        Err(e) => {
+            errors.extend(e.iter().map(|e| e.to_string()));
+
+            // The adapters are still checked if the device list is valid.
+            let devices = list.and_then(|list| AdapterConfig::try_from(list.as_str()).ok());
+            (devices, None)
+        }
+    };
+
+    let checks = match (devices, adapter_socket) {
+        (Some(devices), Some(path)) => match privsep::receive_adapters(&path) {
+            Ok(files) => I2cMap::<D>::check(&devices, Some(files)),
+            Err(e) => {
+                errors.push(e.to_string());
+                Vec::new()
+            }
+        },
+        (Some(devices), None) => I2cMap::<D>::check(&devices, None),
+        (None, _) => Vec::new(),
+    };
+
+    let mut adapters = Vec::new();
+
+    for check in checks.iter() {
+        if let Some(e) = &check.error {
+            errors.push(format!("Adapter {}: {}", check.adapter_no, e));
+        }
+
+        let clients: Vec<Value> = check
+            .clients
+            .iter()
+            .map(|(addr, error)| {
+                if let Some(e) = error {
+                    errors.push(format!(
+                        "Client {} of adapter {}: {}",
+                        addr, check.adapter_no, e
+                    ));
+                }
+                json!({
+                    "addr": addr,
+                    "ok": error.is_none(),
+                    "error": error.map(|e| e.to_string()),
+                })
+            })
+            .collect();
+
+        adapters.push(json!({
+            "adapter": check.adapter_no,
+            "ok": check.is_ok(),
+            "smbus": check.smbus,
+            "error": check.error.map(|e| e.to_string()),
+            "clients": clients,
+        }));
+    }
+
+    json!({ "ok": errors.is_empty(), "errors": errors, "adapters": adapters })
+}
+
+/// Prints the JSON report of the configuration check, fails if any problem was found.
+fn start_check(cmd_args: ArgMatches) -> Result<()> {
+    let report = check_configuration::<PhysDevice>(cmd_args);
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    match report["errors"].as_array().map_or(0, |errors| errors.len()) {
+        0 => Ok(()),
+        count => Err(Error::CheckFailed(count)),
+    }
+}
+
+/// Probes the adapters, prints their functionality and clients, and the device list of the
+/// clients available to the guests.
+fn start_probe(cmd_args: &ArgMatches) -> Result<()> {
+    let config = ProbeConfiguration::try_from(cmd_args)?;
+    let mut reports: Vec<AdapterReport> = Vec::new();
+
+    for adapter_no in config.adapters {
+        let mut device = PhysDevice::open(&format!("/dev/i2c-{}", adapter_no), adapter_no)
+            .map_err(Error::I2cFailure)?;
+        let report =
+            probe::probe(&mut device, config.range, config.mode).map_err(Error::ProbeFailure)?;
+
+        println!("{}", report);
+        reports.push(report);
+    }
+
+    match probe::device_list(&reports).as_str() {
+        "" => println!("\nNo clients available to the guests"),
+        list => println!("\nDevice list: --device-list {}", list),
+    }
+    Ok(())
+}
+
+/// Runs the daemon, the helper, or one of the subcommands, as selected by the command line
+/// parsed with cli.yaml.
+pub fn start(cmd_args: ArgMatches) -> Result<()> {
+    if let Some(probe_args) = cmd_args.subcommand_matches("probe") {
+        start_probe(probe_args)
+    } else if cmd_args.is_present("check") {
+        start_check(cmd_args)
+    } else if cmd_args.is_present("adapter_helper") {
+        start_helper(cmd_args)
+    } else if cmd_args.is_present("faults") {
+        start_backend::<FaultDevice<PhysDevice>>(cmd_args)
+    } else {
+        start_backend::<PhysDevice>(cmd_args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{load_yaml, App};
+    use virtio_i2c::config::DeviceConfig;
+    use virtio_i2c::i2c::mock::DummyDevice;
+
+    fn get_cmd_args(name: Option<&str>, devices: &str, count: Option<&str>) -> ArgMatches {
+        let mut args = vec!["prog", "-l", devices];
+        let yaml = load_yaml!("cli.yaml");
+        let app = App::from(yaml);
+
+        if let Some(name) = name {
+            args.extend_from_slice(&["-s", name]);
+        }
+
+        if let Some(count) = count {
+            args.extend_from_slice(&["-c", count]);
+        }
+        app.try_get_matches_from(args).unwrap()
+    }
+
+    #[test]
+    fn test_parse_failure() {
+        let socket_name = Some("vi2c.sock");
+
+        // Invalid bus_addr
+        let cmd_args = get_cmd_args(socket_name, "1:4,3d:5", Some("5"));
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::ParseFailure("3d".parse::<u32>().unwrap_err())
+        );
+
+        // Invalid client address
+        let cmd_args = get_cmd_args(socket_name, "1:4d", Some("5"));
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::ParseFailure("4d".parse::<u16>().unwrap_err())
+        );
+
+        // Invalid socket path
+        let cmd_args = get_cmd_args(None, "1:4d", Some("5"));
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::SocketPathInvalid
+        );
+
+        // Invalid socket count
+        let cmd_args = get_cmd_args(socket_name, "1:4", Some("1d"));
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::ParseFailure("1d".parse::<u16>().unwrap_err())
+        );
+
+        // Zero socket count
+        let cmd_args = get_cmd_args(socket_name, "1:4", Some("0"));
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::SocketCountInvalid(0)
+        );
+
+        // Duplicate client address: 4
+        let cmd_args = get_cmd_args(socket_name, "1:4,2:32:21,5:4:23", Some("5"));
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::ClientAddressDuplicate(4)
+        );
+    }
+
+    #[test]
+    fn test_parse_successful() {
+        let socket_name = Some("vi2c.sock");
+
+        // Missing socket count, default (1) should be used.
+        let cmd_args = get_cmd_args(socket_name, "1:4,2:32:21,5:5:23", None);
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+        assert_eq!(config.sockets.len(), 1);
+
+        let cmd_args = get_cmd_args(socket_name, "1:4,2:32:21,5:5:23", Some("5"));
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+
+        let mut expected_devices = AdapterConfig::new();
+        for (adapter_no, clients) in [(1, vec![4]), (2, vec![32, 21]), (5, vec![5, 23])] {
+            let mut device = DeviceConfig::new(adapter_no);
+
+            for addr in clients {
+                device.push(addr).unwrap();
+            }
+            expected_devices.push(device).unwrap();
+        }
+
+        let expected_config = I2cConfiguration {
+            sockets: (0..5)
+                .map(|i| SocketConfig::new(format!("vi2c.sock{}", i)))
+                .collect(),
+            peers: PeerPolicy::default(),
+            devices: expected_devices,
+            faults: FaultList::default(),
+            unbind: Vec::new(),
+            sysfs_root: SYSFS_ROOT.to_string(),
+            policy: Policy::default(),
+            rate_limits: Vec::new(),
+            quirks: Vec::new(),
+            auto_increment: Vec::new(),
+            control_socket: None,
+            control_peers: PeerPolicy::default(),
+            metrics: None,
+            adapter_socket: None,
+            credentials: Credentials::default(),
+            sandbox: false,
+            hotplug: false,
+        };
+
+        assert_eq!(config, expected_config);
+    }
+
+    #[test]
+    fn test_parse_sockets() {
+        let yaml = load_yaml!("cli.yaml");
+
+        // Template
+        let args = vec!["prog", "-s", "/run/vi2c-{}.sock", "-l", "1:4", "-c", "2"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+        assert_eq!(
+            config.sockets,
+            vec![
+                SocketConfig::new("/run/vi2c-0.sock".to_string()),
+                SocketConfig::new("/run/vi2c-1.sock".to_string()),
+            ]
+        );
+
+        // Explicit paths, with their own options
+        let args = vec![
+            "prog",
+            "--socket",
+            "path=/run/vm1.sock,mode=0600,devices=4",
+            "--socket",
+            "path=/run/vm2.sock,queue-size=128",
+            "-l",
+            "1:4,2:32",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+
+        let mut socket1 = SocketConfig::new("/run/vm1.sock".to_string());
+        socket1.mode = Some(0o600);
+        socket1.devices = Some(vec![4]);
+        let mut socket2 = SocketConfig::new("/run/vm2.sock".to_string());
+        socket2.queue_size = Some(128);
+        assert_eq!(config.sockets, vec![socket1, socket2]);
+
+        // Duplicate path
+        let args = vec![
+            "prog",
+            "--socket",
+            "path=/run/vm1.sock",
+            "--socket",
+            "path=/run/vm1.sock",
+            "-l",
+            "1:4",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::SocketPathDuplicate("/run/vm1.sock".to_string())
+        );
+
+        // Client not in the device list
+        let args = vec!["prog", "--socket", "path=vm.sock,devices=5", "-l", "1:4"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::SocketClientInvalid(5)
+        );
+
+        // Invalid options
+        let args = vec!["prog", "--socket", "mode=0600", "-l", "1:4"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::SocketConfigInvalid(socket::Error::PathMissing)
+        );
+    }
+
+    #[test]
+    fn test_parse_privsep() {
+        let yaml = load_yaml!("cli.yaml");
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--adapter-socket",
+            "/run/vi2c-helper.sock",
+            "--user",
+            "0",
+            "--group",
+            "5",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+
+        assert_eq!(
+            config.adapter_socket,
+            Some("/run/vi2c-helper.sock".to_string())
+        );
+        assert_eq!(
+            config.credentials,
+            Credentials::new(Some("0"), Some("5")).unwrap()
+        );
+
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--user",
+            "no-such-user",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::PrivsepFailure(privsep::Error::UserUnknown("no-such-user".to_string()))
+        );
+
+        // Helper socket, with its ownership and mode
+        assert_eq!(
+            helper_socket("/run/vi2c-helper.sock").unwrap(),
+            SocketConfig::new("/run/vi2c-helper.sock".to_string())
+        );
+        assert_eq!(
+            helper_socket("path=/run/vi2c-helper.sock,owner=0,mode=0600").unwrap(),
+            SocketConfig {
+                owner: Some(0),
+                mode: Some(0o600),
+                ..SocketConfig::new("/run/vi2c-helper.sock".to_string())
+            }
+        );
+        assert_eq!(
+            helper_socket("path=/run/vi2c-helper.sock,devices=4").unwrap_err(),
+            Error::SocketConfigInvalid(socket::Error::OptionInvalid(
+                "path=/run/vi2c-helper.sock,devices=4".to_string()
+            ))
+        );
+
+        // The helper doesn't pass the adapters to anyone.
+        let args = vec![
+            "prog",
+            "-l",
+            "1:4",
+            "--adapter-helper",
+            "/run/vi2c-helper.sock",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            start_helper(cmd_args).unwrap_err(),
+            Error::HelperPeersMissing
+        );
+    }
+
+    #[test]
+    fn test_check_configuration() {
+        let yaml = load_yaml!("cli.yaml");
+        let args = vec!["prog", "-s", "vi2c.sock", "-l", "1:4,2:32", "--check"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let report = check_configuration::<DummyDevice>(cmd_args);
+
+        assert_eq!(
+            report,
+            json!({
+                "ok": true,
+                "errors": [],
+                "adapters": [
+                    {
+                        "adapter": 1,
+                        "ok": true,
+                        "smbus": false,
+                        "error": null,
+                        "clients": [{ "addr": 4, "ok": true, "error": null }],
+                    },
+                    {
+                        "adapter": 2,
+                        "ok": true,
+                        "smbus": false,
+                        "error": null,
+                        "clients": [{ "addr": 32, "ok": true, "error": null }],
+                    },
+                ],
+            })
+        );
+
+        // The adapters are still checked along with an invalid configuration.
+        let args = vec!["prog", "-s", "vi2c.sock", "-c", "0", "-l", "1:4", "--check"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let report = check_configuration::<DummyDevice>(cmd_args);
+
+        assert_eq!(report["ok"], json!(false));
+        assert_eq!(
+            report["errors"],
+            json!([Error::SocketCountInvalid(0).to_string()])
+        );
+        assert_eq!(report["adapters"][0]["adapter"], json!(1));
+
+        // All the invalid options are reported.
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-c",
+            "0",
+            "-l",
+            "1:4",
+            "--unbind",
+            "4,9",
+            "--auto-increment",
+            "7,x",
+            "--check",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let report = check_configuration::<DummyDevice>(cmd_args);
+
+        let errors = report["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0], json!(Error::SocketCountInvalid(0).to_string()));
+        assert_eq!(errors[1], json!(Error::UnbindClientInvalid(9).to_string()));
+        assert_eq!(
+            errors[2],
+            json!(Error::AutoIncrementClientInvalid(7).to_string())
+        );
+        assert!(errors[3]
+            .as_str()
+            .unwrap()
+            .starts_with("Failed while parsing"));
+        assert_eq!(report["adapters"][0]["adapter"], json!(1));
+
+        // The adapters can't be received from the helper.
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--adapter-socket",
+            "/path/not/present/helper.sock",
+            "--check",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let report = check_configuration::<DummyDevice>(cmd_args);
+
+        assert_eq!(report["errors"].as_array().unwrap().len(), 1);
+        assert_eq!(report["adapters"], json!([]));
+    }
+
+    #[test]
+    fn test_parse_probe() {
+        let yaml = load_yaml!("cli.yaml");
+        let args = vec![
+            "prog",
+            "probe",
+            "1",
+            "3",
+            "--range",
+            "0x50-0x57",
+            "--mode",
+            "read",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config =
+            ProbeConfiguration::try_from(cmd_args.subcommand_matches("probe").unwrap()).unwrap();
+
+        assert_eq!(
+            config,
+            ProbeConfiguration {
+                adapters: vec![1, 3],
+                range: (0x50, 0x57),
+                mode: ProbeMode::Read,
+            }
+        );
+
+        let args = vec!["prog", "probe", "1"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config =
+            ProbeConfiguration::try_from(cmd_args.subcommand_matches("probe").unwrap()).unwrap();
+        assert_eq!(config.range, probe::DEFAULT_RANGE);
+        assert_eq!(config.mode, ProbeMode::Auto);
+
+        let args = vec!["prog", "probe", "1", "1"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            ProbeConfiguration::try_from(cmd_args.subcommand_matches("probe").unwrap())
+                .unwrap_err(),
+            Error::AdapterDuplicate(1)
+        );
+
+        let args = vec!["prog", "probe", "1", "--range", "0x50"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            ProbeConfiguration::try_from(cmd_args.subcommand_matches("probe").unwrap())
+                .unwrap_err(),
+            Error::ProbeFailure(probe::Error::AddressRangeInvalid("0x50".to_string()))
+        );
+
+        // The adapters are required.
+        assert!(App::from(yaml)
+            .try_get_matches_from(vec!["prog", "probe"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_unbind() {
+        let yaml = load_yaml!("cli.yaml");
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4:80,3:32",
+            "--unbind",
+            "80,32",
+            "--sysfs-root",
+            "/tmp/sys",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+
+        assert_eq!(config.unbind, vec![(1, 80), (3, 32)]);
+        assert_eq!(config.sysfs_root, "/tmp/sys");
+
+        let args = vec!["prog", "-s", "vi2c.sock", "-l", "1:4", "--unbind", "5"];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::UnbindClientInvalid(5)
+        );
+
+        // Unbinding requires the privileges until shutdown.
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--unbind",
+            "4",
+            "--user",
+            "0",
+        ];
+        assert!(App::from(yaml).try_get_matches_from(args).is_err());
+
+        // Nothing to unbind in the missing sysfs tree.
+        let unbinder = unbind_clients(&[(1, 4)], "/path/not/present").unwrap();
+        drop(unbinder);
+    }
+
+    #[test]
+    fn test_parse_hotplug() {
+        let yaml = load_yaml!("cli.yaml");
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--hotplug",
+            "--sandbox",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+
+        assert!(config.hotplug);
+        assert!(config.sandbox);
+
+        // The adapters are reopened by the daemon itself.
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--hotplug",
+            "--adapter-socket",
+            "helper.sock",
+        ];
+        assert!(App::from(yaml).try_get_matches_from(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_rate_limits() {
+        let yaml = load_yaml!("cli.yaml");
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-c",
+            "2",
+            "-l",
+            "1:4",
+            "--rate-limit",
+            "socket=all,ops=100",
+            "--rate-limit",
+            "adapter=1,bytes=4096,mode=reject",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+
+        assert_eq!(
+            config.rate_limits,
+            vec![
+                RateLimitConfig::try_from("socket=all,ops=100").unwrap(),
+                RateLimitConfig::try_from("adapter=1,bytes=4096,mode=reject").unwrap(),
+            ]
+        );
+
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-c",
+            "2",
+            "-l",
+            "1:4",
+            "--rate-limit",
+            "socket=2,ops=100",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::RateLimitSocketInvalid(2)
+        );
+
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--rate-limit",
+            "socket=0",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::RateLimitInvalid(ratelimit::Error::LimitMissing("socket=0".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_quirks() {
+        let yaml = load_yaml!("cli.yaml");
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4,3:5",
+            "--adapter-quirks",
+            "adapter=1,max-msgs=2",
+            "--adapter-quirks",
+            "adapter=3,max-len=32,no-combined",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+
+        assert_eq!(
+            config.quirks,
+            vec![
+                QuirksConfig::try_from("adapter=1,max-msgs=2").unwrap(),
+                QuirksConfig::try_from("adapter=3,max-len=32,no-combined").unwrap(),
+            ]
+        );
+
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--adapter-quirks",
+            "adapter=2,max-msgs=2",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::QuirksAdapterInvalid(2)
+        );
+
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--adapter-quirks",
+            "max-msgs=2",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::QuirksInvalid(quirks::Error::AdapterMissing("max-msgs=2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_auto_increment() {
+        let yaml = load_yaml!("cli.yaml");
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4:80,3:32",
+            "--auto-increment",
+            "80:16,32",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        let config = I2cConfiguration::try_from(cmd_args).unwrap();
+
+        assert_eq!(config.auto_increment, vec![(80, Some(16)), (32, None)]);
+
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4:80",
+            "--auto-increment",
+            "80:1k",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::ParseFailure("1k".parse::<u32>().unwrap_err())
+        );
+
+        let args = vec![
+            "prog",
+            "-s",
+            "vi2c.sock",
+            "-l",
+            "1:4",
+            "--auto-increment",
+            "5",
+        ];
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+        assert_eq!(
+            I2cConfiguration::try_from(cmd_args).unwrap_err(),
+            Error::AutoIncrementClientInvalid(5)
+        );
+    }
+
+    #[test]
+    fn test_fail_listener() {
+        // This will fail the listeners, before any thread is spawned.
+        let socket_name = Some("~/path/not/present/i2c");
+        let cmd_args = get_cmd_args(socket_name, "1:4,3:5", Some("5"));
+
+        assert_eq!(
+            start_backend::<DummyDevice>(cmd_args).unwrap_err(),
+            Error::SocketFailure(socket::Error::BindFailed(
+                "~/path/not/present/i2c0".to_string(),
+                libc::ENOENT
+            ))
+        );
+
+        // The configuration is invalid, nothing is started.
+        let cmd_args = get_cmd_args(socket_name, "1:4d", Some("5"));
+        assert_eq!(
+            start_backend::<DummyDevice>(cmd_args).unwrap_err(),
+            Error::ParseFailure("4d".parse::<u16>().unwrap_err())
+        );
+    }
+}
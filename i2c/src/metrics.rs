@@ -0,0 +1,483 @@
+// Transfer metrics and Prometheus endpoint
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use thiserror::Error as ThisError;
+use virtio_i2c::i2c::{Error as I2cError, I2cReq, I2C_M_RD};
+use virtio_i2c::ratelimit::RateLimiter;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the metrics endpoint
+pub enum Error {
+    #[error("Failed to create metrics socket at {0}")]
+    SocketCreateFailed(String),
+}
+
+/// How long a scrape request may take to arrive, or its response to be sent,
+/// before the connection is dropped, for the other ones not to wait behind.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct Labels {
+    socket: usize,
+    // Unknown for transfers to clients which aren't in the I2C map.
+    adapter: Option<u32>,
+    // Unknown as well then, the guest picks these addresses, and they would otherwise grow the
+    // metrics without bounds.
+    client: Option<u16>,
+}
+
+impl Labels {
+    fn render(&self) -> String {
+        let adapter = match self.adapter {
+            Some(adapter) => adapter.to_string(),
+            None => String::new(),
+        };
+        let client = match self.client {
+            Some(client) => client.to_string(),
+            None => String::new(),
+        };
+
+        format!(
+            "socket=\"{}\",adapter=\"{}\",client=\"{}\"",
+            self.socket, adapter, client
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    transfers: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    errors: BTreeMap<&'static str, u64>,
+    // Not cumulative, the last entry counts the transfers above all bounds.
+    latency_buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: f64,
+}
+
+// Extracts the value of a counter, for rendering.
+type CounterValue = fn(&Counters) -> u64;
+
+/// Per socket, adapter and client transfer metrics.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<BTreeMap<Labels, Counters>>,
+    // The limiters keep their own counters.
+    limiters: Mutex<Vec<Arc<RateLimiter>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accounts for a transfer of `reqs`, issued by the guest attached to
+    /// `socket`. The transfers to clients without an `adapter` all share the
+    /// same unknown client.
+    pub fn record(
+        &self,
+        socket: usize,
+        adapter: Option<u32>,
+        reqs: &[I2cReq],
+        result: &std::result::Result<(), I2cError>,
+        elapsed: Duration,
+    ) {
+        let labels = Labels {
+            socket,
+            adapter,
+            client: adapter.and(reqs.first().map(|req| req.addr)),
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        let counters = inner.entry(labels).or_default();
+
+        counters.transfers += 1;
+
+        match result {
+            Ok(()) => {
+                for req in reqs {
+                    if (req.flags & I2C_M_RD) != 0 {
+                        counters.bytes_read += req.len as u64;
+                    } else {
+                        counters.bytes_written += req.len as u64;
+                    }
+                }
+            }
+            Err(e) => *counters.errors.entry(e.name()).or_insert(0) += 1,
+        }
+
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        counters.latency_buckets[bucket] += 1;
+        counters.latency_sum += secs;
+    }
+
+    /// Exposes the counters of a rate limiter.
+    pub(crate) fn add_rate_limiter(&self, limiter: Arc<RateLimiter>) {
+        self.limiters.lock().unwrap().push(limiter);
+    }
+
+    /// Returns the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        let counters: [(&str, &str, CounterValue); 3] = [
+            (
+                "vhost_device_i2c_transfers_total",
+                "Number of I2C transfers.",
+                |c| c.transfers,
+            ),
+            (
+                "vhost_device_i2c_read_bytes_total",
+                "Number of bytes read by successful I2C transfers.",
+                |c| c.bytes_read,
+            ),
+            (
+                "vhost_device_i2c_written_bytes_total",
+                "Number of bytes written by successful I2C transfers.",
+                |c| c.bytes_written,
+            ),
+        ];
+
+        // Writing to a String can't fail.
+        for (name, help, value) in counters.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+
+            for (labels, c) in inner.iter() {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels.render(), value(c));
+            }
+        }
+
+        let name = "vhost_device_i2c_errors_total";
+        let _ = writeln!(out, "# HELP {} Number of failed I2C transfers.", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+
+        for (labels, c) in inner.iter() {
+            for (error, count) in c.errors.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{{},error=\"{}\"}} {}",
+                    name,
+                    labels.render(),
+                    error,
+                    count
+                );
+            }
+        }
+
+        let name = "vhost_device_i2c_transfer_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Latency of I2C transfers.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        for (labels, c) in inner.iter() {
+            let labels = labels.render();
+            let mut count = 0;
+
+            for (i, bucket) in c.latency_buckets.iter().enumerate() {
+                let le = match LATENCY_BUCKETS.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+
+                count += bucket;
+                let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+            }
+
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, c.latency_sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+        }
+        drop(inner);
+
+        let limiters = self.limiters.lock().unwrap();
+        if limiters.is_empty() {
+            return out;
+        }
+
+        let name = "vhost_device_i2c_rate_limited_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Number of I2C transfers delayed or rejected by rate limits.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+
+        for limiter in limiters.iter() {
+            let (kind, index) = limiter.scope().labels();
+            let (delayed, rejected, _) = limiter.counters();
+
+            for (action, count) in [("delayed", delayed), ("rejected", rejected)].iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{{}=\"{}\",action=\"{}\"}} {}",
+                    name, kind, index, action, count
+                );
+            }
+        }
+
+        let name = "vhost_device_i2c_rate_limit_delay_seconds_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time I2C transfers were delayed by rate limits.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+
+        for limiter in limiters.iter() {
+            let (kind, index) = limiter.scope().labels();
+            let (_, _, delay) = limiter.counters();
+
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                kind,
+                index,
+                delay.as_secs_f64()
+            );
+        }
+
+        out
+    }
+
+    // Answers a single scrape request, with a minimal HTTP response.
+    fn serve<S: Read + Write>(&self, mut stream: S) -> io::Result<()> {
+        // The request itself doesn't matter, all the metrics are always
+        // returned. Read it anyway so the peer doesn't see a reset.
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf)?;
+
+        let body = self.render();
+        write!(
+            stream,
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+}
+
+/// Location where the metrics are exposed.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum MetricsEndpoint {
+    Unix(String),
+    // Loopback TCP port, the metrics aren't meant to be exposed remotely.
+    Tcp(u16),
+}
+
+enum MetricsListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// Serves the metrics over HTTP, to be scraped by Prometheus.
+pub struct MetricsServer {
+    listener: MetricsListener,
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsServer {
+    pub(crate) fn new(endpoint: &MetricsEndpoint, metrics: Arc<Metrics>) -> Result<Self> {
+        let listener = match endpoint {
+            MetricsEndpoint::Unix(path) => {
+                // Remove a stale socket, left behind by a previous instance.
+                let _ = fs::remove_file(path);
+                MetricsListener::Unix(
+                    UnixListener::bind(path)
+                        .map_err(|_| Error::SocketCreateFailed(path.to_string()))?,
+                )
+            }
+            MetricsEndpoint::Tcp(port) => MetricsListener::Tcp(
+                TcpListener::bind((Ipv4Addr::LOCALHOST, *port))
+                    .map_err(|_| Error::SocketCreateFailed(format!("127.0.0.1:{}", port)))?,
+            ),
+        };
+
+        info!("Exposing metrics at {:?}", endpoint);
+        Ok(MetricsServer { listener, metrics })
+    }
+
+    /// Serves the scrape requests one by one, forever. Idle clients are
+    /// dropped after SCRAPE_TIMEOUT.
+    pub fn run(&self) {
+        loop {
+            let result = match &self.listener {
+                MetricsListener::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+                    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+                    self.metrics.serve(stream)
+                }),
+                MetricsListener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+                    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+                    self.metrics.serve(stream)
+                }),
+            };
+
+            if let Err(e) = result {
+                warn!("Failed to serve metrics: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::os::unix::net::UnixStream;
+    use vmm_sys_util::errno::Error as IoError;
+
+    use virtio_i2c::ratelimit::RateLimitConfig;
+
+    fn reqs(addr: u16) -> Vec<I2cReq> {
+        vec![
+            I2cReq {
+                addr,
+                flags: 0,
+                len: 1,
+                buf: vec![0].into(),
+            },
+            I2cReq {
+                addr,
+                flags: I2C_M_RD,
+                len: 2,
+                buf: vec![0; 2].into(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_metrics_render() {
+        let metrics = Metrics::new();
+
+        metrics.record(0, Some(1), &reqs(4), &Ok(()), Duration::from_micros(50));
+        metrics.record(0, Some(1), &reqs(4), &Ok(()), Duration::from_millis(2));
+        metrics.record(
+            0,
+            Some(1),
+            &reqs(4),
+            &Err(I2cError::IoctlFailure("rdwr", IoError::new(libc::ENXIO))),
+            Duration::from_secs(1),
+        );
+        // Unknown clients share the same counters.
+        for addr in [8, 9].iter() {
+            metrics.record(
+                1,
+                None,
+                &reqs(*addr),
+                &Err(I2cError::ClientAddressInvalid),
+                Duration::from_micros(1),
+            );
+        }
+
+        let out = metrics.render();
+        let labels = "socket=\"0\",adapter=\"1\",client=\"4\"";
+
+        assert!(out.contains("# TYPE vhost_device_i2c_transfers_total counter\n"));
+        assert!(out.contains(&format!(
+            "vhost_device_i2c_transfers_total{{{}}} 3\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "vhost_device_i2c_read_bytes_total{{{}}} 4\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "vhost_device_i2c_written_bytes_total{{{}}} 2\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "vhost_device_i2c_errors_total{{{},error=\"IoctlFailure\"}} 1\n",
+            labels
+        )));
+        assert!(out.contains(
+            "vhost_device_i2c_errors_total{socket=\"1\",adapter=\"\",client=\"\",error=\"ClientAddressInvalid\"} 2\n"
+        ));
+        assert!(!out.contains("client=\"8\""));
+
+        // Buckets are cumulative
+        let name = "vhost_device_i2c_transfer_duration_seconds";
+        assert!(out.contains(&format!("{}_bucket{{{},le=\"0.0001\"}} 1\n", name, labels)));
+        assert!(out.contains(&format!("{}_bucket{{{},le=\"0.0025\"}} 2\n", name, labels)));
+        assert!(out.contains(&format!("{}_bucket{{{},le=\"0.1\"}} 2\n", name, labels)));
+        assert!(out.contains(&format!("{}_bucket{{{},le=\"+Inf\"}} 3\n", name, labels)));
+        assert!(out.contains(&format!("{}_count{{{}}} 3\n", name, labels)));
+    }
+
+    #[test]
+    fn test_metrics_rate_limits() {
+        let metrics = Metrics::new();
+        assert!(!metrics.render().contains("rate_limit"));
+
+        let config = RateLimitConfig::try_from("adapter=1,ops=1,mode=reject").unwrap();
+        let limiter = Arc::new(RateLimiter::new(&config, config.scope));
+        metrics.add_rate_limiter(limiter.clone());
+
+        assert!(limiter.acquire(&reqs(4)));
+        assert!(!limiter.acquire(&reqs(4)));
+
+        let out = metrics.render();
+        assert!(out
+            .contains("vhost_device_i2c_rate_limited_total{adapter=\"1\",action=\"delayed\"} 0\n"));
+        assert!(out.contains(
+            "vhost_device_i2c_rate_limited_total{adapter=\"1\",action=\"rejected\"} 1\n"
+        ));
+        assert!(out.contains("vhost_device_i2c_rate_limit_delay_seconds_total{adapter=\"1\"} 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_serve() {
+        let metrics = Metrics::new();
+        metrics.record(2, Some(3), &reqs(5), &Ok(()), Duration::from_micros(50));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        metrics.serve(server).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        let body = metrics.render();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(response.ends_with(&body));
+    }
+
+    #[test]
+    fn test_metrics_server_failure() {
+        let endpoint = MetricsEndpoint::Unix("/path/not/present/metrics.sock".to_string());
+        assert_eq!(
+            MetricsServer::new(&endpoint, Arc::new(Metrics::new())).err(),
+            Some(Error::SocketCreateFailed(
+                "/path/not/present/metrics.sock".to_string()
+            ))
+        );
+    }
+}
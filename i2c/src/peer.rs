@@ -0,0 +1,570 @@
+// Peer credential checks on vhost-user sockets
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::{c_void, gid_t, pid_t, ucred, uid_t};
+use log::{info, warn};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem::{size_of, size_of_val, zeroed};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::spawn;
+
+use thiserror::Error as ThisError;
+use vmm_sys_util::tempdir::TempDir;
+
+use crate::socket::{lookup_group, lookup_user};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Size of the vhost-user message header: request, flags and payload size.
+const HEADER_SIZE: usize = 12;
+/// Largest vhost-user payload relayed, well above the size of any known request.
+const MAX_PAYLOAD_SIZE: usize = 0x10000;
+/// Largest number of file descriptors relayed with a message.
+const MAX_FDS: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the peer credential checks
+pub enum Error {
+    #[error("Invalid peer rule: {0}")]
+    RuleInvalid(String),
+    #[error("Invalid peer user: {0}")]
+    UserInvalid(String),
+    #[error("Invalid peer group: {0}")]
+    GroupInvalid(String),
+    #[error("Failed to create private socket directory")]
+    PrivateDirFailed,
+}
+
+/// Peers allowed to connect to the vhost-user sockets, a peer matching any of the rules is
+/// allowed. Rules are in the format uid=<user>, gid=<group> or exe=<path>.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct PeerPolicy {
+    uids: Vec<uid_t>,
+    gids: Vec<gid_t>,
+    exes: Vec<PathBuf>,
+}
+
+impl PeerPolicy {
+    pub fn push(&mut self, rule: &str) -> Result<()> {
+        let (key, value) = match rule.find('=') {
+            Some(pos) => (&rule[..pos], &rule[pos + 1..]),
+            None => return Err(Error::RuleInvalid(rule.to_string())),
+        };
+
+        match key {
+            "uid" => self
+                .uids
+                .push(lookup_user(value).map_err(|_| Error::UserInvalid(value.to_string()))?),
+            "gid" => self
+                .gids
+                .push(lookup_group(value).map_err(|_| Error::GroupInvalid(value.to_string()))?),
+            "exe" if value.starts_with('/') => self.exes.push(PathBuf::from(value)),
+            _ => return Err(Error::RuleInvalid(rule.to_string())),
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty() && self.gids.is_empty() && self.exes.is_empty()
+    }
+
+    /// Checks the credentials of a peer against the rules.
+    pub fn allows(&self, cred: &ucred) -> bool {
+        self.uids.contains(&cred.uid)
+            || self.gids.contains(&cred.gid)
+            || (!self.exes.is_empty()
+                && match peer_exe(cred.pid) {
+                    Some(exe) => self.exes.contains(&exe),
+                    None => false,
+                })
+    }
+}
+
+impl TryFrom<Vec<&str>> for PeerPolicy {
+    type Error = Error;
+
+    fn try_from(rules: Vec<&str>) -> Result<Self> {
+        let mut policy = PeerPolicy::default();
+
+        for rule in rules {
+            policy.push(rule)?;
+        }
+        Ok(policy)
+    }
+}
+
+fn peer_exe(pid: pid_t) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
+
+/// Returns the credentials of the process connected to the socket.
+pub(crate) fn peer_credentials(stream: &UnixStream) -> io::Result<ucred> {
+    // SAFETY: Safe as ucred is a plain C structure.
+    let mut cred: ucred = unsafe { zeroed() };
+    let mut len = size_of::<ucred>() as libc::socklen_t;
+
+    // SAFETY: Safe as the kernel only writes within the bounds of `cred`.
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred)
+}
+
+/// Accepts the connections on a vhost-user socket in place of the daemon, and relays the
+/// connections of the allowed peers to a private socket the daemon listens on.
+pub(crate) struct PeerGate {
+    listener: UnixListener,
+    policy: Arc<PeerPolicy>,
+    // Only accessible to the daemon, removed when stopped or dropped.
+    dir: TempDir,
+    stopping: AtomicBool,
+}
+
+impl PeerGate {
+    /// Creates the private directory of the gate of the socket at `socket`, next to it, where
+    /// the sandbox allows creating and removing it.
+    pub fn private_dir(socket: &str) -> Result<TempDir> {
+        TempDir::new_with_prefix(format!("{}.gate", socket)).map_err(|_| Error::PrivateDirFailed)
+    }
+
+    pub fn new(listener: UnixListener, policy: Arc<PeerPolicy>, dir: TempDir) -> Self {
+        PeerGate {
+            listener,
+            policy,
+            dir,
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    /// Path of the private socket the daemon listens on.
+    pub fn private_path(&self) -> PathBuf {
+        self.dir.as_path().join("vhost-user.sock")
+    }
+
+    /// Serves the connections one by one, until stopped.
+    pub fn run(&self) {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Some(peer) = self.verify(&stream) {
+                        self.forward(peer, stream);
+                    }
+                }
+                Err(e) => {
+                    if !self.stopping.load(Ordering::SeqCst) {
+                        warn!("Failed to accept vhost-user connection: {}", e);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Stops accepting connections, and removes the private socket and its directory. The
+    /// connections already relayed are left alone.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        // SAFETY: Safe as the fd is valid, this wakes up the thread blocked in accept().
+        unsafe { libc::shutdown(self.listener.as_raw_fd(), libc::SHUT_RDWR) };
+
+        if let Err(e) = self.dir.remove() {
+            warn!("Failed to remove {:?}: {}", self.dir.as_path(), e);
+        }
+    }
+
+    /// Returns the pid of an allowed peer, or None if it is rejected.
+    fn verify(&self, stream: &UnixStream) -> Option<pid_t> {
+        match peer_credentials(stream) {
+            Ok(cred) if self.policy.allows(&cred) => Some(cred.pid),
+            Ok(cred) => {
+                warn!(
+                    "Rejected vhost-user peer: pid {} ({:?}), uid {}, gid {}",
+                    cred.pid,
+                    peer_exe(cred.pid),
+                    cred.uid,
+                    cred.gid
+                );
+                None
+            }
+            Err(e) => {
+                warn!("Rejected vhost-user peer, no credentials: {}", e);
+                None
+            }
+        }
+    }
+
+    fn forward(&self, pid: pid_t, stream: UnixStream) {
+        // The daemon only listens while no guest is connected.
+        let daemon = match UnixStream::connect(self.private_path()) {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                warn!("Dropped vhost-user peer {}, daemon busy: {}", pid, e);
+                return;
+            }
+        };
+
+        info!("Accepted vhost-user peer {}", pid);
+
+        match (stream.try_clone(), daemon.try_clone()) {
+            (Ok(stream_clone), Ok(daemon_clone)) => {
+                spawn(move || relay(stream_clone, daemon_clone));
+                spawn(move || relay(daemon, stream));
+            }
+            _ => warn!("Dropped vhost-user peer {}, failed to clone sockets", pid),
+        }
+    }
+}
+
+/// Relays the vhost-user messages, along with their file descriptors, until either side hangs
+/// up. Both sockets are shut down afterwards, to stop the relay in the other direction as well.
+fn relay(from: UnixStream, to: UnixStream) {
+    loop {
+        match relay_message(&from, &to) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => warn!("Failed to relay vhost-user message: {}", e),
+        }
+        break;
+    }
+
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+/// Relays a single message, returns false once the sender hangs up.
+fn relay_message(mut from: &UnixStream, to: &UnixStream) -> io::Result<bool> {
+    let mut msg = vec![0u8; HEADER_SIZE];
+    let (len, fds) = recv_with_fds(from, &mut msg)?;
+
+    if len == 0 {
+        return Ok(false);
+    }
+
+    from.read_exact(&mut msg[len..])?;
+
+    let mut size = [0u8; 4];
+    size.copy_from_slice(&msg[8..HEADER_SIZE]);
+    let size = u32::from_le_bytes(size) as usize;
+
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("payload too large: {}", size),
+        ));
+    }
+
+    msg.resize(HEADER_SIZE + size, 0);
+    from.read_exact(&mut msg[HEADER_SIZE..])?;
+
+    let fds: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+    send_with_fds(to, &msg, &fds)?;
+
+    // The file descriptors are closed here, once duplicated to the receiver.
+    Ok(true)
+}
+
+fn cmsg_space() -> usize {
+    // SAFETY: Safe as CMSG_SPACE() only computes a size.
+    unsafe { libc::CMSG_SPACE((MAX_FDS * size_of::<RawFd>()) as u32) as usize }
+}
+
+/// Receives data and the file descriptors sent along with it.
+pub(crate) fn recv_with_fds(socket: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Vec<File>)> {
+    // u64 for the alignment of the control messages.
+    let mut cmsg_buf = vec![0u64; cmsg_space() / size_of::<u64>() + 1];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+
+    // SAFETY: Safe as msghdr is a plain C structure.
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = cmsg_space() as _;
+
+    // SAFETY: Safe as the buffers are valid for the lengths passed.
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut files = Vec::new();
+
+    // SAFETY: Safe as the control messages were written by the kernel, within msg_controllen,
+    // and the received file descriptors are owned by us.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count =
+                    ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+
+                for i in 0..count {
+                    files.push(File::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many file descriptors",
+        ));
+    }
+
+    Ok((len as usize, files))
+}
+
+/// Sends data along with file descriptors.
+pub(crate) fn send_with_fds(socket: &UnixStream, buf: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let mut cmsg_buf = vec![0u64; cmsg_space() / size_of::<u64>() + 1];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+
+    // SAFETY: Safe as msghdr is a plain C structure.
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    if !fds.is_empty() {
+        let fds_len = size_of_val(fds);
+
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+
+        // SAFETY: Safe as the control buffer is large enough for MAX_FDS file descriptors,
+        // which is the most received with a message.
+        unsafe {
+            msg.msg_controllen = libc::CMSG_SPACE(fds_len as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+
+            let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+            for (i, fd) in fds.iter().enumerate() {
+                data.add(i).write_unaligned(*fd);
+            }
+        }
+    }
+
+    let mut sent = 0;
+    while sent < buf.len() {
+        // SAFETY: Safe as the buffers are valid for the lengths passed.
+        let len = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The file descriptors are sent along with the first chunk only.
+        sent += len as usize;
+        iov.iov_base = buf[sent..].as_ptr() as *mut c_void;
+        iov.iov_len = buf.len() - sent;
+        msg.msg_iov = &mut iov;
+        msg.msg_control = std::ptr::null_mut();
+        msg.msg_controllen = 0;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::eventfd::EventFd;
+
+    fn own_credentials() -> ucred {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        peer_credentials(&stream).unwrap()
+    }
+
+    #[test]
+    fn test_peer_policy() {
+        let cred = own_credentials();
+
+        // SAFETY: Safe as these just return the ids of the process.
+        assert_eq!(cred.uid, unsafe { libc::getuid() });
+        assert_eq!(cred.gid, unsafe { libc::getgid() });
+        assert_eq!(cred.pid, std::process::id() as pid_t);
+
+        let other = (cred.uid + 1).to_string();
+        let policy = PeerPolicy::try_from(vec![format!("uid={}", other).as_str()]).unwrap();
+        assert!(!policy.allows(&cred));
+
+        let policy = PeerPolicy::try_from(vec![format!("uid={}", cred.uid).as_str()]).unwrap();
+        assert!(policy.allows(&cred));
+
+        let policy = PeerPolicy::try_from(vec![format!("gid={}", cred.gid).as_str()]).unwrap();
+        assert!(policy.allows(&cred));
+
+        let exe = std::env::current_exe().unwrap();
+        let rule = format!("exe={}", exe.to_str().unwrap());
+        let policy = PeerPolicy::try_from(vec![rule.as_str()]).unwrap();
+        assert!(policy.allows(&cred));
+
+        let policy = PeerPolicy::try_from(vec!["exe=/path/not/present"]).unwrap();
+        assert!(!policy.allows(&cred));
+
+        assert!(PeerPolicy::default().is_empty());
+        assert_eq!(
+            PeerPolicy::try_from(vec!["uid"]).unwrap_err(),
+            Error::RuleInvalid("uid".to_string())
+        );
+        assert_eq!(
+            PeerPolicy::try_from(vec!["pid=1"]).unwrap_err(),
+            Error::RuleInvalid("pid=1".to_string())
+        );
+        assert_eq!(
+            PeerPolicy::try_from(vec!["exe=qemu"]).unwrap_err(),
+            Error::RuleInvalid("exe=qemu".to_string())
+        );
+        assert_eq!(
+            PeerPolicy::try_from(vec!["uid=no-such-user"]).unwrap_err(),
+            Error::UserInvalid("no-such-user".to_string())
+        );
+    }
+
+    #[test]
+    fn test_relay() {
+        let (master, relay_in) = UnixStream::pair().unwrap();
+        let (relay_out, slave) = UnixStream::pair().unwrap();
+        let handle = spawn(move || relay(relay_in, relay_out));
+
+        // Header with a 4 byte payload, and an eventfd.
+        let event = EventFd::new(0).unwrap();
+        let msg = [1, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 0xa, 0xb, 0xc, 0xd];
+        send_with_fds(&master, &msg, &[event.as_raw_fd()]).unwrap();
+
+        let mut buf = [0u8; 16];
+        let (len, files) = recv_with_fds(&slave, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &msg[..len]);
+        (&slave).read_exact(&mut buf[len..]).unwrap();
+        assert_eq!(buf, msg);
+
+        // The eventfd received is the one sent.
+        assert_eq!(files.len(), 1);
+        event.write(5).unwrap();
+        let mut value = [0u8; 8];
+        (&files[0]).read_exact(&mut value).unwrap();
+        assert_eq!(u64::from_ne_bytes(value), 5);
+
+        // Oversized payloads stop the relay.
+        (&master)
+            .write_all(&[1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 1])
+            .unwrap();
+        handle.join().unwrap();
+        assert_eq!((&slave).read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_peer_gate() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-peer").unwrap();
+        let path = dir.as_path().join("vi2c.sock");
+        let cred = own_credentials();
+
+        // Rejected peer
+        let other = (cred.uid + 1).to_string();
+        let policy = PeerPolicy::try_from(vec![format!("uid={}", other).as_str()]).unwrap();
+        let private_dir = PeerGate::private_dir(path.to_str().unwrap()).unwrap();
+        let gate = PeerGate::new(
+            UnixListener::bind(&path).unwrap(),
+            Arc::new(policy),
+            private_dir,
+        );
+        let private = UnixListener::bind(gate.private_path()).unwrap();
+
+        let mut peer = UnixStream::connect(&path).unwrap();
+        let (stream, _) = gate.listener.accept().unwrap();
+        assert_eq!(gate.verify(&stream), None);
+        drop(stream);
+        assert_eq!(peer.read(&mut [0u8; 4]).unwrap(), 0);
+        drop(gate);
+        drop(private);
+
+        // Allowed peer
+        fs::remove_file(&path).unwrap();
+        let policy = PeerPolicy::try_from(vec![format!("uid={}", cred.uid).as_str()]).unwrap();
+        let private_dir = PeerGate::private_dir(path.to_str().unwrap()).unwrap();
+        let gate = PeerGate::new(
+            UnixListener::bind(&path).unwrap(),
+            Arc::new(policy),
+            private_dir,
+        );
+        let private = UnixListener::bind(gate.private_path()).unwrap();
+
+        let peer = UnixStream::connect(&path).unwrap();
+        let (stream, _) = gate.listener.accept().unwrap();
+        let pid = gate.verify(&stream).unwrap();
+        assert_eq!(pid, std::process::id() as pid_t);
+        gate.forward(pid, stream);
+
+        let msg = [2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        (&peer).write_all(&msg).unwrap();
+
+        let (mut daemon, _) = private.accept().unwrap();
+        let mut buf = [0u8; 12];
+        daemon.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, msg);
+
+        // The private socket directory is next to the socket, and removed with the gate.
+        let private_dir = gate.dir.as_path().to_path_buf();
+        assert_eq!(private_dir.parent(), Some(dir.as_path()));
+        drop(gate);
+        assert!(!private_dir.exists());
+    }
+
+    #[test]
+    fn test_peer_gate_stop() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-peer").unwrap();
+        let path = dir.as_path().join("vi2c.sock");
+        let policy = PeerPolicy::try_from(vec!["uid=0"]).unwrap();
+        let private_dir = PeerGate::private_dir(path.to_str().unwrap()).unwrap();
+        let gate = Arc::new(PeerGate::new(
+            UnixListener::bind(&path).unwrap(),
+            Arc::new(policy),
+            private_dir,
+        ));
+        let _private = UnixListener::bind(gate.private_path()).unwrap();
+
+        let server = gate.clone();
+        let handle = spawn(move || server.run());
+
+        // The thread waiting for a connection returns, and the private socket is removed
+        // while the gate is still referenced.
+        gate.stop();
+        handle.join().unwrap();
+        assert!(!gate.dir.as_path().exists());
+    }
+}
@@ -0,0 +1,377 @@
+// Privilege separation, with the adapters opened by a privileged helper
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::{gid_t, uid_t};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem::zeroed;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::ptr::null_mut;
+
+use thiserror::Error as ThisError;
+use virtio_i2c::i2c::{I2cDevice, I2cMap};
+
+use crate::peer::{peer_credentials, recv_with_fds, send_with_fds, PeerPolicy};
+use crate::socket::{lookup_group, lookup_user};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Version 3 of the capabilities ABI, with 64-bit capability sets.
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+/// Number of 32-bit words in the capability sets for version 3.
+const LINUX_CAPABILITY_U32S_3: usize = 2;
+/// Upper bound of the capabilities dropped from the bounding set.
+const MAX_CAPABILITY: libc::c_int = 63;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to privilege separation
+pub enum Error {
+    #[error("Unknown user: {0}")]
+    UserUnknown(String),
+    #[error("Unknown group: {0}")]
+    GroupUnknown(String),
+    #[error("Failed to connect to adapter helper at {0}: {1}")]
+    HelperConnectFailed(String, i32),
+    #[error("Failed to receive adapters: {0}")]
+    AdapterReceiveFailed(i32),
+    #[error("Invalid adapter message, with {0} file descriptor(s)")]
+    AdapterMessageInvalid(usize),
+    #[error("Failed to drop privileges, {0} failed: {1}")]
+    DropFailed(&'static str, i32),
+}
+
+/// User and group to switch to, once the adapters are opened.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Credentials {
+    uid: Option<uid_t>,
+    gid: Option<gid_t>,
+}
+
+impl Credentials {
+    /// Looks up the user and group, the primary group of the user is used if no group is
+    /// passed.
+    pub fn new(user: Option<&str>, group: Option<&str>) -> Result<Self> {
+        let uid = match user {
+            Some(user) => {
+                Some(lookup_user(user).map_err(|_| Error::UserUnknown(user.to_string()))?)
+            }
+            None => None,
+        };
+
+        let gid = match (group, uid) {
+            (Some(group), _) => {
+                Some(lookup_group(group).map_err(|_| Error::GroupUnknown(group.to_string()))?)
+            }
+            (None, Some(uid)) => Some(
+                primary_group(uid).ok_or_else(|| Error::UserUnknown(user.unwrap().to_string()))?,
+            ),
+            (None, None) => None,
+        };
+
+        Ok(Credentials { uid, gid })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none()
+    }
+
+    /// Switches to the group and user, and drops all the capabilities. This must be called
+    /// before spawning any threads, which would otherwise keep the privileges.
+    pub fn drop_privileges(&self) -> Result<()> {
+        if let Some(gid) = self.gid {
+            // SAFETY: Safe as the list holds a single valid group.
+            if unsafe { libc::setgroups(1, &gid) } != 0 {
+                return Err(Error::DropFailed("setgroups", errno()));
+            }
+
+            // SAFETY: Safe as setgid() doesn't access memory.
+            if unsafe { libc::setgid(gid) } != 0 {
+                return Err(Error::DropFailed("setgid", errno()));
+            }
+        }
+
+        // The bounding set can only be changed with CAP_SETPCAP, which is gone with the user.
+        for cap in 0..=MAX_CAPABILITY {
+            // SAFETY: Safe as prctl() doesn't access memory for PR_CAPBSET_DROP. Unknown
+            // capabilities fail with EINVAL, and are the last ones.
+            if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } != 0 {
+                break;
+            }
+        }
+
+        if let Some(uid) = self.uid {
+            // SAFETY: Safe as setuid() doesn't access memory.
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(Error::DropFailed("setuid", errno()));
+            }
+        }
+
+        // Changing to a non-root user clears the capabilities already, this covers root and
+        // the group only case.
+        clear_capabilities()?;
+
+        // SAFETY: Safe as setuid() doesn't access memory.
+        if matches!(self.uid, Some(uid) if uid != 0) && unsafe { libc::setuid(0) } == 0 {
+            return Err(Error::DropFailed("setuid", 0));
+        }
+
+        info!(
+            "Dropped privileges to uid {:?}, gid {:?}",
+            self.uid, self.gid
+        );
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn clear_capabilities() -> Result<()> {
+    let header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [CapUserData::default(); LINUX_CAPABILITY_U32S_3];
+
+    // SAFETY: Safe as the header and the data are valid for the capabilities version passed.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            &header as *const CapUserHeader,
+            data.as_ptr(),
+        )
+    };
+
+    if ret != 0 {
+        return Err(Error::DropFailed("capset", errno()));
+    }
+    Ok(())
+}
+
+fn primary_group(uid: uid_t) -> Option<gid_t> {
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = null_mut();
+
+    // SAFETY: Safe as the structure and the buffer are valid for the lengths passed, and
+    // libc::passwd is a plain C structure.
+    unsafe {
+        let mut passwd: libc::passwd = zeroed();
+
+        let ret = libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result);
+
+        if ret == 0 && !result.is_null() {
+            return Some(passwd.pw_gid);
+        }
+    }
+    None
+}
+
+fn errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// Passes the adapters to the daemons connecting to the helper socket, forever. Each adapter
+/// is sent in a separate message, with its number and file descriptor. Only the peers allowed
+/// by the policy are served, none if it is empty.
+pub(crate) fn serve_adapters<D: I2cDevice + AsRawFd>(
+    listener: &UnixListener,
+    i2c_map: &I2cMap<D>,
+    policy: &PeerPolicy,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept adapter helper connection: {}", e);
+                return;
+            }
+        };
+
+        match peer_credentials(&stream) {
+            Ok(cred) if policy.allows(&cred) => {
+                info!("Passing adapters to pid {}", cred.pid);
+            }
+            Ok(cred) => {
+                warn!(
+                    "Rejected adapter helper peer: pid {}, uid {}, gid {}",
+                    cred.pid, cred.uid, cred.gid
+                );
+                continue;
+            }
+            Err(e) => {
+                warn!("Rejected adapter helper peer, no credentials: {}", e);
+                continue;
+            }
+        }
+
+        if let Err(e) = send_adapters(&stream, i2c_map) {
+            warn!("Failed to pass adapters: {}", e);
+        }
+    }
+}
+
+fn send_adapters<D: I2cDevice + AsRawFd>(
+    stream: &UnixStream,
+    i2c_map: &I2cMap<D>,
+) -> io::Result<()> {
+    for (adapter_no, fd) in i2c_map.adapter_fds() {
+        send_with_fds(stream, &adapter_no.to_le_bytes(), &[fd])?;
+    }
+    Ok(())
+}
+
+/// Receives the adapters from the helper listening at `path`.
+pub(crate) fn receive_adapters(path: &str) -> Result<Vec<(u32, File)>> {
+    let stream = UnixStream::connect(path)
+        .map_err(|e| Error::HelperConnectFailed(path.to_string(), e.raw_os_error().unwrap_or(0)))?;
+
+    let adapters = recv_adapters(&stream)?;
+    info!("Received {} adapter(s) from {}", adapters.len(), path);
+    Ok(adapters)
+}
+
+fn recv_adapters(mut stream: &UnixStream) -> Result<Vec<(u32, File)>> {
+    let receive_failed = |e: io::Error| Error::AdapterReceiveFailed(e.raw_os_error().unwrap_or(0));
+    let mut adapters = Vec::new();
+
+    // The helper hangs up once all the adapters are sent.
+    loop {
+        let mut buf = [0u8; 4];
+        let (len, mut files) = recv_with_fds(stream, &mut buf).map_err(receive_failed)?;
+
+        if len == 0 {
+            break;
+        }
+
+        stream.read_exact(&mut buf[len..]).map_err(receive_failed)?;
+
+        if files.len() != 1 {
+            return Err(Error::AdapterMessageInvalid(files.len()));
+        }
+        adapters.push((u32::from_le_bytes(buf), files.remove(0)));
+    }
+
+    Ok(adapters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    use std::thread::spawn;
+    use virtio_i2c::config::AdapterConfig;
+    use virtio_i2c::i2c::mock::DummyDevice;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_credentials() {
+        assert!(Credentials::new(None, None).unwrap().is_empty());
+
+        // The primary group of the user is used by default.
+        assert_eq!(
+            Credentials::new(Some("root"), None).unwrap(),
+            Credentials {
+                uid: Some(0),
+                gid: Some(0)
+            }
+        );
+        assert_eq!(
+            Credentials::new(Some("0"), Some("5")).unwrap(),
+            Credentials {
+                uid: Some(0),
+                gid: Some(5)
+            }
+        );
+        assert_eq!(
+            Credentials::new(None, Some("5")).unwrap(),
+            Credentials {
+                uid: None,
+                gid: Some(5)
+            }
+        );
+
+        assert_eq!(
+            Credentials::new(Some("no-such-user"), None).unwrap_err(),
+            Error::UserUnknown("no-such-user".to_string())
+        );
+        assert_eq!(
+            Credentials::new(None, Some("no-such-group")).unwrap_err(),
+            Error::GroupUnknown("no-such-group".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pass_adapters() {
+        let adapter_config = AdapterConfig::try_from("1:4,3:5:6").unwrap();
+        let i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+        let (helper, daemon) = UnixStream::pair().unwrap();
+
+        send_adapters(&helper, &i2c_map).unwrap();
+        drop(helper);
+
+        let files = recv_adapters(&daemon).unwrap();
+        assert_eq!(
+            files.iter().map(|(no, _)| *no).collect::<Vec<u32>>(),
+            vec![1, 3]
+        );
+
+        let i2c_map: I2cMap<DummyDevice> = I2cMap::from_files(&adapter_config, files).unwrap();
+        assert_eq!(i2c_map.adapter_fds().len(), 2);
+
+        // A message without a file descriptor
+        let (helper, daemon) = UnixStream::pair().unwrap();
+        send_with_fds(&helper, &1u32.to_le_bytes(), &[]).unwrap();
+        assert_eq!(
+            recv_adapters(&daemon).unwrap_err(),
+            Error::AdapterMessageInvalid(0)
+        );
+
+        assert_eq!(
+            receive_adapters("/path/not/present/helper.sock").unwrap_err(),
+            Error::HelperConnectFailed("/path/not/present/helper.sock".to_string(), libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn test_serve_adapters() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-privsep").unwrap();
+        let adapter_config = AdapterConfig::try_from("1:4").unwrap();
+
+        for (allowed, count) in [(false, 0), (true, 1)] {
+            let path = dir.as_path().join("helper.sock");
+            let path = path.to_str().unwrap().to_string();
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).unwrap();
+            let i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+
+            // SAFETY: Safe as this just returns the id of the process.
+            let uid = format!("uid={}", unsafe { libc::getuid() });
+            let policy = match allowed {
+                true => PeerPolicy::try_from(vec![uid.as_str()]).unwrap(),
+                false => PeerPolicy::default(),
+            };
+            spawn(move || serve_adapters(&listener, &i2c_map, &policy));
+
+            // Without any rule, the connection is closed without passing the adapters.
+            assert_eq!(receive_adapters(&path).unwrap().len(), count);
+        }
+    }
+}
@@ -0,0 +1,406 @@
+// Adapter capability probe and bus scan
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use thiserror::Error as ThisError;
+use virtio_i2c::i2c::{
+    self, I2cDevice, I2cReq, SmbusMsg, I2C_FUNC_10BIT_ADDR, I2C_FUNC_I2C, I2C_FUNC_NOSTART,
+    I2C_FUNC_PROTOCOL_MANGLING, I2C_FUNC_SLAVE, I2C_FUNC_SMBUS_ALL, I2C_FUNC_SMBUS_BLOCK_PROC_CALL,
+    I2C_FUNC_SMBUS_HOST_NOTIFY, I2C_FUNC_SMBUS_PEC, I2C_FUNC_SMBUS_PROC_CALL, I2C_FUNC_SMBUS_QUICK,
+    I2C_FUNC_SMBUS_READ_BLOCK_DATA, I2C_FUNC_SMBUS_READ_BYTE, I2C_FUNC_SMBUS_READ_BYTE_DATA,
+    I2C_FUNC_SMBUS_READ_I2C_BLOCK, I2C_FUNC_SMBUS_READ_WORD_DATA, I2C_FUNC_SMBUS_WRITE_BLOCK_DATA,
+    I2C_FUNC_SMBUS_WRITE_BYTE, I2C_FUNC_SMBUS_WRITE_BYTE_DATA, I2C_FUNC_SMBUS_WRITE_I2C_BLOCK,
+    I2C_FUNC_SMBUS_WRITE_WORD_DATA, I2C_M_RD, MAX_I2C_VDEV,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Names of the functionality bits, as printed by i2cdetect.
+const FUNC_NAMES: &[(u64, &str)] = &[
+    (I2C_FUNC_I2C, "I2C"),
+    (I2C_FUNC_10BIT_ADDR, "10BIT_ADDR"),
+    (I2C_FUNC_PROTOCOL_MANGLING, "PROTOCOL_MANGLING"),
+    (I2C_FUNC_SMBUS_PEC, "SMBUS_PEC"),
+    (I2C_FUNC_NOSTART, "NOSTART"),
+    (I2C_FUNC_SLAVE, "SLAVE"),
+    (I2C_FUNC_SMBUS_BLOCK_PROC_CALL, "SMBUS_BLOCK_PROC_CALL"),
+    (I2C_FUNC_SMBUS_QUICK, "SMBUS_QUICK"),
+    (I2C_FUNC_SMBUS_READ_BYTE, "SMBUS_READ_BYTE"),
+    (I2C_FUNC_SMBUS_WRITE_BYTE, "SMBUS_WRITE_BYTE"),
+    (I2C_FUNC_SMBUS_READ_BYTE_DATA, "SMBUS_READ_BYTE_DATA"),
+    (I2C_FUNC_SMBUS_WRITE_BYTE_DATA, "SMBUS_WRITE_BYTE_DATA"),
+    (I2C_FUNC_SMBUS_READ_WORD_DATA, "SMBUS_READ_WORD_DATA"),
+    (I2C_FUNC_SMBUS_WRITE_WORD_DATA, "SMBUS_WRITE_WORD_DATA"),
+    (I2C_FUNC_SMBUS_PROC_CALL, "SMBUS_PROC_CALL"),
+    (I2C_FUNC_SMBUS_READ_BLOCK_DATA, "SMBUS_READ_BLOCK_DATA"),
+    (I2C_FUNC_SMBUS_WRITE_BLOCK_DATA, "SMBUS_WRITE_BLOCK_DATA"),
+    (I2C_FUNC_SMBUS_READ_I2C_BLOCK, "SMBUS_READ_I2C_BLOCK"),
+    (I2C_FUNC_SMBUS_WRITE_I2C_BLOCK, "SMBUS_WRITE_I2C_BLOCK"),
+    (I2C_FUNC_SMBUS_HOST_NOTIFY, "SMBUS_HOST_NOTIFY"),
+];
+
+/// Addresses scanned by default, the reserved ones are skipped like i2cdetect does.
+pub(crate) const DEFAULT_RANGE: (u16, u16) = (0x08, 0x77);
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the bus scan
+pub enum Error {
+    #[error("Invalid address range: {0}")]
+    AddressRangeInvalid(String),
+    #[error("Invalid probe mode: {0}")]
+    ProbeModeInvalid(String),
+    #[error("Adapter {0} supports neither SMBus quick nor read byte, can't scan it")]
+    ScanUnsupported(u32),
+    #[error("Low level I2c failure: {0:?}")]
+    I2cFailure(i2c::Error),
+}
+
+/// How the clients are detected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ProbeMode {
+    // Read byte for the EEPROM ranges, where a quick write could lock the write protection,
+    // and quick write for the others. Same heuristics as i2cdetect.
+    Auto,
+    Quick,
+    Read,
+}
+
+impl TryFrom<&str> for ProbeMode {
+    type Error = Error;
+
+    fn try_from(mode: &str) -> Result<Self> {
+        match mode {
+            "auto" => Ok(ProbeMode::Auto),
+            "quick" => Ok(ProbeMode::Quick),
+            "read" => Ok(ProbeMode::Read),
+            _ => Err(Error::ProbeModeInvalid(mode.to_string())),
+        }
+    }
+}
+
+/// Parses an address, in decimal or in hexadecimal with the "0x" prefix.
+fn parse_addr(addr: &str) -> Option<u16> {
+    match addr.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => addr.parse::<u16>().ok(),
+    }
+}
+
+/// Parses an inclusive address range, in format <first>-<last>.
+pub(crate) fn parse_range(range: &str) -> Result<(u16, u16)> {
+    let invalid = || Error::AddressRangeInvalid(range.to_string());
+    let pos = range.find('-').ok_or_else(invalid)?;
+    let first = parse_addr(&range[..pos]).ok_or_else(invalid)?;
+    let last = parse_addr(&range[pos + 1..]).ok_or_else(invalid)?;
+
+    if first > last || last as usize >= MAX_I2C_VDEV {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+/// Capabilities and clients of an adapter.
+#[derive(Debug, PartialEq)]
+pub(crate) struct AdapterReport {
+    pub adapter_no: u32,
+    pub funcs: u64,
+    // Clients answering the probe.
+    pub clients: Vec<u16>,
+    // Clients already claimed by kernel drivers, which can't be passed to the guests.
+    pub busy: Vec<u16>,
+}
+
+impl AdapterReport {
+    /// Returns the names of the functionality bits set.
+    pub fn func_names(&self) -> Vec<&'static str> {
+        FUNC_NAMES
+            .iter()
+            .filter(|(bit, _)| self.funcs & bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// Whether the daemon can drive the adapter, with I2C or SMBus transfers up to words.
+    pub fn is_usable(&self) -> bool {
+        self.funcs & (I2C_FUNC_I2C | I2C_FUNC_SMBUS_ALL) != 0
+    }
+}
+
+fn list_addrs(addrs: &[u16]) -> String {
+    if addrs.is_empty() {
+        return "none".to_string();
+    }
+
+    addrs
+        .iter()
+        .map(|addr| format!("0x{:02x}", addr))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl fmt::Display for AdapterReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Adapter {}:", self.adapter_no)?;
+        writeln!(
+            f,
+            "  Functionality: {:#010x} {}",
+            self.funcs,
+            self.func_names().join(" ")
+        )?;
+        if !self.is_usable() {
+            writeln!(f, "  Unsupported: neither I2C nor SMBus transfers")?;
+        }
+        writeln!(f, "  Clients: {}", list_addrs(&self.clients))?;
+        write!(f, "  Claimed by kernel drivers: {}", list_addrs(&self.busy))
+    }
+}
+
+/// Returns the device list of the clients found on the usable adapters, in the format of
+/// the --device-list option.
+pub(crate) fn device_list(reports: &[AdapterReport]) -> String {
+    reports
+        .iter()
+        .filter(|report| report.is_usable() && !report.clients.is_empty())
+        .map(|report| {
+            let mut entry = report.adapter_no.to_string();
+
+            for addr in report.clients.iter() {
+                entry += &format!(":{}", addr);
+            }
+            entry
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Decodes the functionality of the adapter, and scans the addresses in `range`.
+pub(crate) fn probe<D: I2cDevice>(
+    device: &mut D,
+    range: (u16, u16),
+    mode: ProbeMode,
+) -> Result<AdapterReport> {
+    let funcs = device.funcs().map_err(Error::I2cFailure)?;
+    let quick = funcs & I2C_FUNC_SMBUS_QUICK != 0;
+    let read = funcs & I2C_FUNC_SMBUS_READ_BYTE != 0;
+
+    if !quick && !read {
+        return Err(Error::ScanUnsupported(device.adapter_no()));
+    }
+
+    let mut report = AdapterReport {
+        adapter_no: device.adapter_no(),
+        funcs,
+        clients: Vec::new(),
+        busy: Vec::new(),
+    };
+
+    for addr in range.0..=range.1 {
+        // I2C_SLAVE fails with EBUSY for the clients bound to a kernel driver.
+        match device.slave(addr as u64) {
+            Ok(()) => (),
+            Err(i2c::Error::IoctlFailure(_, e)) if e.errno() == libc::EBUSY => {
+                report.busy.push(addr);
+                continue;
+            }
+            Err(e) => return Err(Error::I2cFailure(e)),
+        }
+
+        let use_read = match mode {
+            ProbeMode::Auto => {
+                !quick || (read && ((0x30..=0x37).contains(&addr) || (0x50..=0x5f).contains(&addr)))
+            }
+            ProbeMode::Quick => !quick,
+            ProbeMode::Read => read,
+        };
+
+        // A quick write is a request without data, a read byte a single byte read.
+        let mut reqs = [I2cReq {
+            addr,
+            flags: if use_read { I2C_M_RD } else { 0 },
+            len: use_read as u16,
+            buf: vec![0; use_read as usize].into(),
+        }];
+        let mut msg = SmbusMsg::new(&mut reqs).map_err(Error::I2cFailure)?;
+
+        // Absent clients don't acknowledge their address, which fails the transfer.
+        match device.smbus(&mut msg) {
+            Ok(()) => report.clients.push(addr),
+            Err(i2c::Error::IoctlFailure(..)) => (),
+            Err(e) => return Err(Error::I2cFailure(e)),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::fs::File;
+    use vmm_sys_util::errno::Error as IoError;
+
+    type I2cResult<T> = std::result::Result<T, i2c::Error>;
+
+    // A bus with a few clients, some bound to kernel drivers.
+    struct BusDevice {
+        funcs: u64,
+        clients: Vec<u16>,
+        busy: Vec<u16>,
+        addr: Cell<u16>,
+        // Address and whether a read byte was used, for every probe.
+        probes: RefCell<Vec<(u16, bool)>>,
+    }
+
+    impl BusDevice {
+        fn new(funcs: u64) -> Self {
+            BusDevice {
+                funcs,
+                clients: vec![0x20, 0x50, 0x68],
+                busy: vec![0x1a],
+                addr: Cell::new(0),
+                probes: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl I2cDevice for BusDevice {
+        fn open(_path: &str, _adapter_no: u32) -> I2cResult<Self> {
+            Ok(BusDevice::new(I2C_FUNC_I2C))
+        }
+
+        fn from_file(_file: File, _adapter_no: u32) -> I2cResult<Self> {
+            Ok(BusDevice::new(I2C_FUNC_I2C))
+        }
+
+        fn funcs(&mut self) -> I2cResult<u64> {
+            Ok(self.funcs)
+        }
+
+        fn rdwr(&self, _reqs: &mut [I2cReq]) -> I2cResult<()> {
+            Ok(())
+        }
+
+        fn smbus(&self, msg: &mut SmbusMsg) -> I2cResult<()> {
+            let addr = self.addr.get();
+
+            self.probes.borrow_mut().push((addr, msg.is_read()));
+            if self.clients.contains(&addr) {
+                Ok(())
+            } else {
+                Err(i2c::Error::IoctlFailure("smbus", IoError::new(libc::ENXIO)))
+            }
+        }
+
+        fn slave(&self, addr: u64) -> I2cResult<()> {
+            if self.busy.contains(&(addr as u16)) {
+                return Err(i2c::Error::IoctlFailure("slave", IoError::new(libc::EBUSY)));
+            }
+            self.addr.set(addr as u16);
+            Ok(())
+        }
+
+        fn adapter_no(&self) -> u32 {
+            3
+        }
+    }
+
+    #[test]
+    fn test_probe() {
+        let funcs = I2C_FUNC_I2C | I2C_FUNC_SMBUS_QUICK | I2C_FUNC_SMBUS_READ_BYTE;
+        let mut device = BusDevice::new(funcs);
+        let report = probe(&mut device, DEFAULT_RANGE, ProbeMode::Auto).unwrap();
+
+        assert_eq!(
+            report,
+            AdapterReport {
+                adapter_no: 3,
+                funcs,
+                clients: vec![0x20, 0x50, 0x68],
+                busy: vec![0x1a],
+            }
+        );
+        assert_eq!(
+            report.func_names(),
+            vec!["I2C", "SMBUS_QUICK", "SMBUS_READ_BYTE"]
+        );
+
+        // EEPROMs are probed with a read byte, the others with a quick write.
+        let probes = device.probes.borrow();
+        assert_eq!(probes.len(), 0x77 - 0x08);
+        assert!(probes.contains(&(0x50, true)));
+        assert!(probes.contains(&(0x34, true)));
+        assert!(probes.contains(&(0x20, false)));
+        assert!(probes.contains(&(0x68, false)));
+
+        // Read byte only
+        let mut device = BusDevice::new(I2C_FUNC_SMBUS_READ_BYTE);
+        let report = probe(&mut device, (0x40, 0x5f), ProbeMode::Quick).unwrap();
+        assert_eq!(report.clients, vec![0x50]);
+        assert!(device.probes.borrow().iter().all(|(_, read)| *read));
+        assert!(report.is_usable());
+
+        let mut device = BusDevice::new(I2C_FUNC_I2C);
+        assert_eq!(
+            probe(&mut device, DEFAULT_RANGE, ProbeMode::Auto).unwrap_err(),
+            Error::ScanUnsupported(3)
+        );
+    }
+
+    #[test]
+    fn test_device_list() {
+        let report = |adapter_no, funcs, clients| AdapterReport {
+            adapter_no,
+            funcs,
+            clients,
+            busy: vec![0x1a],
+        };
+
+        let reports = vec![
+            report(1, I2C_FUNC_I2C, vec![0x20, 0x50]),
+            report(2, I2C_FUNC_I2C, vec![]),
+            report(3, I2C_FUNC_SMBUS_QUICK, vec![0x30]),
+            report(4, I2C_FUNC_SMBUS_READ_BYTE, vec![0x08]),
+        ];
+        assert_eq!(device_list(&reports), "1:32:80,4:8");
+        assert_eq!(device_list(&[]), "");
+
+        assert_eq!(
+            reports[0].to_string(),
+            "Adapter 1:\n  Functionality: 0x00000001 I2C\n  Clients: 0x20 0x50\n  \
+             Claimed by kernel drivers: 0x1a"
+        );
+        assert!(reports[2].to_string().contains("Unsupported"));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_range("0x03-0x77").unwrap(), (3, 0x77));
+        assert_eq!(parse_range("8-20").unwrap(), (8, 20));
+        assert_eq!(
+            parse_range("0x50").unwrap_err(),
+            Error::AddressRangeInvalid("0x50".to_string())
+        );
+        assert_eq!(
+            parse_range("0x50-0x40").unwrap_err(),
+            Error::AddressRangeInvalid("0x50-0x40".to_string())
+        );
+        assert_eq!(
+            parse_range("0-0x80").unwrap_err(),
+            Error::AddressRangeInvalid("0-0x80".to_string())
+        );
+        assert!(parse_range("0x-1").is_err());
+
+        assert_eq!(ProbeMode::try_from("read").unwrap(), ProbeMode::Read);
+        assert_eq!(
+            ProbeMode::try_from("write").unwrap_err(),
+            Error::ProbeModeInvalid("write".to_string())
+        );
+    }
+}
@@ -0,0 +1,643 @@
+// Seccomp and Landlock sandboxing
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::{c_long, c_void, sock_filter, sock_fprog};
+use log::{info, warn};
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error as ThisError;
+use virtio_i2c::i2c::{I2C_FUNCS, I2C_RDWR, I2C_SLAVE, I2C_SMBUS};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to sandboxing
+pub enum Error {
+    #[error("Failed to set no_new_privs: {0}")]
+    NoNewPrivsFailed(i32),
+    #[error("Failed to install seccomp filter: {0}")]
+    SeccompFailed(i32),
+    #[error("Landlock {0} failed: {1}")]
+    LandlockFailed(&'static str, i32),
+    #[error("Invalid sandbox path: {0}")]
+    PathInvalid(String),
+    #[error("Seccomp filter not supported on {0}")]
+    ArchUnsupported(&'static str),
+}
+
+// Classic BPF, see include/uapi/linux/filter.h
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+// See include/uapi/linux/seccomp.h
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// Offsets in struct seccomp_data, the low word of the arguments on little endian.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARG0: u32 = 16;
+const SECCOMP_DATA_ARG1: u32 = 24;
+
+// See include/uapi/linux/audit.h, the system calls are only listed for these architectures.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+// The Landlock system calls are the same on all architectures.
+const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+// Filesystem access rights of the first Landlock ABI, see include/uapi/linux/landlock.h
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const LANDLOCK_ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const LANDLOCK_ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const LANDLOCK_ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const LANDLOCK_ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const LANDLOCK_ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const LANDLOCK_ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const LANDLOCK_ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+
+const LANDLOCK_ACCESS_FS_ALL: u64 = LANDLOCK_ACCESS_FS_EXECUTE
+    | LANDLOCK_ACCESS_FS_WRITE_FILE
+    | LANDLOCK_ACCESS_FS_READ_FILE
+    | LANDLOCK_ACCESS_FS_READ_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_FILE
+    | LANDLOCK_ACCESS_FS_MAKE_CHAR
+    | LANDLOCK_ACCESS_FS_MAKE_DIR
+    | LANDLOCK_ACCESS_FS_MAKE_REG
+    | LANDLOCK_ACCESS_FS_MAKE_SOCK
+    | LANDLOCK_ACCESS_FS_MAKE_FIFO
+    | LANDLOCK_ACCESS_FS_MAKE_BLOCK
+    | LANDLOCK_ACCESS_FS_MAKE_SYM;
+
+/// Access to the directories of the sockets: creating and removing the sockets, and the
+/// private directories of the peer gates.
+const LANDLOCK_ACCESS_FS_SOCKET_DIR: u64 = LANDLOCK_ACCESS_FS_READ_DIR
+    | LANDLOCK_ACCESS_FS_MAKE_SOCK
+    | LANDLOCK_ACCESS_FS_MAKE_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_FILE
+    | LANDLOCK_ACCESS_FS_REMOVE_DIR;
+
+/// Access to the directory of the adapter nodes, to reopen the adapters plugged back. The
+/// nodes are created again when plugged back, the rule can't be limited to the current ones.
+const LANDLOCK_ACCESS_FS_ADAPTER_DIR: u64 =
+    LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_WRITE_FILE;
+
+/// Ioctls allowed on top of the I2C ones, FIONBIO is used by the standard library.
+const GENERIC_IOCTLS: &[u32] = &[libc::FIONBIO as u32];
+
+/// System calls used after startup: by the vhost-user daemons, the I2C transfers, and the
+/// control, metrics and peer sockets.
+const SYSCALLS: &[c_long] = &[
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_close,
+    libc::SYS_connect,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_fchmodat,
+    libc::SYS_fchownat,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_fsync,
+    libc::SYS_futex,
+    libc::SYS_getdents64,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_getsockname,
+    libc::SYS_getsockopt,
+    libc::SYS_gettid,
+    libc::SYS_listen,
+    libc::SYS_lseek,
+    libc::SYS_madvise,
+    libc::SYS_mkdirat,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_newfstatat,
+    libc::SYS_openat,
+    libc::SYS_ppoll,
+    libc::SYS_read,
+    libc::SYS_readlinkat,
+    libc::SYS_readv,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_set_robust_list,
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_sigaltstack,
+    libc::SYS_socket,
+    libc::SYS_statx,
+    libc::SYS_tgkill,
+    libc::SYS_unlinkat,
+    libc::SYS_write,
+    libc::SYS_writev,
+    // Legacy variants, used by the standard library on x86_64.
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rmdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chmod,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chown,
+];
+
+/// Restricts the system calls and the filesystem accesses of the daemon to those needed
+/// after startup. This is applied once the adapters are open, before spawning any threads.
+#[derive(Debug, Default)]
+pub(crate) struct Sandbox {
+    // Directories the sockets are created in.
+    dirs: Vec<PathBuf>,
+    // Directories the adapters are reopened from.
+    adapter_dirs: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows creating and removing the socket at `path`, and any other one in its directory.
+    pub fn allow_socket<P: AsRef<Path>>(&mut self, path: P) {
+        let dir = match path.as_ref().parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        if !self.dirs.contains(&dir) {
+            self.dirs.push(dir);
+        }
+    }
+
+    /// Allows opening the adapter nodes in `dir` for reading and writing.
+    pub fn allow_adapters<P: AsRef<Path>>(&mut self, dir: P) {
+        let dir = dir.as_ref().to_path_buf();
+
+        if !self.adapter_dirs.contains(&dir) {
+            self.adapter_dirs.push(dir);
+        }
+    }
+
+    pub fn apply(&self) -> Result<()> {
+        let arch = AUDIT_ARCH.ok_or(Error::ArchUnsupported(std::env::consts::ARCH))?;
+
+        // SAFETY: Safe as prctl() doesn't access memory for PR_SET_NO_NEW_PRIVS.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(Error::NoNewPrivsFailed(errno()));
+        }
+
+        // Landlock is only available on recent kernels, the seccomp filter still applies.
+        match self.restrict_paths() {
+            Ok(()) => info!(
+                "Restricted filesystem access to {:?} and adapters in {:?}",
+                self.dirs, self.adapter_dirs
+            ),
+            Err(Error::LandlockFailed(_, e)) if e == libc::ENOSYS || e == libc::EOPNOTSUPP => {
+                warn!("Landlock isn't supported by the kernel, filesystem access not restricted")
+            }
+            Err(e) => return Err(e),
+        }
+
+        install_filter(&filter(arch, SYSCALLS, &allowed_ioctls()))?;
+        info!("Installed seccomp filter");
+        Ok(())
+    }
+
+    fn restrict_paths(&self) -> Result<()> {
+        let attr = LandlockRulesetAttr {
+            handled_access_fs: LANDLOCK_ACCESS_FS_ALL,
+        };
+
+        // SAFETY: Safe as the attribute is valid for the size passed.
+        let ruleset = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const LandlockRulesetAttr,
+                size_of::<LandlockRulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            return Err(Error::LandlockFailed("create_ruleset", errno()));
+        }
+        let ruleset = ruleset as libc::c_int;
+
+        let result = self.add_rules(ruleset).and_then(|_| {
+            // SAFETY: Safe as the ruleset is a valid fd.
+            if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) } != 0 {
+                return Err(Error::LandlockFailed("restrict_self", errno()));
+            }
+            Ok(())
+        });
+
+        // SAFETY: Safe as the ruleset is a valid fd, owned here.
+        unsafe { libc::close(ruleset) };
+        result
+    }
+
+    fn add_rules(&self, ruleset: libc::c_int) -> Result<()> {
+        let dirs = self
+            .dirs
+            .iter()
+            .map(|dir| (dir, LANDLOCK_ACCESS_FS_SOCKET_DIR));
+        let adapter_dirs = self
+            .adapter_dirs
+            .iter()
+            .map(|dir| (dir, LANDLOCK_ACCESS_FS_ADAPTER_DIR));
+
+        for (dir, allowed_access) in dirs.chain(adapter_dirs) {
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|_| Error::PathInvalid(dir.display().to_string()))?;
+
+            // SAFETY: Safe as the path is a valid C string.
+            let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(Error::PathInvalid(dir.display().to_string()));
+            }
+
+            let rule = LandlockPathBeneathAttr {
+                allowed_access,
+                parent_fd: fd,
+            };
+
+            // SAFETY: Safe as the rule is valid for the rule type passed.
+            let ret = unsafe {
+                libc::syscall(
+                    SYS_LANDLOCK_ADD_RULE,
+                    ruleset,
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule as *const LandlockPathBeneathAttr as *const c_void,
+                    0,
+                )
+            };
+            let err = errno();
+
+            // SAFETY: Safe as the fd is valid, owned here.
+            unsafe { libc::close(fd) };
+
+            if ret != 0 {
+                return Err(Error::LandlockFailed("add_rule", err));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+fn allowed_ioctls() -> Vec<u32> {
+    let mut ioctls = vec![
+        I2C_RDWR as u32,
+        I2C_SMBUS as u32,
+        I2C_SLAVE as u32,
+        I2C_FUNCS as u32,
+    ];
+
+    ioctls.extend_from_slice(GENERIC_IOCTLS);
+    ioctls
+}
+
+fn stmt(code: u16, k: u32) -> sock_filter {
+    sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jeq(k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: BPF_JEQ_K,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Builds a filter allowing the system calls, prctl to name the threads, and ioctl with the
+/// request numbers only. Other prctls and ioctls fail with EPERM, and other system calls kill
+/// the process.
+fn filter(arch: u32, syscalls: &[c_long], ioctls: &[u32]) -> Vec<sock_filter> {
+    let mut prog = vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jeq(arch, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+
+    for nr in syscalls {
+        prog.push(jeq(*nr as u32, 0, 1));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+
+    // The threads spawned by the vhost-user daemons are named, with PR_SET_NAME.
+    prog.push(jeq(libc::SYS_prctl as u32, 0, 4));
+    prog.push(stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARG0));
+    prog.push(jeq(libc::PR_SET_NAME as u32, 0, 1));
+    prog.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    prog.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+
+    prog.push(jeq(libc::SYS_ioctl as u32, 1, 0));
+    prog.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+    prog.push(stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARG1));
+
+    for request in ioctls {
+        prog.push(jeq(*request, 0, 1));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+
+    prog.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    prog
+}
+
+fn install_filter(prog: &[sock_filter]) -> Result<()> {
+    let fprog = sock_fprog {
+        len: prog.len() as u16,
+        filter: prog.as_ptr() as *mut sock_filter,
+    };
+
+    // SAFETY: Safe as the program is valid for the length passed, and copied by the kernel.
+    let ret = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &fprog as *const sock_fprog,
+        )
+    };
+
+    if ret != 0 {
+        return Err(Error::SeccompFailed(errno()));
+    }
+    Ok(())
+}
+
+fn errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Command, Stdio};
+    use vmm_sys_util::tempdir::TempDir;
+
+    /// Set for the child processes of the tests, with the directory of their files.
+    const CHILD_DIR_ENV: &str = "VHOST_DEVICE_I2C_SANDBOX_TEST_DIR";
+
+    /// Directory of the files of a test, created by the test and reused by its child.
+    pub(crate) struct TestDir {
+        path: PathBuf,
+        _dir: Option<TempDir>,
+    }
+
+    impl TestDir {
+        pub fn new() -> Self {
+            match env::var_os(CHILD_DIR_ENV) {
+                Some(path) => TestDir {
+                    path: PathBuf::from(path),
+                    _dir: None,
+                },
+                None => {
+                    let dir = TempDir::new_with_prefix("/tmp/vi2c-sandbox").unwrap();
+
+                    TestDir {
+                        path: dir.as_path().to_path_buf(),
+                        _dir: Some(dir),
+                    }
+                }
+            }
+        }
+
+        pub fn as_path(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    // Runs `f` in a child process, as the sandbox can't be undone, and returns its wait status.
+    // The child is a new instance of the test binary, running the test `name` only with the
+    // same directory, which calls `f` and exits with the code returned. Forking the harness
+    // instead would run `f` in a copy of a multithreaded process, where allocating isn't safe.
+    pub(crate) fn run_in_child<F: FnOnce() -> i32>(name: &str, dir: &TestDir, f: F) -> i32 {
+        if env::var_os(CHILD_DIR_ENV).is_some() {
+            let code = f();
+            // SAFETY: Safe as the child exits without running the other tests, or returning
+            // to the harness, which the sandbox doesn't allow.
+            unsafe { libc::_exit(code) };
+        }
+
+        Command::new(env::current_exe().unwrap())
+            .args([name, "--exact", "--test-threads=1"])
+            .env(CHILD_DIR_ENV, dir.as_path())
+            .stdout(Stdio::null())
+            .status()
+            .unwrap()
+            .into_raw()
+    }
+
+    #[test]
+    fn test_allow_socket() {
+        let mut sandbox = Sandbox::new();
+
+        sandbox.allow_socket("/run/vi2c/vi2c.sock0");
+        sandbox.allow_socket("/run/vi2c/vi2c.sock1");
+        sandbox.allow_socket("vi2c.sock");
+        assert_eq!(
+            sandbox.dirs,
+            vec![PathBuf::from("/run/vi2c"), PathBuf::from(".")]
+        );
+    }
+
+    #[test]
+    fn test_seccomp_filter() {
+        let dir = TestDir::new();
+        let prog = filter(
+            AUDIT_ARCH.unwrap(),
+            &[libc::SYS_exit_group, libc::SYS_getpid],
+            &[I2C_FUNCS as u32],
+        );
+        assert_eq!(prog.len(), 4 + 2 * 2 + 5 + 3 + 2 + 1);
+
+        let status = run_in_child("sandbox::tests::test_seccomp_filter", &dir, || {
+            // SAFETY: Safe as the child only issues system calls.
+            unsafe {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                    || install_filter(&prog).is_err()
+                {
+                    return 1;
+                }
+
+                // Allowed system call
+                libc::syscall(libc::SYS_getpid);
+
+                // Allowed prctl, and denied one.
+                let name = b"sandbox\0";
+                if libc::prctl(libc::PR_SET_NAME, name.as_ptr(), 0, 0, 0) != 0 {
+                    return 6;
+                }
+                if libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) == 0
+                    || *libc::__errno_location() != libc::EPERM
+                {
+                    return 7;
+                }
+
+                // Allowed ioctl, fails on stdin.
+                if libc::ioctl(0, I2C_FUNCS as _, 0) == 0 {
+                    return 2;
+                }
+                if *libc::__errno_location() == libc::EPERM {
+                    return 3;
+                }
+
+                // Denied ioctl
+                if libc::ioctl(0, libc::FIONREAD as _, 0) == 0 {
+                    return 4;
+                }
+                if *libc::__errno_location() != libc::EPERM {
+                    return 5;
+                }
+            }
+            0
+        });
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    #[test]
+    fn test_seccomp_kill() {
+        let dir = TestDir::new();
+        let prog = filter(AUDIT_ARCH.unwrap(), &[libc::SYS_exit_group], &[]);
+
+        // Denied system call, the process is killed.
+        let status = run_in_child("sandbox::tests::test_seccomp_kill", &dir, || {
+            // SAFETY: Safe as the child only issues system calls.
+            unsafe {
+                libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+                if install_filter(&prog).is_ok() {
+                    libc::syscall(libc::SYS_getpid);
+                }
+            }
+            0
+        });
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::WTERMSIG(status), libc::SIGSYS);
+    }
+
+    #[test]
+    fn test_landlock() {
+        let dir = TestDir::new();
+        let allowed = dir.as_path().join("allowed");
+        let denied = dir.as_path().join("denied");
+        std::fs::create_dir_all(&allowed).unwrap();
+        std::fs::create_dir_all(&denied).unwrap();
+
+        let allowed_dir = CString::new(allowed.join("dir").to_str().unwrap()).unwrap();
+        let denied_dir = CString::new(denied.join("dir").to_str().unwrap()).unwrap();
+
+        let allowed_node = CString::new(allowed.join("i2c-3").to_str().unwrap()).unwrap();
+        let denied_node = CString::new(denied.join("i2c-3").to_str().unwrap()).unwrap();
+
+        let mut sandbox = Sandbox::new();
+        sandbox.allow_socket(allowed.join("vi2c.sock"));
+        sandbox.allow_adapters(&allowed);
+        sandbox.allow_adapters(&allowed);
+        assert_eq!(sandbox.adapter_dirs, vec![allowed.clone()]);
+
+        std::fs::write(allowed.join("i2c-3"), "").unwrap();
+        std::fs::write(denied.join("i2c-3"), "").unwrap();
+
+        let status = run_in_child("sandbox::tests::test_landlock", &dir, || {
+            match sandbox.restrict_paths() {
+                Ok(()) => (),
+                // Not supported by the kernel, nothing to test.
+                Err(Error::LandlockFailed(_, e)) if e == libc::ENOSYS || e == libc::EOPNOTSUPP => {
+                    return 0
+                }
+                Err(_) => return 1,
+            }
+
+            // SAFETY: Safe as the paths are valid C strings.
+            unsafe {
+                if libc::mkdir(allowed_dir.as_ptr(), 0o700) != 0 {
+                    return 2;
+                }
+                if libc::mkdir(denied_dir.as_ptr(), 0o700) == 0 {
+                    return 3;
+                }
+                if libc::open(allowed_node.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) < 0 {
+                    return 4;
+                }
+                if libc::open(denied_node.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) >= 0 {
+                    return 5;
+                }
+            }
+            0
+        });
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
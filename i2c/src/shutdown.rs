@@ -0,0 +1,346 @@
+// Graceful shutdown on termination signals
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::{c_int, sigset_t};
+use log::{info, warn};
+use std::fs::{self, File};
+use std::mem::zeroed;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr::null_mut;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use thiserror::Error as ThisError;
+use vhost::vhost_user::Listener;
+use virtio_i2c::i2c::I2cDevice;
+
+use crate::peer::PeerGate;
+use crate::vhu_i2c::VhostUserI2cBackend;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to signal handling
+pub enum Error {
+    #[error("Failed to block signals: {0}")]
+    SignalMaskFailed(i32),
+}
+
+/// Events reported to the main thread.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Event {
+    Signal(c_int),
+    ThreadExited,
+}
+
+/// Blocks SIGTERM and SIGINT in the calling thread, and the threads spawned by it afterwards,
+/// and reports them from a dedicated thread instead. This must be called before spawning any
+/// other threads, otherwise the signals may be delivered to them.
+pub(crate) fn watch_signals(events: Sender<Event>) -> Result<()> {
+    // SAFETY: Safe as the set is initialized by sigemptyset() and only used by libc.
+    let set = unsafe {
+        let mut set: sigset_t = zeroed();
+
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, null_mut());
+        if ret != 0 {
+            return Err(Error::SignalMaskFailed(ret));
+        }
+        set
+    };
+
+    spawn(move || loop {
+        let mut signal: c_int = 0;
+
+        // SAFETY: Safe as the set is valid and the signal is written by sigwait() only.
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0
+            && events.send(Event::Signal(signal)).is_err()
+        {
+            break;
+        }
+    });
+
+    Ok(())
+}
+
+/// Reports the exit of a thread to the main thread when dropped, including on panic.
+pub(crate) struct ExitGuard(pub Sender<Event>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(Event::ThreadExited);
+    }
+}
+
+/// How long the socket threads are given to exit on shutdown.
+pub(crate) const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for `running` threads to report their exit, for up to `timeout`, and joins them. A
+/// thread still serving a connected guest is left behind, it would otherwise delay the exit
+/// for as long as the guest is around. Returns the number of threads left behind.
+pub(crate) fn join_threads(
+    events: &Receiver<Event>,
+    mut running: usize,
+    handles: Vec<JoinHandle<()>>,
+    timeout: Duration,
+) -> usize {
+    let deadline = Instant::now() + timeout;
+
+    while running > 0 {
+        match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Event::ThreadExited) => running -= 1,
+            // Another signal doesn't hurry the threads.
+            Ok(Event::Signal(_)) => (),
+            Err(_) => break,
+        }
+    }
+
+    for handle in handles {
+        // The threads which reported their exit are about to finish.
+        if (running == 0 || handle.is_finished()) && handle.join().is_err() {
+            warn!("Thread panicked while shutting down");
+        }
+    }
+
+    running
+}
+
+struct SocketInner<D: I2cDevice> {
+    stopping: bool,
+    backend: Option<Arc<RwLock<VhostUserI2cBackend<D>>>>,
+    // Duplicate of the listener, to interrupt the daemon waiting for a connection.
+    listener: Option<File>,
+    // Gate accepting the connections in place of the daemon, if the peers are checked.
+    gate: Option<Arc<PeerGate>>,
+}
+
+/// State of a socket thread, shared with the main thread to stop it.
+pub(crate) struct Socket<D: I2cDevice> {
+    // Sockets passed by systemd have no path, and are left for it to manage.
+    path: Option<String>,
+    inner: Mutex<SocketInner<D>>,
+}
+
+impl<D: I2cDevice> Socket<D> {
+    pub fn new(path: Option<String>) -> Self {
+        Socket {
+            path,
+            inner: Mutex::new(SocketInner {
+                stopping: false,
+                backend: None,
+                listener: None,
+                gate: None,
+            }),
+        }
+    }
+
+    /// Records the gate of the socket, to stop it along with the socket.
+    pub fn set_gate(&self, gate: Arc<PeerGate>) {
+        self.inner.lock().unwrap().gate = Some(gate);
+    }
+
+    /// Records the backend and the listener about to serve a guest, returns false if the
+    /// socket is stopping instead.
+    pub fn serve(&self, backend: Arc<RwLock<VhostUserI2cBackend<D>>>, listener: &Listener) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.stopping {
+            return false;
+        }
+
+        if self.path.is_some() {
+            // SAFETY: Safe as the listener fd is valid, and the duplicate is owned by the file.
+            let fd = unsafe { libc::dup(listener.as_raw_fd()) };
+            if fd >= 0 {
+                inner.listener = Some(unsafe { File::from_raw_fd(fd) });
+            }
+        }
+
+        inner.backend = Some(backend);
+        true
+    }
+
+    /// Forgets the backend and the listener, once the guest is gone.
+    pub fn done(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.backend = None;
+        inner.listener = None;
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.inner.lock().unwrap().stopping
+    }
+
+    /// Stops the worker thread of the backend, once it is done with the in-flight requests,
+    /// the listener and the gate, and removes the socket file.
+    pub fn stop(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.stopping = true;
+
+        if let Some(backend) = inner.backend.take() {
+            if let Err(e) = backend.read().unwrap().exit_event.write(1) {
+                warn!("Failed to write exit event: {}", e);
+            }
+
+            // The requests are processed with the backend locked for writing.
+            drop(backend.write().unwrap());
+        }
+
+        if let Some(listener) = inner.listener.take() {
+            // SAFETY: Safe as the fd is valid, this wakes up the thread blocked in accept().
+            unsafe { libc::shutdown(listener.as_raw_fd(), libc::SHUT_RDWR) };
+        }
+
+        if let Some(gate) = inner.gate.take() {
+            gate.stop();
+        }
+
+        if let Some(path) = &self.path {
+            remove_socket(path);
+        }
+    }
+}
+
+/// Removes a socket file, left behind by the servers.
+pub(crate) fn remove_socket(path: &str) {
+    match fs::remove_file(path) {
+        Ok(()) => info!("Removed socket {}", path),
+        Err(e) => warn!("Failed to remove socket {}: {}", path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::mpsc::channel;
+    use vmm_sys_util::tempdir::TempDir;
+
+    use crate::metrics::Metrics;
+    use crate::vhu_i2c::QUEUE_SIZE;
+    use virtio_i2c::config::AdapterConfig;
+    use virtio_i2c::i2c::mock::DummyDevice;
+    use virtio_i2c::i2c::I2cMap;
+
+    fn backend() -> Arc<RwLock<VhostUserI2cBackend<DummyDevice>>> {
+        let adapter_config = AdapterConfig::try_from("1:4").unwrap();
+        let i2c_map = I2cMap::new(&adapter_config).unwrap();
+
+        Arc::new(RwLock::new(
+            VhostUserI2cBackend::new(
+                Arc::new(i2c_map),
+                Arc::new(Metrics::new()),
+                0,
+                None,
+                QUEUE_SIZE,
+                None,
+            )
+            .unwrap(),
+        ))
+    }
+
+    #[test]
+    fn test_exit_guard() {
+        let (tx, rx) = channel();
+
+        let handle = spawn(move || {
+            let _guard = ExitGuard(tx);
+            panic!("Thread failure");
+        });
+
+        assert!(handle.join().is_err());
+        assert_eq!(rx.recv().unwrap(), Event::ThreadExited);
+    }
+
+    #[test]
+    fn test_join_threads() {
+        let (tx, rx) = channel();
+        let guard = ExitGuard(tx.clone());
+        let quick = spawn(move || drop(guard));
+
+        // Both exit in time.
+        let guard = ExitGuard(tx.clone());
+        let slow = spawn(move || {
+            let _guard = guard;
+            std::thread::sleep(Duration::from_millis(50));
+        });
+        assert_eq!(
+            join_threads(&rx, 2, vec![quick, slow], Duration::from_secs(5)),
+            0
+        );
+
+        // One is left behind, once the timeout elapses.
+        let (stop_tx, stop_rx) = channel::<()>();
+        let guard = ExitGuard(tx.clone());
+        let stuck = spawn(move || {
+            let _guard = guard;
+            let _ = stop_rx.recv();
+        });
+        let guard = ExitGuard(tx);
+        let quick = spawn(move || drop(guard));
+
+        let start = Instant::now();
+        assert_eq!(
+            join_threads(&rx, 2, vec![stuck, quick], Duration::from_millis(100)),
+            1
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        drop(stop_tx);
+    }
+
+    #[test]
+    fn test_socket_stop() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-shutdown").unwrap();
+        let path = dir.as_path().join("vi2c.sock0");
+        let path = path.to_str().unwrap().to_string();
+        let socket = Arc::new(Socket::new(Some(path.clone())));
+        let backend = backend();
+
+        let listener = Listener::new(&path, true).unwrap();
+        assert!(socket.serve(backend.clone(), &listener));
+
+        // The thread waiting for a connection is woken up.
+        let (tx, rx) = channel();
+        spawn(move || {
+            let _ = tx.send(listener.accept().is_err());
+        });
+
+        socket.stop();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert_eq!(backend.read().unwrap().exit_event.read().unwrap(), 1);
+        assert!(!std::path::Path::new(&path).exists());
+
+        // No new guests are served.
+        assert!(socket.stopping());
+        let listener = Listener::new(&path, true).unwrap();
+        assert!(!socket.serve(backend, &listener));
+    }
+
+    #[test]
+    fn test_socket_done() {
+        let socket = Socket::new(None);
+        let backend = backend();
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-shutdown").unwrap();
+        let path = dir.as_path().join("vi2c.sock0");
+        let listener = Listener::new(path.to_str().unwrap(), true).unwrap();
+
+        assert!(socket.serve(backend.clone(), &listener));
+        socket.done();
+        socket.stop();
+
+        // Nothing to stop, and the socket isn't ours to remove.
+        assert!(backend.read().unwrap().exit_event.read().is_err());
+        assert!(path.exists());
+    }
+}
@@ -0,0 +1,390 @@
+// Per socket configuration
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::{gid_t, uid_t};
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs::{self, Permissions};
+use std::mem::zeroed;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::ptr::null_mut;
+
+use thiserror::Error as ThisError;
+use virtio_i2c::i2c::MAX_I2C_VDEV;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Largest queue size allowed by the virtio specification.
+const MAX_QUEUE_SIZE: usize = 32768;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the socket configuration
+pub enum Error {
+    #[error("Invalid socket option: {0}")]
+    OptionInvalid(String),
+    #[error("Missing socket path")]
+    PathMissing,
+    #[error("Unknown user: {0}")]
+    UserUnknown(String),
+    #[error("Unknown group: {0}")]
+    GroupUnknown(String),
+    #[error("Invalid socket mode: {0}")]
+    ModeInvalid(String),
+    #[error("Invalid client address: {0}")]
+    ClientAddressInvalid(String),
+    #[error("Invalid queue size: {0}")]
+    QueueSizeInvalid(String),
+    #[error("Failed to change owner of socket {0}: {1}")]
+    ChownFailed(String, i32),
+    #[error("Failed to change mode of socket {0}: {1}")]
+    ChmodFailed(String, i32),
+    #[error("Failed to create socket {0}: {1}")]
+    BindFailed(String, i32),
+}
+
+/// Configuration of a vhost-user socket, in the format:
+///
+///   path=<path>[,owner=<user>][,group=<group>][,mode=<octal>][,devices=<addr>[:<addr>]]
+///   [,queue-size=<size>]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SocketConfig {
+    pub path: String,
+    pub owner: Option<uid_t>,
+    pub group: Option<gid_t>,
+    pub mode: Option<u32>,
+    // Subset of the clients exposed to the guest, all of them otherwise.
+    pub devices: Option<Vec<u16>>,
+    pub queue_size: Option<usize>,
+}
+
+impl SocketConfig {
+    pub fn new(path: String) -> Self {
+        SocketConfig {
+            path,
+            owner: None,
+            group: None,
+            mode: None,
+            devices: None,
+            queue_size: None,
+        }
+    }
+
+    /// Creates the listening socket, replacing any stale one. The socket is only accessible to
+    /// the user of the daemon until the ownership and mode are applied. This changes the umask
+    /// of the process for a moment, and must be called before spawning any thread.
+    pub fn bind(&self) -> Result<UnixListener> {
+        let _ = fs::remove_file(&self.path);
+
+        // SAFETY: Safe as umask() only changes the file mode creation mask of the process.
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&self.path);
+        // SAFETY: Same as above, the previous mask is restored.
+        unsafe { libc::umask(umask) };
+
+        let listener = listener
+            .map_err(|e| Error::BindFailed(self.path.clone(), e.raw_os_error().unwrap_or(0)))?;
+        self.apply()?;
+        Ok(listener)
+    }
+
+    /// Applies the ownership and mode to the socket, once created.
+    pub fn apply(&self) -> Result<()> {
+        if self.owner.is_some() || self.group.is_some() {
+            let path = CString::new(self.path.as_str())
+                .map_err(|_| Error::OptionInvalid(self.path.clone()))?;
+
+            // SAFETY: Safe as the path is a valid C string, -1 leaves the id unchanged.
+            let ret = unsafe {
+                libc::chown(
+                    path.as_ptr(),
+                    self.owner.unwrap_or(uid_t::MAX),
+                    self.group.unwrap_or(gid_t::MAX),
+                )
+            };
+
+            if ret != 0 {
+                return Err(Error::ChownFailed(self.path.clone(), errno()));
+            }
+        }
+
+        if let Some(mode) = self.mode {
+            fs::set_permissions(&self.path, Permissions::from_mode(mode)).map_err(|e| {
+                Error::ChmodFailed(self.path.clone(), e.raw_os_error().unwrap_or(0))
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TryFrom<&str> for SocketConfig {
+    type Error = Error;
+
+    fn try_from(options: &str) -> Result<Self> {
+        let mut config = SocketConfig::new(String::new());
+
+        for option in options.split(',') {
+            let (key, value) = match option.find('=') {
+                Some(pos) => (&option[..pos], &option[pos + 1..]),
+                None => return Err(Error::OptionInvalid(option.to_string())),
+            };
+
+            match key {
+                "path" if !value.is_empty() => config.path = value.to_string(),
+                "owner" => config.owner = Some(lookup_user(value)?),
+                "group" => config.group = Some(lookup_group(value)?),
+                "mode" => {
+                    config.mode = Some(
+                        u32::from_str_radix(value, 8)
+                            .ok()
+                            .filter(|mode| *mode <= 0o7777)
+                            .ok_or_else(|| Error::ModeInvalid(value.to_string()))?,
+                    )
+                }
+                "devices" => {
+                    let mut devices = Vec::new();
+
+                    for addr in value.split(':') {
+                        let addr = addr
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|addr| *addr as usize <= MAX_I2C_VDEV)
+                            .ok_or_else(|| Error::ClientAddressInvalid(addr.to_string()))?;
+
+                        if !devices.contains(&addr) {
+                            devices.push(addr);
+                        }
+                    }
+                    config.devices = Some(devices);
+                }
+                "queue-size" => {
+                    config.queue_size = Some(
+                        value
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|size| size.is_power_of_two() && *size <= MAX_QUEUE_SIZE)
+                            .ok_or_else(|| Error::QueueSizeInvalid(value.to_string()))?,
+                    )
+                }
+                _ => return Err(Error::OptionInvalid(option.to_string())),
+            }
+        }
+
+        if config.path.is_empty() {
+            return Err(Error::PathMissing);
+        }
+
+        Ok(config)
+    }
+}
+
+fn errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+// Buffer size for the getpwnam_r() and getgrnam_r() strings.
+const NAME_BUF_SIZE: usize = 16384;
+
+/// Looks up a user by name, or takes a numeric id as is.
+pub(crate) fn lookup_user(name: &str) -> Result<uid_t> {
+    if let Ok(uid) = name.parse::<uid_t>() {
+        return Ok(uid);
+    }
+
+    let cname = CString::new(name).map_err(|_| Error::UserUnknown(name.to_string()))?;
+    let mut buf = vec![0 as libc::c_char; NAME_BUF_SIZE];
+    let mut result = null_mut();
+
+    // SAFETY: Safe as the structure and the buffer are valid for the lengths passed, and
+    // libc::passwd is a plain C structure.
+    unsafe {
+        let mut passwd: libc::passwd = zeroed();
+
+        let ret = libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+
+        if ret == 0 && !result.is_null() {
+            return Ok(passwd.pw_uid);
+        }
+    }
+
+    Err(Error::UserUnknown(name.to_string()))
+}
+
+/// Looks up a group by name, or takes a numeric id as is.
+pub(crate) fn lookup_group(name: &str) -> Result<gid_t> {
+    if let Ok(gid) = name.parse::<gid_t>() {
+        return Ok(gid);
+    }
+
+    let cname = CString::new(name).map_err(|_| Error::GroupUnknown(name.to_string()))?;
+    let mut buf = vec![0 as libc::c_char; NAME_BUF_SIZE];
+    let mut result = null_mut();
+
+    // SAFETY: Safe as the structure and the buffer are valid for the lengths passed, and
+    // libc::group is a plain C structure.
+    unsafe {
+        let mut group: libc::group = zeroed();
+
+        let ret = libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+
+        if ret == 0 && !result.is_null() {
+            return Ok(group.gr_gid);
+        }
+    }
+
+    Err(Error::GroupUnknown(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_socket_config_parse() {
+        assert_eq!(
+            SocketConfig::try_from("path=vi2c.sock").unwrap(),
+            SocketConfig::new("vi2c.sock".to_string())
+        );
+
+        let config = SocketConfig::try_from(
+            "path=/run/vi2c.sock,owner=root,group=0,mode=0660,devices=32:21,queue-size=256",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            SocketConfig {
+                path: "/run/vi2c.sock".to_string(),
+                owner: Some(0),
+                group: Some(0),
+                mode: Some(0o660),
+                devices: Some(vec![32, 21]),
+                queue_size: Some(256),
+            }
+        );
+
+        assert_eq!(
+            SocketConfig::try_from("owner=0").unwrap_err(),
+            Error::PathMissing
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=").unwrap_err(),
+            Error::OptionInvalid("path=".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,size=4").unwrap_err(),
+            Error::OptionInvalid("size=4".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,owner").unwrap_err(),
+            Error::OptionInvalid("owner".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,owner=no-such-user").unwrap_err(),
+            Error::UserUnknown("no-such-user".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,group=no-such-group").unwrap_err(),
+            Error::GroupUnknown("no-such-group".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,mode=0999").unwrap_err(),
+            Error::ModeInvalid("0999".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,mode=17777").unwrap_err(),
+            Error::ModeInvalid("17777".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,devices=32:200").unwrap_err(),
+            Error::ClientAddressInvalid("200".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,queue-size=1000").unwrap_err(),
+            Error::QueueSizeInvalid("1000".to_string())
+        );
+        assert_eq!(
+            SocketConfig::try_from("path=a,queue-size=65536").unwrap_err(),
+            Error::QueueSizeInvalid("65536".to_string())
+        );
+    }
+
+    #[test]
+    fn test_socket_config_apply() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-socket").unwrap();
+        let path = dir.as_path().join("vi2c.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let mut config = SocketConfig::new(path.to_str().unwrap().to_string());
+        config.mode = Some(0o600);
+        config.apply().unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o7777,
+            0o600
+        );
+
+        // The ownership is left unchanged for ids not set.
+        let metadata = fs::metadata(&path).unwrap();
+        config.owner = Some(std::os::unix::fs::MetadataExt::uid(&metadata));
+        config.apply().unwrap();
+
+        let config = SocketConfig {
+            mode: Some(0o600),
+            ..SocketConfig::new("/path/not/present/vi2c.sock".to_string())
+        };
+        assert_eq!(
+            config.apply().unwrap_err(),
+            Error::ChmodFailed(config.path.clone(), libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn test_socket_config_bind() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-socket").unwrap();
+        let path = dir.as_path().join("vi2c.sock");
+        let config = SocketConfig::new(path.to_str().unwrap().to_string());
+
+        // Only accessible to the owner without a mode, and replacing the stale socket.
+        fs::write(&path, b"").unwrap();
+        let _listener = config.bind().unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(std::os::unix::fs::FileTypeExt::is_socket(
+            &metadata.file_type()
+        ));
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        let config = SocketConfig {
+            mode: Some(0o660),
+            ..config
+        };
+        let _listener = config.bind().unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o7777,
+            0o660
+        );
+
+        let config = SocketConfig::new("/path/not/present/vi2c.sock".to_string());
+        assert_eq!(
+            config.bind().unwrap_err(),
+            Error::BindFailed(config.path.clone(), libc::ENOENT)
+        );
+    }
+}
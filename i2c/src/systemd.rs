@@ -0,0 +1,294 @@
+// Systemd socket activation and readiness notification
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::{c_void, sa_family_t, sockaddr, sockaddr_un, socklen_t};
+use log::info;
+use std::env;
+use std::mem::{size_of, zeroed};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::process;
+
+use thiserror::Error as ThisError;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// First file descriptor passed by systemd, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to systemd integration
+pub enum Error {
+    #[error("Invalid environment variable {0}: {1}")]
+    EnvInvalid(&'static str, String),
+    #[error("Inherited file descriptor {0} isn't a listening socket")]
+    FdInvalid(RawFd),
+    #[error("No inherited socket for: {0}")]
+    FdMissing(String),
+    #[error("Invalid notification socket: {0}")]
+    NotifySocketInvalid(String),
+    #[error("Failed to send notification: {0}")]
+    NotifyFailed(i32),
+}
+
+/// Listening sockets passed by systemd, in the order of the socket unit.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ListenFds {
+    inner: Vec<(RawFd, String)>,
+}
+
+impl ListenFds {
+    /// Parses the LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES environment variables, which are
+    /// unset afterwards to not leak them to other processes. Changing the environment isn't
+    /// thread safe, this must be called before spawning any thread.
+    pub fn from_env() -> Result<Self> {
+        let fds = Self::parse(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            env::var("LISTEN_FDNAMES").ok().as_deref(),
+            process::id(),
+        )?;
+
+        // The environment is left alone when not socket activated.
+        if env::var_os("LISTEN_PID").is_some() {
+            env::remove_var("LISTEN_PID");
+            env::remove_var("LISTEN_FDS");
+            env::remove_var("LISTEN_FDNAMES");
+        }
+
+        for (fd, _) in fds.inner.iter() {
+            if !is_listening_socket(*fd) {
+                return Err(Error::FdInvalid(*fd));
+            }
+
+            // SAFETY: Safe as the fd is valid, and we don't want it inherited any further.
+            unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+
+        if !fds.is_empty() {
+            info!("Using {} socket(s) passed by systemd", fds.inner.len());
+        }
+        Ok(fds)
+    }
+
+    fn parse(pid: Option<&str>, fds: Option<&str>, names: Option<&str>, own: u32) -> Result<Self> {
+        let pid = match pid {
+            Some(pid) => pid
+                .parse::<u32>()
+                .map_err(|_| Error::EnvInvalid("LISTEN_PID", pid.to_string()))?,
+            None => return Ok(Self::default()),
+        };
+
+        // The sockets are meant for another process.
+        if pid != own {
+            return Ok(Self::default());
+        }
+
+        let count = match fds {
+            Some(fds) => fds
+                .parse::<RawFd>()
+                .map_err(|_| Error::EnvInvalid("LISTEN_FDS", fds.to_string()))?,
+            None => return Ok(Self::default()),
+        };
+
+        let names: Vec<&str> = match names {
+            Some(names) => names.split(':').collect(),
+            None => Vec::new(),
+        };
+
+        let inner = (0..count)
+            .map(|i| {
+                let name = names.get(i as usize).copied().unwrap_or_default();
+                (SD_LISTEN_FDS_START + i, name.to_string())
+            })
+            .collect();
+
+        Ok(ListenFds { inner })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Finds the socket for the socket path at `index`. The sockets are matched by the name
+    /// (FileDescriptorName=) against the socket path or its file name, and by order otherwise.
+    pub fn find(&self, socket: &str, index: usize) -> Result<RawFd> {
+        let file_name = Path::new(socket).file_name().and_then(|name| name.to_str());
+
+        let named = self
+            .inner
+            .iter()
+            .find(|(_, name)| name == socket || Some(name.as_str()) == file_name);
+
+        match named {
+            Some((fd, _)) => Ok(*fd),
+            None => self
+                .inner
+                .get(index)
+                .map(|(fd, _)| *fd)
+                .ok_or_else(|| Error::FdMissing(socket.to_string())),
+        }
+    }
+}
+
+fn is_listening_socket(fd: RawFd) -> bool {
+    let mut listening: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as socklen_t;
+
+    // SAFETY: Safe as the kernel only writes within the bounds of `listening`.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut _ as *mut c_void,
+            &mut len,
+        )
+    };
+
+    ret == 0 && listening != 0
+}
+
+/// Sends a state notification to the service manager, see sd_notify(3). This does nothing if
+/// the daemon isn't run by systemd with a notification socket.
+pub(crate) fn notify(state: &str) -> Result<()> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(path) => notify_to(&path, state),
+        Err(_) => Ok(()),
+    }
+}
+
+fn notify_to(path: &str, state: &str) -> Result<()> {
+    // SAFETY: Safe as sockaddr_un is a plain C structure.
+    let mut addr: sockaddr_un = unsafe { zeroed() };
+    addr.sun_family = libc::AF_UNIX as sa_family_t;
+
+    // Abstract sockets start with '@', which is replaced by a null byte.
+    let bytes = path.as_bytes();
+    if bytes.is_empty() || bytes.len() >= addr.sun_path.len() {
+        return Err(Error::NotifySocketInvalid(path.to_string()));
+    }
+
+    for (i, byte) in bytes.iter().enumerate() {
+        let byte = if i == 0 && *byte == b'@' { 0 } else { *byte };
+        addr.sun_path[i] = byte as libc::c_char;
+    }
+
+    let len = size_of::<sa_family_t>() + bytes.len();
+    let socket =
+        UnixDatagram::unbound().map_err(|e| Error::NotifyFailed(e.raw_os_error().unwrap_or(0)))?;
+
+    // SAFETY: Safe as the address and the buffer are valid for the lengths passed.
+    let ret = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            state.as_ptr() as *const c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const sockaddr_un as *const sockaddr,
+            len as socklen_t,
+        )
+    };
+
+    if ret < 0 {
+        return Err(Error::NotifyFailed(
+            std::io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_listen_fds_parse() {
+        // Not socket activated
+        assert!(ListenFds::parse(None, None, None, 10).unwrap().is_empty());
+        assert!(ListenFds::parse(Some("10"), None, None, 10)
+            .unwrap()
+            .is_empty());
+
+        // Meant for another process
+        assert!(ListenFds::parse(Some("11"), Some("2"), None, 10)
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            ListenFds::parse(Some("10d"), Some("2"), None, 10).unwrap_err(),
+            Error::EnvInvalid("LISTEN_PID", "10d".to_string())
+        );
+        assert_eq!(
+            ListenFds::parse(Some("10"), Some("x"), None, 10).unwrap_err(),
+            Error::EnvInvalid("LISTEN_FDS", "x".to_string())
+        );
+
+        let fds =
+            ListenFds::parse(Some("10"), Some("3"), Some("vi2c.sock1:vi2c.sock0"), 10).unwrap();
+        assert_eq!(
+            fds.inner,
+            vec![
+                (3, "vi2c.sock1".to_string()),
+                (4, "vi2c.sock0".to_string()),
+                (5, String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_listen_fds_find() {
+        let fds = ListenFds::parse(Some("10"), Some("3"), Some("vi2c.sock1:unknown"), 10).unwrap();
+
+        // By name, with or without the directory
+        assert_eq!(fds.find("vi2c.sock1", 0).unwrap(), 3);
+        assert_eq!(fds.find("/run/vi2c/vi2c.sock1", 0).unwrap(), 3);
+
+        // By order
+        assert_eq!(fds.find("vi2c.sock0", 0).unwrap(), 3);
+        assert_eq!(fds.find("vi2c.sock2", 2).unwrap(), 5);
+        assert_eq!(
+            fds.find("vi2c.sock3", 3).unwrap_err(),
+            Error::FdMissing("vi2c.sock3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_is_listening_socket() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-systemd").unwrap();
+        let listener = UnixListener::bind(dir.as_path().join("listen.sock")).unwrap();
+        let datagram = UnixDatagram::unbound().unwrap();
+
+        assert!(is_listening_socket(listener.as_raw_fd()));
+        assert!(!is_listening_socket(datagram.as_raw_fd()));
+        assert!(!is_listening_socket(-1));
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-systemd").unwrap();
+        let path = dir.as_path().join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let mut buf = [0u8; 64];
+
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        assert_eq!(
+            notify_to("", "READY=1").unwrap_err(),
+            Error::NotifySocketInvalid(String::new())
+        );
+        assert_eq!(
+            notify_to("/path/not/present/notify.sock", "READY=1").unwrap_err(),
+            Error::NotifyFailed(libc::ENOENT)
+        );
+    }
+}
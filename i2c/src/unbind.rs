@@ -0,0 +1,164 @@
+// Unbinding and rebinding of the kernel drivers of passthrough clients
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error as ThisError;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Default location of sysfs.
+pub(crate) const SYSFS_ROOT: &str = "/sys";
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the kernel drivers of the clients
+pub enum Error {
+    #[error("Failed to look up the driver of client {0}: {1}")]
+    DriverLookupFailed(String, i32),
+    #[error("Failed to unbind client {0} from driver {1}: {2}")]
+    UnbindFailed(String, String, i32),
+}
+
+/// Returns the name of the client in sysfs, e.g. 1-0050.
+fn client_name(adapter_no: u32, addr: u16) -> String {
+    format!("{}-{:04x}", adapter_no, addr)
+}
+
+fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(0)
+}
+
+/// Detaches the kernel drivers from the clients passed through to the guests, and reattaches
+/// them once dropped.
+#[derive(Debug)]
+pub(crate) struct Unbinder {
+    root: PathBuf,
+    // Clients detached from their drivers, along with the driver directories.
+    unbound: Vec<(String, PathBuf)>,
+}
+
+impl Unbinder {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Unbinder {
+            root: root.as_ref().to_path_buf(),
+            unbound: Vec::new(),
+        }
+    }
+
+    /// Detaches the kernel driver bound to the client, if any.
+    pub fn unbind(&mut self, adapter_no: u32, addr: u16) -> Result<()> {
+        let name = client_name(adapter_no, addr);
+        let link = self.root.join("bus/i2c/devices").join(&name).join("driver");
+
+        // The link is only present while a driver is bound.
+        let driver = match fs::read_link(&link) {
+            Ok(target) => match target.file_name() {
+                Some(driver) => driver.to_string_lossy().into_owned(),
+                None => return Err(Error::DriverLookupFailed(name, libc::EINVAL)),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Error::DriverLookupFailed(name, errno(e))),
+        };
+
+        let dir = self.root.join("bus/i2c/drivers").join(&driver);
+        fs::write(dir.join("unbind"), &name)
+            .map_err(|e| Error::UnbindFailed(name.clone(), driver.clone(), errno(e)))?;
+
+        info!("Unbound client {} from driver {}", name, driver);
+        self.unbound.push((name, dir));
+        Ok(())
+    }
+
+    /// Reattaches the clients to their drivers, failures are only logged.
+    pub fn rebind(&mut self) {
+        while let Some((name, dir)) = self.unbound.pop() {
+            match fs::write(dir.join("bind"), &name) {
+                Ok(()) => info!("Rebound client {} to driver {}", name, dir.display()),
+                Err(e) => warn!(
+                    "Failed to rebind client {} to driver {}: {}",
+                    name,
+                    dir.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+impl Drop for Unbinder {
+    fn drop(&mut self) {
+        self.rebind();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Creates a sysfs tree with the at24 driver bound to 1-0050, and 1-0051 without driver.
+    fn sysfs_tree() -> TempDir {
+        let root = TempDir::new().unwrap();
+        let path = root.as_path();
+        let driver = path.join("bus/i2c/drivers/at24");
+
+        fs::create_dir_all(&driver).unwrap();
+        fs::write(driver.join("bind"), "").unwrap();
+        fs::write(driver.join("unbind"), "").unwrap();
+
+        fs::create_dir_all(path.join("bus/i2c/devices/1-0050")).unwrap();
+        fs::create_dir_all(path.join("bus/i2c/devices/1-0051")).unwrap();
+        symlink(
+            "../../../../bus/i2c/drivers/at24",
+            path.join("bus/i2c/devices/1-0050/driver"),
+        )
+        .unwrap();
+        root
+    }
+
+    #[test]
+    fn test_unbind_rebind() {
+        let root = sysfs_tree();
+        let driver = root.as_path().join("bus/i2c/drivers/at24");
+        let mut unbinder = Unbinder::new(root.as_path());
+
+        unbinder.unbind(1, 0x50).unwrap();
+        assert_eq!(fs::read_to_string(driver.join("unbind")).unwrap(), "1-0050");
+        assert_eq!(fs::read_to_string(driver.join("bind")).unwrap(), "");
+
+        // No driver bound, or no such client
+        unbinder.unbind(1, 0x51).unwrap();
+        unbinder.unbind(2, 0x50).unwrap();
+        assert_eq!(unbinder.unbound.len(), 1);
+
+        drop(unbinder);
+        assert_eq!(fs::read_to_string(driver.join("bind")).unwrap(), "1-0050");
+    }
+
+    #[test]
+    fn test_unbind_failure() {
+        let root = sysfs_tree();
+        let mut unbinder = Unbinder::new(root.as_path());
+
+        fs::remove_dir_all(root.as_path().join("bus/i2c/drivers/at24")).unwrap();
+        assert_eq!(
+            unbinder.unbind(1, 0x50).unwrap_err(),
+            Error::UnbindFailed("1-0050".to_string(), "at24".to_string(), libc::ENOENT)
+        );
+
+        // Not a link
+        fs::write(root.as_path().join("bus/i2c/devices/1-0051/driver"), "").unwrap();
+        assert_eq!(
+            unbinder.unbind(1, 0x51).unwrap_err(),
+            Error::DriverLookupFailed("1-0051".to_string(), libc::EINVAL)
+        );
+    }
+}
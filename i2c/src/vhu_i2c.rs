@@ -5,9 +5,9 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use log::warn;
-use std::mem::size_of;
+use log::{debug, warn};
 use std::sync::Arc;
+use std::time::Instant;
 use std::{convert, io};
 
 use thiserror::Error as ThisError;
@@ -17,19 +17,21 @@ use virtio_bindings::bindings::virtio_net::{VIRTIO_F_NOTIFY_ON_EMPTY, VIRTIO_F_V
 use virtio_bindings::bindings::virtio_ring::{
     VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
 };
+use virtio_i2c::i2c::Error as I2cError;
+use virtio_i2c::i2c::*;
+use virtio_i2c::ratelimit::RateLimiter;
+use virtio_i2c::request::{complete_request, fail_request, parse_request_zero_copy};
 use virtio_queue::DescriptorChain;
-use vm_memory::{
-    ByteValued, Bytes, GuestMemoryAtomic, GuestMemoryLoadGuard, GuestMemoryMmap, Le16, Le32,
-};
+use vm_memory::{GuestMemoryAtomic, GuestMemoryLoadGuard, GuestMemoryMmap};
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
-use crate::i2c::*;
+use crate::metrics::Metrics;
 
 /// Virtio I2C Feature bits
 const VIRTIO_I2C_F_ZERO_LENGTH_REQUEST: u16 = 0;
 
-const QUEUE_SIZE: usize = 1024;
+pub(crate) const QUEUE_SIZE: usize = 1024;
 const NUM_QUEUES: usize = 1;
 
 type Result<T> = std::result::Result<T, Error>;
@@ -42,20 +44,8 @@ pub enum Error {
     HandleEventNotEpollIn,
     #[error("Failed to handle unknown event")]
     HandleEventUnknown,
-    #[error("Received unexpected write only descriptor at index {0}")]
-    UnexpectedWriteOnlyDescriptor(usize),
-    #[error("Received unexpected readable descriptor at index {0}")]
-    UnexpectedReadableDescriptor(usize),
-    #[error("Invalid descriptor count {0}")]
-    UnexpectedDescriptorCount(usize),
-    #[error("Invalid descriptor size, expected: {0}, found: {1}")]
-    UnexpectedDescriptorSize(usize, u32),
     #[error("Descriptor not found")]
     DescriptorNotFound,
-    #[error("Descriptor read failed")]
-    DescriptorReadFailed,
-    #[error("Descriptor write failed")]
-    DescriptorWriteFailed,
     #[error("Failed to send notification")]
     NotificationFailed,
     #[error("Failed to create new EventFd")]
@@ -64,37 +54,20 @@ pub enum Error {
 
 impl convert::From<Error> for io::Error {
     fn from(e: Error) -> Self {
-        io::Error::new(io::ErrorKind::Other, e)
+        io::Error::other(e)
     }
 }
 
-/// I2C definitions from Virtio Spec
-
-/// The final status written by the device
-const VIRTIO_I2C_MSG_OK: u8 = 0;
-const VIRTIO_I2C_MSG_ERR: u8 = 1;
-
-#[derive(Copy, Clone, Default)]
-#[repr(C)]
-struct VirtioI2cOutHdr {
-    addr: Le16,
-    padding: Le16,
-    flags: Le32,
-}
-unsafe impl ByteValued for VirtioI2cOutHdr {}
-
-/// VirtioI2cOutHdr Flags
-const VIRTIO_I2C_FLAGS_M_RD: u32 = 1 << 1;
-
-#[derive(Copy, Clone, Default)]
-#[repr(C)]
-struct VirtioI2cInHdr {
-    status: u8,
-}
-unsafe impl ByteValued for VirtioI2cInHdr {}
-
 pub struct VhostUserI2cBackend<D: I2cDevice> {
     i2c_map: Arc<I2cMap<D>>,
+    metrics: Arc<Metrics>,
+    // Index of the socket the backend is serving, used to label metrics.
+    socket: usize,
+    // Subset of the clients exposed to the guest, all of them otherwise.
+    clients: Option<Vec<u16>>,
+    queue_size: usize,
+    // Limits the transfers of the guest, shared by the backends of the socket.
+    limiter: Option<Arc<RateLimiter>>,
     event_idx: bool,
     pub exit_event: EventFd,
 }
@@ -102,9 +75,21 @@ pub struct VhostUserI2cBackend<D: I2cDevice> {
 type I2cDescriptorChain = DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap<()>>>;
 
 impl<D: I2cDevice> VhostUserI2cBackend<D> {
-    pub fn new(i2c_map: Arc<I2cMap<D>>) -> Result<Self> {
+    pub fn new(
+        i2c_map: Arc<I2cMap<D>>,
+        metrics: Arc<Metrics>,
+        socket: usize,
+        clients: Option<Vec<u16>>,
+        queue_size: usize,
+        limiter: Option<Arc<RateLimiter>>,
+    ) -> Result<Self> {
         Ok(VhostUserI2cBackend {
             i2c_map,
+            metrics,
+            socket,
+            clients,
+            queue_size,
+            limiter,
             event_idx: false,
             exit_event: EventFd::new(EFD_NONBLOCK).map_err(|_| Error::EventFdFailed)?,
         })
@@ -116,132 +101,67 @@ impl<D: I2cDevice> VhostUserI2cBackend<D> {
         requests: Vec<I2cDescriptorChain>,
         vring: &VringRwLock,
     ) -> Result<bool> {
-        let mut reqs: Vec<I2cReq> = Vec::new();
-
         if requests.is_empty() {
             return Ok(true);
         }
 
-        // Iterate over each I2C request and push it to "reqs" vector.
-        for desc_chain in requests.clone() {
-            let descriptors: Vec<_> = desc_chain.clone().collect();
-
-            if (descriptors.len() != 2) && (descriptors.len() != 3) {
-                return Err(Error::UnexpectedDescriptorCount(descriptors.len()));
-            }
-
-            let desc_out_hdr = descriptors[0];
-
-            if desc_out_hdr.is_write_only() {
-                return Err(Error::UnexpectedWriteOnlyDescriptor(0));
-            }
-
-            if desc_out_hdr.len() as usize != size_of::<VirtioI2cOutHdr>() {
-                return Err(Error::UnexpectedDescriptorSize(
-                    size_of::<VirtioI2cOutHdr>(),
-                    desc_out_hdr.len(),
-                ));
-            }
-
-            let out_hdr = desc_chain
-                .memory()
-                .read_obj::<VirtioI2cOutHdr>(desc_out_hdr.addr())
-                .map_err(|_| Error::DescriptorReadFailed)?;
-
-            let flags = match out_hdr.flags.to_native() & VIRTIO_I2C_FLAGS_M_RD {
-                VIRTIO_I2C_FLAGS_M_RD => I2C_M_RD,
-                _ => 0,
-            };
-
-            let desc_in_hdr = descriptors[descriptors.len() - 1];
-            if !desc_in_hdr.is_write_only() {
-                return Err(Error::UnexpectedReadableDescriptor(descriptors.len() - 1));
-            }
-
-            if desc_in_hdr.len() as usize != size_of::<u8>() {
-                return Err(Error::UnexpectedDescriptorSize(
-                    size_of::<u8>(),
-                    desc_in_hdr.len(),
-                ));
-            }
-
-            let (buf, len) = match descriptors.len() {
-                // Buffer is available
-                3 => {
-                    let desc_buf = descriptors[1];
-                    let len = desc_buf.len();
-
-                    if len == 0 {
-                        return Err(Error::UnexpectedDescriptorSize(1, len));
-                    }
-                    let mut buf = vec![0; len as usize];
-
-                    if flags != I2C_M_RD {
-                        if desc_buf.is_write_only() {
-                            return Err(Error::UnexpectedWriteOnlyDescriptor(1));
-                        }
-
-                        desc_chain
-                            .memory()
-                            .read(&mut buf, desc_buf.addr())
-                            .map_err(|_| Error::DescriptorReadFailed)?;
-                    } else if !desc_buf.is_write_only() {
-                        return Err(Error::UnexpectedReadableDescriptor(1));
-                    }
-
-                    (buf, len)
+        // A malformed chain is completed with an error on its own, the others are still
+        // transferred. The chains are declared first, to outlive the messages.
+        let mut chains = Vec::with_capacity(requests.len());
+        let mut reqs = Vec::with_capacity(requests.len());
+
+        for desc_chain in requests {
+            // The buffers of the messages point straight at the guest memory. Safe as the
+            // descriptor chains, which keep that memory mapped, outlive the messages.
+            match unsafe { parse_request_zero_copy(&desc_chain) } {
+                Ok(req) => {
+                    reqs.push(req);
+                    chains.push(desc_chain);
                 }
+                Err(e) => {
+                    warn!("Invalid request: {}", e);
+                    let len = fail_request(&desc_chain);
+                    add_used(vring, &desc_chain, len);
+                }
+            }
+        }
 
-                _ => (Vec::<u8>::new(), 0),
-            };
-
-            reqs.push(I2cReq {
-                addr: out_hdr.addr.to_native() >> 1,
-                flags,
-                len: len as u16,
-                buf,
-            });
+        if reqs.is_empty() {
+            return Ok(true);
         }
 
-        let in_hdr = {
-            VirtioI2cInHdr {
-                status: match self.i2c_map.transfer(&mut reqs) {
-                    Ok(()) => VIRTIO_I2C_MSG_OK,
-                    Err(_) => VIRTIO_I2C_MSG_ERR,
-                },
-            }
+        // Clients outside of the subset don't exist for the guest, whichever message of the
+        // batch addresses them.
+        let outside = self.clients.as_ref().and_then(|clients| {
+            reqs.iter()
+                .map(|req| req.addr)
+                .find(|addr| !clients.contains(addr))
+        });
+
+        let start = Instant::now();
+        let result = match outside {
+            Some(addr) => Err(I2cError::ClientAddressNotFound(addr)),
+            None => match &self.limiter {
+                Some(limiter) if !limiter.acquire(&reqs) => Err(I2cError::RateLimited),
+                _ => self.i2c_map.transfer(&mut reqs),
+            },
         };
 
-        for (i, desc_chain) in requests.iter().enumerate() {
-            let descriptors: Vec<_> = desc_chain.clone().collect();
-            let desc_in_hdr = descriptors[descriptors.len() - 1];
-            let mut len = size_of::<VirtioI2cInHdr>() as u32;
-
-            if descriptors.len() == 3 {
-                let desc_buf = descriptors[1];
-
-                // Write the data read from the I2C device
-                if reqs[i].flags == I2C_M_RD {
-                    desc_chain
-                        .memory()
-                        .write(&reqs[i].buf, desc_buf.addr())
-                        .map_err(|_| Error::DescriptorWriteFailed)?;
-                }
-
-                if in_hdr.status == VIRTIO_I2C_MSG_OK {
-                    len += desc_buf.len();
-                }
-            }
+        self.metrics.record(
+            self.socket,
+            self.i2c_map.client_adapter(reqs[0].addr),
+            &reqs,
+            &result,
+            start.elapsed(),
+        );
 
-            // Write the transfer status
-            desc_chain
-                .memory()
-                .write_obj::<VirtioI2cInHdr>(in_hdr, desc_in_hdr.addr())
-                .map_err(|_| Error::DescriptorWriteFailed)?;
+        for (desc_chain, req) in chains.iter().zip(reqs.iter()) {
+            let len = complete_request(desc_chain, req, &result).unwrap_or_else(|e| {
+                warn!("Failed to complete request: {}", e);
+                0
+            });
 
-            if vring.add_used(desc_chain.head_index(), len).is_err() {
-                warn!("Couldn't return used descriptors to the ring");
-            }
+            add_used(vring, desc_chain, len);
         }
 
         Ok(true)
@@ -267,6 +187,12 @@ impl<D: I2cDevice> VhostUserI2cBackend<D> {
     }
 }
 
+fn add_used(vring: &VringRwLock, desc_chain: &I2cDescriptorChain, len: u32) {
+    if vring.add_used(desc_chain.head_index(), len).is_err() {
+        warn!("Couldn't return used descriptors to the ring");
+    }
+}
+
 /// VhostUserBackendMut trait methods
 impl<D: 'static + I2cDevice + Sync + Send> VhostUserBackendMut<VringRwLock, ()>
     for VhostUserI2cBackend<D>
@@ -276,11 +202,12 @@ impl<D: 'static + I2cDevice + Sync + Send> VhostUserBackendMut<VringRwLock, ()>
     }
 
     fn max_queue_size(&self) -> usize {
-        QUEUE_SIZE
+        self.queue_size
     }
 
     fn features(&self) -> u64 {
-        // this matches the current libvhost defaults except VHOST_F_LOG_ALL
+        // this matches the current libvhost defaults except VHOST_F_LOG_ALL, the dirty page
+        // log isn't supported by vhost-user-backend 0.1, which prevents live migration.
         1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_F_NOTIFY_ON_EMPTY
             | 1 << VIRTIO_RING_F_INDIRECT_DESC
@@ -289,12 +216,27 @@ impl<D: 'static + I2cDevice + Sync + Send> VhostUserBackendMut<VringRwLock, ()>
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
     }
 
+    // INFLIGHT_SHMFD isn't offered: vhost-user-backend 0.1 fails the inflight messages with
+    // InvalidOperation, the requests in flight can't be tracked across a restart.
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
         VhostUserProtocolFeatures::MQ
     }
 
+    // Called on every feature negotiation, by a new VMM connection or a guest driver reload,
+    // which starts from a clean state. The event index is set again once the vrings are
+    // started. Nothing else is kept between the requests, which are completed synchronously.
+    fn acked_features(&mut self, features: u64) {
+        debug!("Negotiated features {:#x}", features);
+        self.event_idx = false;
+    }
+
+    // Called with the negotiated features whenever the vrings are (re)started.
     fn set_event_idx(&mut self, enabled: bool) {
-        dbg!(self.event_idx = enabled);
+        debug!(
+            "Event index {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        self.event_idx = enabled;
     }
 
     fn update_memory(
@@ -325,9 +267,16 @@ impl<D: 'static + I2cDevice + Sync + Send> VhostUserBackendMut<VringRwLock, ()>
                     // calling process_queue() until it stops finding new
                     // requests on the queue.
                     loop {
-                        vring.disable_notification().unwrap();
+                        // The rings are in guest memory, a failure must not bring the
+                        // thread down.
+                        vring
+                            .disable_notification()
+                            .map_err(|_| Error::NotificationFailed)?;
                         self.process_queue(vring)?;
-                        if !vring.enable_notification().unwrap() {
+                        if !vring
+                            .enable_notification()
+                            .map_err(|_| Error::NotificationFailed)?
+                        {
                             break;
                         }
                     }
@@ -353,20 +302,26 @@ impl<D: 'static + I2cDevice + Sync + Send> VhostUserBackendMut<VringRwLock, ()>
 #[cfg(test)]
 mod tests {
     use std::convert::TryFrom;
+    use std::mem::size_of;
 
     use virtio_queue::defs::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
     use virtio_queue::{mock::MockSplitQueue, Descriptor};
-    use vm_memory::{Address, GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
 
-    use super::Error;
     use super::*;
-    use crate::i2c::tests::{update_rdwr_buf, verify_rdwr_buf, DummyDevice};
-    use crate::AdapterConfig;
+    use virtio_i2c::config::AdapterConfig;
+    use virtio_i2c::i2c::mock::{update_rdwr_buf, verify_rdwr_buf, DummyDevice};
+    use virtio_i2c::ratelimit::RateLimitConfig;
+    use virtio_i2c::request::{
+        self, VirtioI2cInHdr, VirtioI2cOutHdr, VIRTIO_I2C_FLAGS_M_RD, VIRTIO_I2C_MSG_ERR,
+        VIRTIO_I2C_MSG_OK,
+    };
+    use vm_memory::ByteValued;
 
     // Prepares a single chain of descriptors
     fn prepare_desc_chain(
         start_addr: GuestAddress,
-        buf: &mut Vec<u8>,
+        buf: &mut [u8],
         flag: u32,
         client_addr: u16,
     ) -> I2cDescriptorChain {
@@ -515,7 +470,15 @@ mod tests {
     fn process_requests_success() {
         let device_config = AdapterConfig::try_from("1:4,2:32:21,5:10:23").unwrap();
         let i2c_map = I2cMap::<DummyDevice>::new(&device_config).unwrap();
-        let backend = VhostUserI2cBackend::new(Arc::new(i2c_map)).unwrap();
+        let backend = VhostUserI2cBackend::new(
+            Arc::new(i2c_map),
+            Arc::new(Metrics::new()),
+            0,
+            None,
+            QUEUE_SIZE,
+            None,
+        )
+        .unwrap();
         let mem = GuestMemoryAtomic::new(
             GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap(),
         );
@@ -573,7 +536,15 @@ mod tests {
     fn process_requests_failure() {
         let device_config = AdapterConfig::try_from("1:4,2:32:21,5:10:23").unwrap();
         let i2c_map = I2cMap::<DummyDevice>::new(&device_config).unwrap();
-        let backend = VhostUserI2cBackend::new(Arc::new(i2c_map)).unwrap();
+        let backend = VhostUserI2cBackend::new(
+            Arc::new(i2c_map),
+            Arc::new(Metrics::new()),
+            0,
+            None,
+            QUEUE_SIZE,
+            None,
+        )
+        .unwrap();
         let mem = GuestMemoryAtomic::new(
             GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap(),
         );
@@ -583,22 +554,22 @@ mod tests {
         let flags: Vec<u16> = vec![0];
         let len: Vec<u32> = vec![0];
         let desc_chain = prepare_desc_chain_dummy(None, flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::UnexpectedDescriptorCount(1)
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedDescriptorCount(1),
         );
 
         // Four descriptors
         let flags: Vec<u16> = vec![0, 0, 0, 0];
         let len: Vec<u32> = vec![0, 0, 0, 0];
         let desc_chain = prepare_desc_chain_dummy(None, flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::UnexpectedDescriptorCount(4)
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedDescriptorCount(4),
         );
 
         // Write only out hdr
@@ -609,22 +580,22 @@ mod tests {
             size_of::<u8>() as u32,
         ];
         let desc_chain = prepare_desc_chain_dummy(None, flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::UnexpectedWriteOnlyDescriptor(0)
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedWriteOnlyDescriptor(0),
         );
 
         // Invalid out hdr length
         let flags: Vec<u16> = vec![0, 0, VIRTQ_DESC_F_WRITE];
         let len: Vec<u32> = vec![100, 1, size_of::<u8>() as u32];
         let desc_chain = prepare_desc_chain_dummy(None, flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::UnexpectedDescriptorSize(size_of::<VirtioI2cOutHdr>(), 100)
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedDescriptorSize(size_of::<VirtioI2cOutHdr>(), 100),
         );
 
         // Invalid out hdr address
@@ -636,11 +607,11 @@ mod tests {
             size_of::<u8>() as u32,
         ];
         let desc_chain = prepare_desc_chain_dummy(Some(addr), flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::DescriptorReadFailed
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::DescriptorReadFailed,
         );
 
         // Read only in hdr
@@ -651,22 +622,22 @@ mod tests {
             size_of::<u8>() as u32,
         ];
         let desc_chain = prepare_desc_chain_dummy(None, flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::UnexpectedReadableDescriptor(2)
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedReadableDescriptor(2),
         );
 
         // Invalid in hdr length
         let flags: Vec<u16> = vec![0, 0, VIRTQ_DESC_F_WRITE];
         let len: Vec<u32> = vec![size_of::<VirtioI2cOutHdr>() as u32, 1, 100];
         let desc_chain = prepare_desc_chain_dummy(None, flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::UnexpectedDescriptorSize(size_of::<u8>(), 100)
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedDescriptorSize(size_of::<u8>(), 100),
         );
 
         // Invalid in hdr address
@@ -678,12 +649,7 @@ mod tests {
             size_of::<u8>() as u32,
         ];
         let desc_chain = prepare_desc_chain_dummy(Some(addr), flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::DescriptorWriteFailed
-        );
+        backend.process_requests(vec![desc_chain], &vring).unwrap();
 
         // Invalid buf length
         let flags: Vec<u16> = vec![0, 0, VIRTQ_DESC_F_WRITE];
@@ -693,11 +659,26 @@ mod tests {
             size_of::<u8>() as u32,
         ];
         let desc_chain = prepare_desc_chain_dummy(None, flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::UnexpectedDescriptorSize(1, 0)
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedDescriptorSize(1, 0),
+        );
+
+        // Buf length not fitting in a message
+        let flags: Vec<u16> = vec![0, 0, VIRTQ_DESC_F_WRITE];
+        let len: Vec<u32> = vec![
+            size_of::<VirtioI2cOutHdr>() as u32,
+            0x10000,
+            size_of::<u8>() as u32,
+        ];
+        let desc_chain = prepare_desc_chain_dummy(None, flags, len);
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedDescriptorSize(u16::MAX as usize, 0x10000),
         );
 
         // Invalid buf address
@@ -709,11 +690,11 @@ mod tests {
             size_of::<u8>() as u32,
         ];
         let desc_chain = prepare_desc_chain_dummy(Some(addr), flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::DescriptorReadFailed
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::DescriptorReadFailed,
         );
 
         // Write only buf for write operation
@@ -724,11 +705,11 @@ mod tests {
             size_of::<u8>() as u32,
         ];
         let desc_chain = prepare_desc_chain_dummy(None, flags, len);
-        assert_eq!(
-            backend
-                .process_requests(vec![desc_chain], &vring)
-                .unwrap_err(),
-            Error::UnexpectedWriteOnlyDescriptor(1)
+        check_invalid(
+            &backend,
+            &vring,
+            desc_chain,
+            request::Error::UnexpectedWriteOnlyDescriptor(1),
         );
 
         // Missing buffer for I2C rdwr transfer
@@ -742,11 +723,198 @@ mod tests {
         validate_desc_chains(desc_chains, VIRTIO_I2C_MSG_ERR);
     }
 
+    // Processes a malformed descriptor chain, refused with `error`. The chain is completed on
+    // its own, without failing the queue.
+    fn check_invalid(
+        backend: &VhostUserI2cBackend<DummyDevice>,
+        vring: &VringRwLock,
+        desc_chain: I2cDescriptorChain,
+        error: request::Error,
+    ) {
+        assert_eq!(request::parse_request(&desc_chain).err(), Some(error));
+        backend.process_requests(vec![desc_chain], vring).unwrap();
+    }
+
+    #[test]
+    fn process_requests_malformed_chain() {
+        let device_config = AdapterConfig::try_from("1:4").unwrap();
+        let i2c_map = I2cMap::<DummyDevice>::new(&device_config).unwrap();
+        let mut backend = VhostUserI2cBackend::new(
+            Arc::new(i2c_map),
+            Arc::new(Metrics::new()),
+            0,
+            None,
+            QUEUE_SIZE,
+            None,
+        )
+        .unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let vq = MockSplitQueue::new(&mem, 16);
+        let hdr_len = size_of::<VirtioI2cOutHdr>() as u32;
+        let out_hdr = VirtioI2cOutHdr {
+            addr: From::from(4 << 1),
+            padding: From::from(0),
+            flags: From::from(0),
+        };
+        let mut data = vec![0; 8];
+        update_rdwr_buf(&mut data);
+        mem.write_slice(out_hdr.as_slice(), GuestAddress(0x400))
+            .unwrap();
+        mem.write_slice(&data, GuestAddress(0x500)).unwrap();
+
+        // The out header of the first chain is too long, the second one is valid.
+        let descs = [
+            (0x400, 100, VIRTQ_DESC_F_NEXT, 1),
+            (0x600, 1, VIRTQ_DESC_F_WRITE, 0),
+            (0x400, hdr_len, VIRTQ_DESC_F_NEXT, 3),
+            (0x500, 8, VIRTQ_DESC_F_NEXT, 4),
+            (0x700, 1, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        for (i, (addr, len, flags, next)) in descs.iter().enumerate() {
+            vq.desc_table()
+                .store(i as u16, Descriptor::new(*addr, *len, *flags, *next));
+        }
+        mem.write_obj(0xffu8, GuestAddress(0x600)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(0x700)).unwrap();
+
+        // Both chains are available, for a single kick.
+        mem.write_obj(0u16, vq.avail_addr().unchecked_add(4))
+            .unwrap();
+        mem.write_obj(2u16, vq.avail_addr().unchecked_add(6))
+            .unwrap();
+        mem.write_obj(2u16, vq.avail_addr().unchecked_add(2))
+            .unwrap();
+
+        let vring = VringRwLock::new(GuestMemoryAtomic::new(mem.clone()), 16);
+        vring.set_queue_info(
+            vq.desc_table_addr().raw_value(),
+            vq.avail_addr().raw_value(),
+            vq.used_addr().raw_value(),
+        );
+        vring.set_queue_size(16);
+        vring.set_queue_ready(true);
+        vring.set_enabled(true);
+
+        backend
+            .handle_event(0, EventSet::IN, std::slice::from_ref(&vring), 0)
+            .unwrap();
+
+        // Both are completed, the first one with an error.
+        let used = vq.used_addr();
+        assert_eq!(mem.read_obj::<u16>(used.unchecked_add(2)).unwrap(), 2);
+        assert_eq!(mem.read_obj::<u32>(used.unchecked_add(4)).unwrap(), 0);
+        assert_eq!(mem.read_obj::<u32>(used.unchecked_add(8)).unwrap(), 1);
+        assert_eq!(mem.read_obj::<u32>(used.unchecked_add(12)).unwrap(), 2);
+        assert_eq!(mem.read_obj::<u32>(used.unchecked_add(16)).unwrap(), 9);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x600)).unwrap(),
+            VIRTIO_I2C_MSG_ERR
+        );
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x700)).unwrap(),
+            VIRTIO_I2C_MSG_OK
+        );
+    }
+
+    #[test]
+    fn process_requests_client_subset() {
+        let device_config = AdapterConfig::try_from("1:4,2:32:21").unwrap();
+        let i2c_map = I2cMap::<DummyDevice>::new(&device_config).unwrap();
+        let backend = VhostUserI2cBackend::new(
+            Arc::new(i2c_map),
+            Arc::new(Metrics::new()),
+            0,
+            Some(vec![32]),
+            256,
+            None,
+        )
+        .unwrap();
+        let mem = GuestMemoryAtomic::new(
+            GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap(),
+        );
+        let vring = VringRwLock::new(mem, 0x1000);
+
+        assert_eq!(backend.max_queue_size(), 256);
+
+        // Client in the subset
+        let mut buf: Vec<u8> = vec![0; 30];
+        let desc_chains = vec![prepare_desc_chain(GuestAddress(0), &mut buf, 0, 32)];
+
+        backend
+            .process_requests(desc_chains.clone(), &vring)
+            .unwrap();
+        validate_desc_chains(desc_chains, VIRTIO_I2C_MSG_OK);
+
+        // Client of the adapter, but not in the subset
+        let mut buf: Vec<u8> = vec![0; 30];
+        let desc_chains = vec![prepare_desc_chain(GuestAddress(0), &mut buf, 0, 4)];
+
+        backend
+            .process_requests(desc_chains.clone(), &vring)
+            .unwrap();
+        validate_desc_chains(desc_chains, VIRTIO_I2C_MSG_ERR);
+
+        // Only a later message of the batch is outside the subset.
+        let mut buf: Vec<Vec<u8>> = vec![vec![0; 30]; 2];
+        let desc_chains = vec![
+            prepare_desc_chain(GuestAddress(0), &mut buf[0], 0, 32),
+            prepare_desc_chain(GuestAddress(0), &mut buf[1], 0, 4),
+        ];
+
+        backend
+            .process_requests(desc_chains.clone(), &vring)
+            .unwrap();
+        validate_desc_chains(desc_chains, VIRTIO_I2C_MSG_ERR);
+    }
+
+    #[test]
+    fn process_requests_rate_limit() {
+        let device_config = AdapterConfig::try_from("1:4").unwrap();
+        let i2c_map = I2cMap::<DummyDevice>::new(&device_config).unwrap();
+        let config = RateLimitConfig::try_from("socket=0,ops=1,mode=reject").unwrap();
+        let backend = VhostUserI2cBackend::new(
+            Arc::new(i2c_map),
+            Arc::new(Metrics::new()),
+            0,
+            None,
+            QUEUE_SIZE,
+            Some(Arc::new(RateLimiter::new(&config, config.scope))),
+        )
+        .unwrap();
+        let mem = GuestMemoryAtomic::new(
+            GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap(),
+        );
+        let vring = VringRwLock::new(mem, 0x1000);
+
+        let mut buf: Vec<u8> = vec![0; 30];
+        let desc_chains = vec![prepare_desc_chain(GuestAddress(0), &mut buf, 0, 4)];
+        backend
+            .process_requests(desc_chains.clone(), &vring)
+            .unwrap();
+        validate_desc_chains(desc_chains, VIRTIO_I2C_MSG_OK);
+
+        // Over the limit
+        let mut buf: Vec<u8> = vec![0; 30];
+        let desc_chains = vec![prepare_desc_chain(GuestAddress(0), &mut buf, 0, 4)];
+        backend
+            .process_requests(desc_chains.clone(), &vring)
+            .unwrap();
+        validate_desc_chains(desc_chains, VIRTIO_I2C_MSG_ERR);
+    }
+
     #[test]
     fn verify_backend() {
         let device_config = AdapterConfig::try_from("1:4,2:32:21,5:10:23").unwrap();
         let i2c_map: I2cMap<DummyDevice> = I2cMap::new(&device_config).unwrap();
-        let mut backend = VhostUserI2cBackend::new(Arc::new(i2c_map)).unwrap();
+        let mut backend = VhostUserI2cBackend::new(
+            Arc::new(i2c_map),
+            Arc::new(Metrics::new()),
+            0,
+            None,
+            QUEUE_SIZE,
+            None,
+        )
+        .unwrap();
 
         assert_eq!(backend.num_queues(), NUM_QUEUES);
         assert_eq!(backend.max_queue_size(), QUEUE_SIZE);
@@ -754,11 +922,20 @@ mod tests {
         assert_eq!(backend.protocol_features(), VhostUserProtocolFeatures::MQ);
 
         assert_eq!(backend.queues_per_thread(), vec![0xffff_ffff]);
-        assert_eq!(backend.get_config(0, 0), vec![]);
+        assert_eq!(backend.get_config(0, 0), Vec::<u8>::new());
 
         backend.set_event_idx(true);
         assert!(backend.event_idx);
 
+        // The guest driver is reloaded, the features are negotiated again.
+        backend.acked_features(backend.features());
+        assert!(!backend.event_idx);
+
+        // Without EVENT_IDX this time.
+        backend.set_event_idx(true);
+        backend.set_event_idx(false);
+        assert!(!backend.event_idx);
+
         assert!(backend.exit_event(0).is_some());
 
         let mem = GuestMemoryAtomic::new(
@@ -769,7 +946,7 @@ mod tests {
         let vring = VringRwLock::new(mem, 0x1000);
         assert_eq!(
             backend
-                .handle_event(0, EventSet::OUT, &[vring.clone()], 0)
+                .handle_event(0, EventSet::OUT, std::slice::from_ref(&vring), 0)
                 .unwrap_err()
                 .kind(),
             io::ErrorKind::Other
@@ -777,7 +954,7 @@ mod tests {
 
         assert_eq!(
             backend
-                .handle_event(1, EventSet::IN, &[vring.clone()], 0)
+                .handle_event(1, EventSet::IN, std::slice::from_ref(&vring), 0)
                 .unwrap_err()
                 .kind(),
             io::ErrorKind::Other
@@ -786,7 +963,7 @@ mod tests {
         // Hit the loop part
         backend.set_event_idx(true);
         backend
-            .handle_event(0, EventSet::IN, &[vring.clone()], 0)
+            .handle_event(0, EventSet::IN, std::slice::from_ref(&vring), 0)
             .unwrap();
 
         // Hit the non-loop part
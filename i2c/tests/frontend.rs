@@ -0,0 +1,412 @@
+// vhost-user frontend, to test the daemon over its socket
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use clap::{load_yaml, App};
+use vhost::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
+use vhost::vhost_user::{Master, VhostUserMaster};
+use vhost::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
+use vhost_device_i2c::start_backend;
+use virtio_bindings::bindings::virtio_net::VIRTIO_F_VERSION_1;
+use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use virtio_i2c::i2c::mock::{update_rdwr_buf, verify_rdwr_buf, DummyDevice};
+use virtio_i2c::i2c::{I2cReq, I2C_M_RD};
+use virtio_i2c::request::{
+    VirtioI2cInHdr, VirtioI2cOutHdr, VIRTIO_I2C_FLAGS_M_RD, VIRTIO_I2C_MSG_ERR, VIRTIO_I2C_MSG_OK,
+};
+use virtio_queue::defs::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use virtio_queue::Descriptor;
+use vm_memory::{Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmm_sys_util::tempdir::TempDir;
+use vmm_sys_util::tempfile::TempFile;
+
+/// Size of the guest memory shared with the backend.
+const MEM_SIZE: usize = 0x10000;
+
+/// Guest layout of the request queue, and of the buffers of the requests.
+const QUEUE_LEN: u16 = 16;
+const DESC_TABLE_ADDR: u64 = 0x0;
+const AVAIL_RING_ADDR: u64 = 0x1000;
+const USED_RING_ADDR: u64 = 0x2000;
+const BUFFERS_ADDR: u64 = 0x3000;
+
+/// Location of the used_event field, right after the available ring.
+const USED_EVENT_ADDR: u64 = AVAIL_RING_ADDR + 4 + 2 * QUEUE_LEN as u64;
+
+/// How long to wait for the backend to complete the requests, in milliseconds.
+const TIMEOUT_MS: i32 = 5000;
+
+/// Plays the role of the VMM and of the guest driver: connects to the daemon started from a
+/// command line, sets up the guest memory and the request queue, and submits the requests the
+/// way the virtio-i2c driver does.
+struct Frontend {
+    master: Master,
+    mem: GuestMemoryMmap,
+    // Address of the guest memory in this process, the vring addresses are translated with it.
+    host_addr: u64,
+    kick: EventFd,
+    call: EventFd,
+    event_idx: bool,
+    // Index of the next available and used ring entries.
+    avail_idx: u16,
+    used_idx: u16,
+    // Owns the socket of the daemon.
+    _dir: TempDir,
+}
+
+impl Frontend {
+    /// Starts the daemon on a single socket, with the options of the socket, e.g. its devices,
+    /// and the other `options` of the command line, and connects to it. EVENT_IDX is negotiated
+    /// as requested.
+    fn new(socket_options: &str, options: &[&str], event_idx: bool) -> Self {
+        let dir = TempDir::new_with_prefix("/tmp/vi2c-frontend").unwrap();
+        let socket = dir.as_path().join("vi2c.sock");
+
+        let mut socket_arg = format!("path={}", socket.to_str().unwrap());
+        if !socket_options.is_empty() {
+            socket_arg = socket_arg + "," + socket_options;
+        }
+
+        let mut args = vec!["vhost-device-i2c", "--socket", &socket_arg];
+        args.extend_from_slice(options);
+
+        let yaml = load_yaml!("../src/cli.yaml");
+        let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+
+        // The daemon runs until the end of the tests, and serves the next guest once the
+        // frontend is dropped.
+        spawn(move || start_backend::<DummyDevice>(cmd_args).unwrap());
+        wait_socket(&socket);
+
+        let master = Master::connect(&socket, 1).unwrap();
+
+        // The memory is backed by a file, for the backend to map it as well.
+        let file: File = TempFile::new().unwrap().into_file();
+        file.set_len(MEM_SIZE as u64).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            MEM_SIZE,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+        let host_addr = mem.get_host_address(GuestAddress(0)).unwrap() as u64;
+
+        let mut frontend = Frontend {
+            master,
+            mem,
+            host_addr,
+            kick: EventFd::new(EFD_NONBLOCK).unwrap(),
+            call: EventFd::new(EFD_NONBLOCK).unwrap(),
+            event_idx,
+            avail_idx: 0,
+            used_idx: 0,
+            _dir: dir,
+        };
+
+        frontend.negotiate();
+        frontend.set_mem_table(&file);
+        frontend.setup_queue();
+        frontend
+    }
+
+    /// Negotiates the features offered by the backend, with or without EVENT_IDX.
+    fn negotiate(&mut self) {
+        self.master.set_owner().unwrap();
+
+        let features = self.master.get_features().unwrap();
+        assert_ne!(features & (1 << VIRTIO_F_VERSION_1), 0);
+        assert_ne!(features & (1 << VIRTIO_RING_F_EVENT_IDX), 0);
+        assert_ne!(
+            features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits(),
+            0
+        );
+
+        let features = match self.event_idx {
+            true => features,
+            false => features & !(1 << VIRTIO_RING_F_EVENT_IDX),
+        };
+        self.master.set_features(features).unwrap();
+
+        let protocol_features = self.master.get_protocol_features().unwrap();
+        assert!(protocol_features.contains(VhostUserProtocolFeatures::MQ));
+
+        self.master
+            .set_protocol_features(protocol_features)
+            .unwrap();
+        assert_eq!(self.master.get_queue_num().unwrap(), 1);
+    }
+
+    fn set_mem_table(&mut self, file: &File) {
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: MEM_SIZE as u64,
+            userspace_addr: self.host_addr,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+
+        self.master.set_mem_table(&[region]).unwrap();
+    }
+
+    /// Sets up and enables the request queue, as the VMM does once the guest driver is ready.
+    fn setup_queue(&mut self) {
+        let config = VringConfigData {
+            queue_max_size: QUEUE_LEN,
+            queue_size: QUEUE_LEN,
+            flags: 0,
+            desc_table_addr: self.host_addr + DESC_TABLE_ADDR,
+            used_ring_addr: self.host_addr + USED_RING_ADDR,
+            avail_ring_addr: self.host_addr + AVAIL_RING_ADDR,
+            log_addr: None,
+        };
+
+        self.master.set_vring_num(0, QUEUE_LEN).unwrap();
+        self.master.set_vring_addr(0, &config).unwrap();
+        self.master.set_vring_base(0, 0).unwrap();
+        self.master.set_vring_call(0, &self.call).unwrap();
+        self.master.set_vring_kick(0, &self.kick).unwrap();
+        self.master.set_vring_enable(0, true).unwrap();
+
+        // The enable message isn't acknowledged, and a kick seen before it is handled is
+        // dropped. Wait for a reply, the messages are handled in order.
+        self.master.get_features().unwrap();
+    }
+
+    /// Stores a descriptor, and returns the address of the next buffer.
+    fn add_desc(&self, index: u16, addr: u64, len: u32, flags: u16) -> u64 {
+        let desc = Descriptor::new(addr, len, flags, index + 1);
+
+        self.mem
+            .write_obj(desc, GuestAddress(DESC_TABLE_ADDR + 16 * index as u64))
+            .unwrap();
+        addr + len as u64
+    }
+
+    /// Submits the messages of a transfer with a single kick, one descriptor chain per message,
+    /// and waits for the backend to complete them. Returns the status of the transfer, the
+    /// buffers of the read messages are updated.
+    pub fn transfer(&mut self, reqs: &mut [I2cReq]) -> u8 {
+        let mut index = 0;
+        let mut addr = BUFFERS_ADDR;
+        let mut chains = Vec::new();
+
+        for req in reqs.iter() {
+            let head = index;
+            let read = req.flags & I2C_M_RD != 0;
+            let out_hdr = VirtioI2cOutHdr {
+                addr: From::from(req.addr << 1),
+                padding: From::from(0),
+                flags: From::from(if read { VIRTIO_I2C_FLAGS_M_RD } else { 0 }),
+            };
+
+            self.mem.write_obj(out_hdr, GuestAddress(addr)).unwrap();
+            addr = self.add_desc(
+                index,
+                addr,
+                size_of::<VirtioI2cOutHdr>() as u32,
+                VIRTQ_DESC_F_NEXT,
+            );
+            index += 1;
+
+            // Zero-length messages have no buffer.
+            let buf = if req.len != 0 {
+                let flags = if read { VIRTQ_DESC_F_WRITE } else { 0 };
+
+                self.mem
+                    .write_slice(&req.buf.to_vec()[..req.len as usize], GuestAddress(addr))
+                    .unwrap();
+                let buf = addr;
+                addr = self.add_desc(index, addr, req.len as u32, flags | VIRTQ_DESC_F_NEXT);
+                index += 1;
+                Some(buf)
+            } else {
+                None
+            };
+
+            let in_hdr = addr;
+            addr = self.add_desc(index, addr, 1, VIRTQ_DESC_F_WRITE);
+            index += 1;
+
+            // Publish the chain in the available ring.
+            let slot = self.avail_idx % QUEUE_LEN;
+            self.mem
+                .write_obj(head, GuestAddress(AVAIL_RING_ADDR + 4 + 2 * slot as u64))
+                .unwrap();
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            chains.push((buf, in_hdr));
+        }
+
+        // With EVENT_IDX, the backend only notifies once the used index moves past used_event,
+        // ask for a notification once all the requests are completed.
+        if self.event_idx {
+            let used_event = self.used_idx.wrapping_add(reqs.len() as u16 - 1);
+            self.mem
+                .write_obj(used_event, GuestAddress(USED_EVENT_ADDR))
+                .unwrap();
+        }
+
+        self.mem
+            .write_obj(self.avail_idx, GuestAddress(AVAIL_RING_ADDR + 2))
+            .unwrap();
+        self.kick.write(1).unwrap();
+        self.wait_used(reqs.len() as u16);
+
+        let mut status = None;
+        for (req, (buf, in_hdr)) in reqs.iter_mut().zip(chains) {
+            let in_hdr = self
+                .mem
+                .read_obj::<VirtioI2cInHdr>(GuestAddress(in_hdr))
+                .unwrap();
+
+            // All the messages of a transfer share its status.
+            assert_eq!(*status.get_or_insert(in_hdr.status), in_hdr.status);
+
+            if let Some(buf) = buf {
+                if req.flags & I2C_M_RD != 0 {
+                    let mut data = vec![0; req.len as usize];
+                    self.mem.read_slice(&mut data, GuestAddress(buf)).unwrap();
+                    req.buf.write_slice(0, &data);
+                }
+            }
+        }
+
+        status.unwrap()
+    }
+
+    /// Waits for the backend to notify the completion of the requests.
+    fn wait_used(&mut self, count: u16) {
+        let expected = self.used_idx.wrapping_add(count);
+
+        loop {
+            let used_idx = self
+                .mem
+                .read_obj::<u16>(GuestAddress(USED_RING_ADDR + 2))
+                .unwrap();
+
+            if used_idx == expected {
+                break;
+            }
+
+            let mut pollfd = libc::pollfd {
+                fd: self.call.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+
+            // SAFETY: Safe as the structure is valid, and a single one is passed.
+            let ret = unsafe { libc::poll(&mut pollfd, 1, TIMEOUT_MS) };
+            assert!(ret > 0, "Timed out waiting for the backend");
+            self.call.read().unwrap();
+        }
+
+        self.used_idx = expected;
+    }
+}
+
+/// Waits for the daemon to listen on the socket.
+fn wait_socket(socket: &Path) {
+    let start = Instant::now();
+
+    while !socket.exists() {
+        assert!(
+            start.elapsed() < Duration::from_millis(TIMEOUT_MS as u64),
+            "Timed out waiting for the daemon"
+        );
+        sleep(Duration::from_millis(10));
+    }
+}
+
+fn message(addr: u16, flags: u16, len: u16) -> I2cReq {
+    let mut buf = vec![0; len as usize];
+
+    // Pattern checked by DummyDevice.
+    if flags & I2C_M_RD == 0 {
+        update_rdwr_buf(&mut buf);
+    }
+
+    I2cReq {
+        addr,
+        flags,
+        len,
+        buf: buf.into(),
+    }
+}
+
+fn check_transfer(event_idx: bool) {
+    let mut frontend = Frontend::new("", &["-l", "1:4,2:32:21,5:10:23"], event_idx);
+
+    // Write
+    let mut reqs = vec![message(4, 0, 30)];
+    assert_eq!(frontend.transfer(&mut reqs), VIRTIO_I2C_MSG_OK);
+
+    // Read
+    let mut reqs = vec![message(32, I2C_M_RD, 30)];
+    assert_eq!(frontend.transfer(&mut reqs), VIRTIO_I2C_MSG_OK);
+    verify_rdwr_buf(&reqs[0].buf.to_vec());
+    assert_eq!(reqs[0].buf.read_byte(29), 30);
+
+    // Write followed by a read, in a single transfer
+    let mut reqs = vec![message(10, 0, 2), message(10, I2C_M_RD, 8)];
+    assert_eq!(frontend.transfer(&mut reqs), VIRTIO_I2C_MSG_OK);
+    verify_rdwr_buf(&reqs[1].buf.to_vec());
+
+    // More requests than the size of the queue, to wrap around the rings.
+    for _ in 0..QUEUE_LEN {
+        let mut reqs = vec![message(23, I2C_M_RD, 4)];
+        assert_eq!(frontend.transfer(&mut reqs), VIRTIO_I2C_MSG_OK);
+        assert_eq!(reqs[0].buf, vec![1, 2, 3, 4]);
+    }
+}
+
+fn check_failure(event_idx: bool) {
+    let mut frontend = Frontend::new("devices=4:32", &["-l", "1:4,2:32:21"], event_idx);
+
+    // No such client
+    let mut reqs = vec![message(5, I2C_M_RD, 4)];
+    assert_eq!(frontend.transfer(&mut reqs), VIRTIO_I2C_MSG_ERR);
+    assert_eq!(reqs[0].buf, vec![0; 4]);
+
+    // Client not exposed to the guest
+    let mut reqs = vec![message(21, 0, 4)];
+    assert_eq!(frontend.transfer(&mut reqs), VIRTIO_I2C_MSG_ERR);
+
+    // Zero-length messages are refused by DummyDevice.
+    let mut reqs = vec![message(4, 0, 0)];
+    assert_eq!(frontend.transfer(&mut reqs), VIRTIO_I2C_MSG_ERR);
+
+    // The queue is still usable.
+    let mut reqs = vec![message(32, I2C_M_RD, 4)];
+    assert_eq!(frontend.transfer(&mut reqs), VIRTIO_I2C_MSG_OK);
+    assert_eq!(reqs[0].buf, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn test_frontend_transfer() {
+    check_transfer(false);
+}
+
+#[test]
+fn test_frontend_transfer_event_idx() {
+    check_transfer(true);
+}
+
+#[test]
+fn test_frontend_failure() {
+    check_failure(false);
+}
+
+#[test]
+fn test_frontend_failure_event_idx() {
+    check_failure(true);
+}
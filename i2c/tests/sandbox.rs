@@ -0,0 +1,123 @@
+// Runs the daemon sandboxed, in a process of its own as the sandbox can't be undone
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Without the libtest harness, which runs the tests on threads that don't block the signals
+// handled by the daemon.
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use clap::{load_yaml, App};
+use vhost::vhost_user::Master;
+use vhost::VhostBackend;
+use vhost_device_i2c::start_backend;
+use virtio_i2c::i2c::mock::DummyDevice;
+use vmm_sys_util::tempdir::TempDir;
+
+/// Set for the daemon process, with the directory of its socket.
+const DAEMON_DIR_ENV: &str = "VHOST_DEVICE_I2C_SANDBOX_DIR";
+
+/// How long to wait for the daemon, in milliseconds.
+const TIMEOUT_MS: u64 = 5000;
+
+fn main() {
+    if let Some(dir) = env::var_os(DAEMON_DIR_ENV) {
+        exit(run_daemon(Path::new(&dir)));
+    }
+
+    let dir = TempDir::new_with_prefix("/tmp/vi2c-sandbox").unwrap();
+    let socket = dir.as_path().join("vi2c.sock");
+
+    let mut daemon = Daemon(
+        Command::new(env::current_exe().unwrap())
+            .env(DAEMON_DIR_ENV, dir.as_path())
+            .spawn()
+            .unwrap(),
+    );
+    wait_for(|| socket.exists());
+
+    // Connects twice, the daemon serves the next frontend once the first one is gone.
+    for _ in 0..2 {
+        let master = Master::connect(&socket, 1).unwrap();
+        master.set_owner().unwrap();
+        assert_ne!(master.get_features().unwrap(), 0);
+    }
+
+    // SAFETY: Safe as the pid is the one of the child process.
+    assert_eq!(
+        unsafe { libc::kill(daemon.0.id() as libc::pid_t, libc::SIGTERM) },
+        0
+    );
+    assert!(daemon.0.wait().unwrap().success());
+
+    // The daemon removed its socket and the directory of its peer gate.
+    assert_eq!(gate_dirs(dir.as_path()), Vec::<PathBuf>::new());
+    assert!(!socket.exists());
+
+    println!("test sandbox::start_backend ... ok");
+}
+
+/// Kills the daemon if the test fails before it exits.
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Starts the daemon sandboxed, on a socket in `dir`, only served to the processes of this user.
+fn run_daemon(dir: &Path) -> i32 {
+    let socket = format!("path={}", dir.join("vi2c.sock").to_str().unwrap());
+    // SAFETY: Safe as getuid() can't fail.
+    let peer = format!("uid={}", unsafe { libc::getuid() });
+    let args = [
+        "vhost-device-i2c",
+        "--socket",
+        &socket,
+        "-l",
+        "1:4",
+        "--sandbox",
+        "--allow-peer",
+        &peer,
+    ];
+
+    let yaml = load_yaml!("../src/cli.yaml");
+    let cmd_args = App::from(yaml).try_get_matches_from(args).unwrap();
+
+    match start_backend::<DummyDevice>(cmd_args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Daemon failed: {}", e);
+            1
+        }
+    }
+}
+
+fn gate_dirs(dir: &Path) -> Vec<PathBuf> {
+    dir.read_dir()
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect()
+}
+
+fn wait_for<F: Fn() -> bool>(f: F) {
+    let start = Instant::now();
+
+    while !f() {
+        assert!(
+            start.elapsed() < Duration::from_millis(TIMEOUT_MS),
+            "Timed out waiting for the daemon"
+        );
+        sleep(Duration::from_millis(10));
+    }
+}
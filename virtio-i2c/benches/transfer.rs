@@ -0,0 +1,113 @@
+// Throughput of the request handling, with and without copying the buffers
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::convert::TryFrom;
+use std::mem::size_of;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use virtio_queue::defs::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use virtio_queue::{mock::MockSplitQueue, Descriptor, DescriptorChain};
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestMemoryAtomic, GuestMemoryLoadGuard, GuestMemoryMmap,
+};
+
+use virtio_i2c::config::AdapterConfig;
+use virtio_i2c::i2c::mock::DummyDevice;
+use virtio_i2c::i2c::{I2cMap, I2cReq};
+use virtio_i2c::request::{
+    complete_request, parse_requests, parse_requests_zero_copy, VirtioI2cOutHdr,
+    VIRTIO_I2C_FLAGS_M_RD,
+};
+
+type I2cDescriptorChain = DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap<()>>>;
+
+// Lengths of the reads: a word, an SMBus block, and a chunk of an EEPROM.
+const SIZES: [u32; 3] = [2, 32, 128];
+
+// A register read as done while polling a sensor: a write of the register number, followed by
+// a read of `len` bytes. The descriptors are 4KiB apart in the guest memory.
+fn register_read(mem: &GuestMemoryMmap<()>, len: u32) -> Vec<I2cDescriptorChain> {
+    let vq = MockSplitQueue::new(mem, 16);
+    let hdr_len = size_of::<VirtioI2cOutHdr>() as u32;
+    let descs = [
+        (hdr_len, VIRTQ_DESC_F_NEXT),
+        (1, VIRTQ_DESC_F_NEXT),
+        (1, VIRTQ_DESC_F_WRITE),
+        (hdr_len, VIRTQ_DESC_F_NEXT),
+        (len, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT),
+        (1, VIRTQ_DESC_F_WRITE),
+    ];
+
+    for (i, (len, flags)) in descs.iter().enumerate() {
+        let addr = 0x1000 * (i as u64 + 1);
+        vq.desc_table()
+            .store(i as u16, Descriptor::new(addr, *len, *flags, i as u16 + 1));
+    }
+
+    for (i, flags) in [0, VIRTIO_I2C_FLAGS_M_RD].iter().enumerate() {
+        let out_hdr = VirtioI2cOutHdr {
+            addr: From::from(4 << 1),
+            padding: From::from(0),
+            flags: From::from(*flags),
+        };
+        mem.write_obj(out_hdr, GuestAddress(0x1000 + 0x3000 * i as u64))
+            .unwrap();
+    }
+
+    // The register number, as expected by the DummyDevice.
+    mem.write_obj(1u8, GuestAddress(0x2000)).unwrap();
+
+    // Put the descriptor indexes 0 and 3 in the available ring, and set `avail_idx` to 2.
+    mem.write_obj(0u16, vq.avail_addr().unchecked_add(4))
+        .unwrap();
+    mem.write_obj(3u16, vq.avail_addr().unchecked_add(6))
+        .unwrap();
+    mem.write_obj(2u16, vq.avail_addr().unchecked_add(2))
+        .unwrap();
+
+    vq.create_queue(GuestMemoryAtomic::<GuestMemoryMmap>::new(mem.clone()))
+        .iter()
+        .unwrap()
+        .collect()
+}
+
+fn transfer(i2c_map: &I2cMap<DummyDevice>, chains: &[I2cDescriptorChain], mut reqs: Vec<I2cReq>) {
+    let result = i2c_map.transfer(&mut reqs);
+
+    for (chain, req) in chains.iter().zip(reqs.iter()) {
+        complete_request(chain, req, &result).unwrap();
+    }
+}
+
+fn bench_transfer(c: &mut Criterion) {
+    let adapter_config = AdapterConfig::try_from("1:4").unwrap();
+    let i2c_map = I2cMap::<DummyDevice>::new(&adapter_config).unwrap();
+    let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let mut group = c.benchmark_group("register-read");
+
+    for len in SIZES.iter() {
+        let chains = register_read(&mem, *len);
+        group.throughput(Throughput::Bytes(*len as u64));
+
+        group.bench_with_input(BenchmarkId::new("copy", len), &chains, |b, chains| {
+            b.iter(|| transfer(&i2c_map, chains, parse_requests(chains).unwrap()))
+        });
+
+        group.bench_with_input(BenchmarkId::new("zero-copy", len), &chains, |b, chains| {
+            b.iter(|| {
+                // Safe as the chains outlive the messages.
+                let reqs = unsafe { parse_requests_zero_copy(chains) }.unwrap();
+                transfer(&i2c_map, chains, reqs)
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_transfer);
+criterion_main!(benches);
@@ -0,0 +1,10 @@
+#![no_main]
+
+use std::convert::TryFrom;
+
+use libfuzzer_sys::fuzz_target;
+use virtio_i2c::config::AdapterConfig;
+
+fuzz_target!(|list: &str| {
+    let _ = AdapterConfig::try_from(list);
+});
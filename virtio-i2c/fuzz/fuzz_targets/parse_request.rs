@@ -0,0 +1,63 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use virtio_i2c::request::{complete_request, fail_request, parse_request};
+use virtio_queue::defs::VIRTQ_DESC_F_NEXT;
+use virtio_queue::{mock::MockSplitQueue, Descriptor};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
+
+const MEM_SIZE: usize = 0x10000;
+const DATA_ADDR: u64 = 0x1000;
+
+// The input holds up to four descriptors of eight bytes each: their offset in the data area,
+// their length and their flags. The rest of the input is copied to the data area, so the
+// headers and buffers of the request are under the control of the fuzzer too.
+fuzz_target!(|data: &[u8]| {
+    let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+    let vq = MockSplitQueue::new(&mem, 16);
+
+    let count = data.first().map_or(0, |count| (*count % 4) as usize + 1);
+    let (descs, payload) = data
+        .get(1..)
+        .unwrap_or(&[])
+        .split_at((count * 8).min(data.len().saturating_sub(1)));
+
+    let last = (descs.len() / 8).saturating_sub(1);
+    for (i, desc) in descs.chunks_exact(8).enumerate() {
+        let offset = u16::from_le_bytes([desc[0], desc[1]]) as u64;
+        let len = u32::from_le_bytes([desc[2], desc[3], desc[4], desc[5]]);
+        let mut flags = u16::from_le_bytes([desc[6], desc[7]]) & !VIRTQ_DESC_F_NEXT;
+        if i < last {
+            flags |= VIRTQ_DESC_F_NEXT;
+        }
+
+        vq.desc_table().store(
+            i as u16,
+            Descriptor::new(DATA_ADDR + offset, len, flags, i as u16 + 1),
+        );
+    }
+
+    let payload = &payload[..payload.len().min(MEM_SIZE - DATA_ADDR as usize)];
+    mem.write_slice(payload, GuestAddress(DATA_ADDR)).unwrap();
+
+    // Put the descriptor index 0 in the first available ring position, and set `avail_idx` to 1.
+    mem.write_obj(0u16, vq.avail_addr().unchecked_add(4))
+        .unwrap();
+    mem.write_obj(1u16, vq.avail_addr().unchecked_add(2))
+        .unwrap();
+
+    let mut queue = vq.create_queue(GuestMemoryAtomic::<GuestMemoryMmap>::new(mem.clone()));
+    let desc_chain = match queue.iter().unwrap().next() {
+        Some(desc_chain) => desc_chain,
+        None => return,
+    };
+
+    match parse_request(&desc_chain) {
+        Ok(req) => {
+            let _ = complete_request(&desc_chain, &req, &Ok(()));
+        }
+        Err(_) => {
+            fail_request(&desc_chain);
+        }
+    }
+});
@@ -0,0 +1,34 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use virtio_i2c::i2c::{I2cReq, SmbusMsg};
+
+// Each request is described by three bytes: its flags, and the length of its buffer, which is
+// filled with the following bytes of the input.
+fuzz_target!(|data: &[u8]| {
+    let mut reqs = Vec::new();
+    let mut data = data;
+
+    while data.len() >= 3 {
+        let flags = data[0] as u16;
+        let len = u16::from_le_bytes([data[1], data[2]]);
+        data = &data[3..];
+
+        let mut buf = vec![0; len as usize];
+        let count = data.len().min(buf.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        data = &data[count..];
+
+        reqs.push(I2cReq {
+            addr: 0x20,
+            flags,
+            len,
+            buf: buf.into(),
+        });
+    }
+
+    if let Ok(msg) = SmbusMsg::new(&mut reqs) {
+        let _ = msg.is_read();
+        let _ = msg.data_len();
+    }
+});
@@ -0,0 +1,200 @@
+// Configuration of the adapters and of their clients
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::convert::TryFrom;
+use std::num::ParseIntError;
+
+use thiserror::Error as ThisError;
+
+use crate::i2c::MAX_I2C_VDEV;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, PartialEq, ThisError)]
+/// Errors related to the device list
+pub enum Error {
+    #[error("Duplicate adapter detected: {0}")]
+    AdapterDuplicate(u32),
+    #[error("Invalid client address: {0}")]
+    ClientAddressInvalid(u16),
+    #[error("Duplicate client address detected: {0}")]
+    ClientAddressDuplicate(u16),
+    #[error("Failed while parsing to integer: {0:?}")]
+    ParseFailure(ParseIntError),
+}
+
+/// An adapter, and the addresses of its clients.
+#[derive(Debug, PartialEq)]
+pub struct DeviceConfig {
+    pub(crate) adapter_no: u32,
+    pub(crate) addr: Vec<u16>,
+}
+
+impl DeviceConfig {
+    pub fn new(adapter_no: u32) -> Self {
+        DeviceConfig {
+            adapter_no,
+            addr: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, addr: u16) -> Result<()> {
+        if addr as usize > MAX_I2C_VDEV {
+            return Err(Error::ClientAddressInvalid(addr));
+        }
+
+        if self.addr.contains(&addr) {
+            return Err(Error::ClientAddressDuplicate(addr));
+        }
+
+        self.addr.push(addr);
+        Ok(())
+    }
+}
+
+/// The adapters passed through to the guests, parsed from a device list such as
+/// "1:4,2:32:21,5:5:23".
+#[derive(Debug, Default, PartialEq)]
+pub struct AdapterConfig {
+    pub(crate) inner: Vec<DeviceConfig>,
+}
+
+impl AdapterConfig {
+    pub fn new() -> Self {
+        Self { inner: Vec::new() }
+    }
+
+    pub fn contains_adapter_no(&self, adapter_no: u32) -> bool {
+        self.inner.iter().any(|elem| elem.adapter_no == adapter_no)
+    }
+
+    pub fn contains_addr(&self, addr: u16) -> bool {
+        self.inner.iter().any(|elem| elem.addr.contains(&addr))
+    }
+
+    pub fn adapter_of(&self, addr: u16) -> Option<u32> {
+        self.inner
+            .iter()
+            .find(|elem| elem.addr.contains(&addr))
+            .map(|elem| elem.adapter_no)
+    }
+
+    pub fn push(&mut self, device: DeviceConfig) -> Result<()> {
+        if self.contains_adapter_no(device.adapter_no) {
+            return Err(Error::AdapterDuplicate(device.adapter_no));
+        }
+
+        for addr in device.addr.iter() {
+            if self.contains_addr(*addr) {
+                return Err(Error::ClientAddressDuplicate(*addr));
+            }
+        }
+
+        self.inner.push(device);
+        Ok(())
+    }
+}
+
+impl TryFrom<&str> for AdapterConfig {
+    type Error = Error;
+
+    fn try_from(list: &str) -> Result<Self> {
+        let busses: Vec<&str> = list.split(',').collect();
+        let mut devices = AdapterConfig::new();
+
+        for businfo in busses.iter() {
+            let list: Vec<&str> = businfo.split(':').collect();
+            let bus_addr = list[0].parse::<u32>().map_err(Error::ParseFailure)?;
+            let mut adapter = DeviceConfig::new(bus_addr);
+
+            for device_str in list[1..].iter() {
+                let addr = device_str.parse::<u16>().map_err(Error::ParseFailure)?;
+                adapter.push(addr)?;
+            }
+
+            devices.push(adapter)?;
+        }
+        Ok(devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_config() {
+        let mut config = DeviceConfig::new(5);
+        let invalid_addr = (MAX_I2C_VDEV + 1) as u16;
+
+        config.push(5).unwrap();
+        config.push(6).unwrap();
+
+        assert_eq!(
+            config.push(invalid_addr).unwrap_err(),
+            Error::ClientAddressInvalid(invalid_addr)
+        );
+
+        assert_eq!(
+            config.push(5).unwrap_err(),
+            Error::ClientAddressDuplicate(5)
+        );
+    }
+
+    #[test]
+    fn test_adapter_config() {
+        let config = AdapterConfig::try_from("1:4,2:32:21,5:5:23").unwrap();
+
+        assert_eq!(
+            config.inner,
+            vec![
+                DeviceConfig {
+                    adapter_no: 1,
+                    addr: vec![4]
+                },
+                DeviceConfig {
+                    adapter_no: 2,
+                    addr: vec![32, 21]
+                },
+                DeviceConfig {
+                    adapter_no: 5,
+                    addr: vec![5, 23]
+                },
+            ]
+        );
+        assert!(config.contains_adapter_no(2));
+        assert!(!config.contains_adapter_no(3));
+        assert!(config.contains_addr(21));
+        assert_eq!(config.adapter_of(23), Some(5));
+        assert_eq!(config.adapter_of(6), None);
+
+        assert_eq!(
+            AdapterConfig::try_from("1:4,3d:5").unwrap_err(),
+            Error::ParseFailure("3d".parse::<u32>().unwrap_err())
+        );
+        assert_eq!(
+            AdapterConfig::try_from("1:4d").unwrap_err(),
+            Error::ParseFailure("4d".parse::<u16>().unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_i2c_map_duplicate_device4() {
+        assert_eq!(
+            AdapterConfig::try_from("1:4,2:32:21,5:4:23").unwrap_err(),
+            Error::ClientAddressDuplicate(4)
+        );
+    }
+
+    #[test]
+    fn test_duplicated_adapter_no() {
+        assert_eq!(
+            AdapterConfig::try_from("1:4,1:32:21,5:10:23").unwrap_err(),
+            Error::AdapterDuplicate(1)
+        );
+    }
+}
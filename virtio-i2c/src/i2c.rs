@@ -0,0 +1,2602 @@
+// Low level I2C definitions
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use libc::{c_ulong, ioctl};
+use thiserror::Error as ThisError;
+use vm_memory::VolatileSlice;
+use vmm_sys_util::errno::Error as IoError;
+
+use crate::config::AdapterConfig;
+use crate::policy::{Access, Policy};
+use crate::quirks::Quirks;
+use crate::ratelimit::RateLimiter;
+
+// The type of the `req` parameter is different for the `musl` library. This will enable
+// successful build for other non-musl libraries.
+#[cfg(target_env = "musl")]
+type IoctlRequest = libc::c_int;
+#[cfg(not(target_env = "musl"))]
+type IoctlRequest = c_ulong;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Copy, Clone, Debug, PartialEq, ThisError)]
+/// Errors related to low level i2c helpers
+pub enum Error {
+    #[error("Incorrect message length for {0} operation: {1}")]
+    MessageLengthInvalid(&'static str, usize),
+    #[error("Invalid SMBUS command: {0}")]
+    SMBusCommandInvalid(u32),
+    #[error("Invalid SMBus transfer, request-count: {0}, req[0].len: {1}, req[1].len: {2}")]
+    SMBusTransferInvalid(usize, u16, u16),
+    #[error("Invalid I2C transfer, request with invalid count: {0}")]
+    I2cTransferInvalid(usize),
+    #[error("Failed to open adapter at /dev/i2c-{0}")]
+    DeviceOpenFailed(u32),
+    #[error("Ioctl command failed for {0} operation: {1}")]
+    IoctlFailure(&'static str, IoError),
+    #[error("Invalid Adapter function: {0:x}")]
+    AdapterFunctionInvalid(u64),
+    #[error("Invalid Client Address")]
+    ClientAddressInvalid,
+    #[error("Client address not found: {0}")]
+    ClientAddressNotFound(u16),
+    #[error("Duplicate client address detected: {0}")]
+    ClientAddressDuplicate(u16),
+    #[error("Client disabled: {0}")]
+    ClientDisabled(u16),
+    #[error("Adapter not found: {0}")]
+    AdapterNotFound(u32),
+    #[error("Transfer denied by policy: {0}")]
+    TransferDenied(u16),
+    #[error("Transfer rejected by rate limit")]
+    RateLimited,
+    #[error("Adapter unavailable: {0}")]
+    AdapterUnavailable(u32),
+    #[error("Adapter {0} reopened with different functionality: {1:x}")]
+    AdapterFunctionChanged(u32, u64),
+    #[error("Message of {0} bytes over the limit of the adapter: {1}")]
+    MessageTooLong(u16, u16),
+    #[error("Transaction of {0} messages over the limit of the adapter: {1}")]
+    TransactionTooLong(usize, usize),
+    #[error("Transaction of {0} messages, without repeated START support from the adapter")]
+    CombinedTransferUnsupported(usize),
+    #[error("Register block past the last register, from {0}: {1} bytes")]
+    RegisterBlockInvalid(u8, usize),
+    #[error("Invalid page size, not a power of two up to 256: {0}")]
+    PageSizeInvalid(usize),
+}
+
+impl Error {
+    /// Returns the name of the error variant, used to classify failures.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Error::MessageLengthInvalid(..) => "MessageLengthInvalid",
+            Error::SMBusCommandInvalid(..) => "SMBusCommandInvalid",
+            Error::SMBusTransferInvalid(..) => "SMBusTransferInvalid",
+            Error::I2cTransferInvalid(..) => "I2cTransferInvalid",
+            Error::DeviceOpenFailed(..) => "DeviceOpenFailed",
+            Error::IoctlFailure(..) => "IoctlFailure",
+            Error::AdapterFunctionInvalid(..) => "AdapterFunctionInvalid",
+            Error::ClientAddressInvalid => "ClientAddressInvalid",
+            Error::ClientAddressNotFound(..) => "ClientAddressNotFound",
+            Error::ClientAddressDuplicate(..) => "ClientAddressDuplicate",
+            Error::ClientDisabled(..) => "ClientDisabled",
+            Error::AdapterNotFound(..) => "AdapterNotFound",
+            Error::TransferDenied(..) => "TransferDenied",
+            Error::RateLimited => "RateLimited",
+            Error::AdapterUnavailable(..) => "AdapterUnavailable",
+            Error::AdapterFunctionChanged(..) => "AdapterFunctionChanged",
+            Error::MessageTooLong(..) => "MessageTooLong",
+            Error::TransactionTooLong(..) => "TransactionTooLong",
+            Error::CombinedTransferUnsupported(..) => "CombinedTransferUnsupported",
+            Error::RegisterBlockInvalid(..) => "RegisterBlockInvalid",
+            Error::PageSizeInvalid(..) => "PageSizeInvalid",
+        }
+    }
+}
+
+// Linux I2C/SMBUS definitions
+// IOCTL commands, refer Linux's Documentation/i2c/dev-interface.rst for further details.
+
+/// NOTE: Slave address is 7 or 10 bits, but 10-bit addresses are NOT supported!
+/// (due to code brokenness)
+pub const I2C_SLAVE: IoctlRequest = 0x0703; // Use this slave address
+pub const I2C_FUNCS: IoctlRequest = 0x0705; // Get the adapter functionality mask
+pub const I2C_RDWR: IoctlRequest = 0x0707; // Combined R/W transfer (one STOP only)
+pub const I2C_SMBUS: IoctlRequest = 0x0720; // SMBus transfer
+
+/// Functions, as reported by I2C_FUNCS, refer Linux's include/uapi/linux/i2c.h
+pub const I2C_FUNC_I2C: u64 = 0x00000001;
+pub const I2C_FUNC_10BIT_ADDR: u64 = 0x00000002;
+pub const I2C_FUNC_PROTOCOL_MANGLING: u64 = 0x00000004;
+pub const I2C_FUNC_SMBUS_PEC: u64 = 0x00000008;
+pub const I2C_FUNC_NOSTART: u64 = 0x00000010;
+pub const I2C_FUNC_SLAVE: u64 = 0x00000020;
+pub const I2C_FUNC_SMBUS_BLOCK_PROC_CALL: u64 = 0x00008000;
+pub const I2C_FUNC_SMBUS_QUICK: u64 = 0x00010000;
+pub const I2C_FUNC_SMBUS_READ_BYTE: u64 = 0x00020000;
+pub const I2C_FUNC_SMBUS_WRITE_BYTE: u64 = 0x00040000;
+pub const I2C_FUNC_SMBUS_READ_BYTE_DATA: u64 = 0x00080000;
+pub const I2C_FUNC_SMBUS_WRITE_BYTE_DATA: u64 = 0x00100000;
+pub const I2C_FUNC_SMBUS_READ_WORD_DATA: u64 = 0x00200000;
+pub const I2C_FUNC_SMBUS_WRITE_WORD_DATA: u64 = 0x00400000;
+pub const I2C_FUNC_SMBUS_PROC_CALL: u64 = 0x00800000;
+pub const I2C_FUNC_SMBUS_READ_BLOCK_DATA: u64 = 0x01000000;
+pub const I2C_FUNC_SMBUS_WRITE_BLOCK_DATA: u64 = 0x02000000;
+pub const I2C_FUNC_SMBUS_READ_I2C_BLOCK: u64 = 0x04000000;
+pub const I2C_FUNC_SMBUS_WRITE_I2C_BLOCK: u64 = 0x08000000;
+pub const I2C_FUNC_SMBUS_HOST_NOTIFY: u64 = 0x10000000;
+
+pub const I2C_FUNC_SMBUS_BYTE: u64 = I2C_FUNC_SMBUS_READ_BYTE | I2C_FUNC_SMBUS_WRITE_BYTE;
+pub const I2C_FUNC_SMBUS_BYTE_DATA: u64 =
+    I2C_FUNC_SMBUS_READ_BYTE_DATA | I2C_FUNC_SMBUS_WRITE_BYTE_DATA;
+pub const I2C_FUNC_SMBUS_WORD_DATA: u64 =
+    I2C_FUNC_SMBUS_READ_WORD_DATA | I2C_FUNC_SMBUS_WRITE_WORD_DATA;
+/// Functions required by the SMBus only adapters, the I2C block transfers are optional.
+pub const I2C_FUNC_SMBUS_ALL: u64 =
+    I2C_FUNC_SMBUS_BYTE | I2C_FUNC_SMBUS_BYTE_DATA | I2C_FUNC_SMBUS_WORD_DATA;
+
+/// I2C protocol definitions
+pub const I2C_M_RD: u16 = 0x0001; // read data, from slave to master
+
+/// Copied (partially) from Linux's include/uapi/linux/i2c.h
+///
+/// I2cMsg - an I2C transaction segment beginning with START
+///
+/// @addr: Slave address, only 7 bit supported by virtio specification.
+///
+/// @flags:
+///   Supported by all adapters:
+///   %I2C_M_RD: read data (from slave to master). Guaranteed to be 0x0001!
+///
+///   Optional:
+///   These aren't supported by virtio specification yet.
+///
+/// @len: Number of data bytes in @buf being read from or written to the I2C
+///   slave address.
+///
+/// @buf: The buffer into which data is read, or from which it's written.
+///
+/// An I2cMsg is the low level representation of one segment of an I2C
+/// transaction.
+///
+/// Each transaction begins with a START.  That is followed by the slave
+/// address, and a bit encoding read versus write.  Then follow all the
+/// data bytes, possibly including a byte with SMBus PEC.  The transfer
+/// terminates with a NAK, or when all those bytes have been transferred
+/// and ACKed.  If this is the last message in a group, it is followed by
+/// a STOP.  Otherwise it is followed by the next @I2cMsg transaction
+/// segment, beginning with a (repeated) START.
+#[repr(C)]
+struct I2cMsg {
+    addr: u16,
+    flags: u16,
+    len: u16,
+    buf: *mut u8,
+}
+
+/// This is the structure as used in the I2C_RDWR ioctl call
+#[repr(C)]
+pub struct I2cRdwrIoctlData {
+    msgs: *mut I2cMsg,
+    nmsgs: u32,
+}
+
+/// SMBUS protocol definitions
+/// SMBUS read or write markers
+const I2C_SMBUS_WRITE: u8 = 0;
+const I2C_SMBUS_READ: u8 = 1;
+
+/// SMBus transaction types (size parameter in the above functions)
+const I2C_SMBUS_QUICK: u32 = 0;
+const I2C_SMBUS_BYTE: u32 = 1;
+const I2C_SMBUS_BYTE_DATA: u32 = 2;
+const I2C_SMBUS_WORD_DATA: u32 = 3;
+const I2C_SMBUS_I2C_BLOCK_DATA: u32 = 8;
+
+/// As specified in SMBus standard
+const I2C_SMBUS_BLOCK_MAX: usize = 32;
+
+/// Longest write cycle of the clients, the default write timeout of Linux's at24 driver, and
+/// the interval between the attempts to write to a client still busy with it.
+const WRITE_CYCLE_TIMEOUT: Duration = Duration::from_millis(25);
+const WRITE_CYCLE_POLL: Duration = Duration::from_millis(1);
+
+#[repr(C)]
+union I2cSmbusData {
+    byte: u8,
+    word: u16,
+
+    /// block[0] is used for length, and one more for user-space compatibility
+    block: [u8; I2C_SMBUS_BLOCK_MAX + 2],
+}
+
+impl I2cSmbusData {
+    fn read_byte(&self) -> u8 {
+        // Safe as we will only read the relevant bytes
+        unsafe { self.byte }
+    }
+
+    fn read_word(&self) -> u16 {
+        // Safe as we will only read the relevant bytes
+        unsafe { self.word }
+    }
+
+    fn block(&self) -> &[u8; I2C_SMBUS_BLOCK_MAX + 2] {
+        // Safe as the block array overlaps all the other fields of the union.
+        unsafe { &self.block }
+    }
+
+    fn block_mut(&mut self) -> &mut [u8; I2C_SMBUS_BLOCK_MAX + 2] {
+        // Safe as the block array overlaps all the other fields of the union.
+        unsafe { &mut self.block }
+    }
+}
+
+/// This is the structure as used in the I2C_SMBUS ioctl call
+#[repr(C)]
+pub struct I2cSmbusIoctlData {
+    read_write: u8,
+    command: u8,
+    size: u32,
+    data: *mut I2cSmbusData,
+}
+
+pub struct SmbusMsg {
+    read_write: u8,
+    command: u8,
+    size: u32,
+    data: Option<I2cSmbusData>,
+}
+
+impl SmbusMsg {
+    /// Based on Linux's drivers/i2c/i2c-core-smbus.c:i2c_smbus_xfer_emulated().
+    ///
+    /// These smbus related functions try to reverse what Linux does, only
+    /// support basic modes (up to word transfer).
+    pub fn new(reqs: &mut [I2cReq]) -> Result<SmbusMsg> {
+        let mut data = I2cSmbusData {
+            block: [0; I2C_SMBUS_BLOCK_MAX + 2],
+        };
+
+        // Write messages have only one request message, while read messages
+        // will have two (except for few special cases of I2C_SMBUS_QUICK and
+        // I2C_SMBUS_BYTE, where only one request messages is sent).
+        match reqs.len() {
+            // Write requests (with some exceptions as mentioned above)
+            1 => {
+                let read_write = match reqs[0].flags & I2C_M_RD {
+                    0 => I2C_SMBUS_WRITE,
+                    _ => I2C_SMBUS_READ,
+                };
+
+                match reqs[0].len {
+                    // Special Read requests
+                    0 => Ok(SmbusMsg {
+                        read_write,
+                        command: 0,
+                        size: I2C_SMBUS_QUICK,
+                        data: None,
+                    }),
+
+                    1 => Ok(SmbusMsg {
+                        read_write,
+                        command: reqs[0].buf.read_byte(0),
+                        size: I2C_SMBUS_BYTE,
+                        data: Some(data),
+                    }),
+
+                    // Write requests
+                    2 => {
+                        if read_write == I2C_SMBUS_READ {
+                            // Special Read requests, reqs[0].len can be 0 or 1 only.
+                            Err(Error::MessageLengthInvalid("read", 2))
+                        } else {
+                            data.byte = reqs[0].buf.read_byte(1);
+                            Ok(SmbusMsg {
+                                read_write,
+                                command: reqs[0].buf.read_byte(0),
+                                size: I2C_SMBUS_BYTE_DATA,
+                                data: Some(data),
+                            })
+                        }
+                    }
+
+                    3 => {
+                        if read_write == I2C_SMBUS_READ {
+                            // Special Read requests, reqs[0].len can be 0 or 1 only.
+                            Err(Error::MessageLengthInvalid("read", 3))
+                        } else {
+                            data.word = reqs[0].buf.read_byte(1) as u16
+                                | ((reqs[0].buf.read_byte(2) as u16) << 8);
+                            Ok(SmbusMsg {
+                                read_write,
+                                command: reqs[0].buf.read_byte(0),
+                                size: I2C_SMBUS_WORD_DATA,
+                                data: Some(data),
+                            })
+                        }
+                    }
+                    _ => Err(Error::MessageLengthInvalid("write", reqs[0].len as usize)),
+                }
+            }
+
+            // Read requests
+            2 => {
+                // The first request contains the command, so its length must be
+                // set to 1 and shouldn't have I2C_M_RD set in flags.
+                //
+                // The second request contains the read buffer, so must have its
+                // I2C_M_RD flag set. We don't support block transfers yet and
+                // so its length must be 1 or 2.
+                if ((reqs[0].flags & I2C_M_RD) != 0)
+                    || ((reqs[1].flags & I2C_M_RD) == 0)
+                    || (reqs[0].len != 1)
+                    || (reqs[1].len == 0)
+                    || (reqs[1].len > 2)
+                {
+                    Err(Error::SMBusTransferInvalid(
+                        reqs.len(),
+                        reqs[0].len,
+                        reqs[1].len,
+                    ))
+                } else {
+                    Ok(SmbusMsg {
+                        read_write: I2C_SMBUS_READ,
+                        command: reqs[0].buf.read_byte(0),
+                        size: if reqs[1].len == 1 {
+                            I2C_SMBUS_BYTE_DATA
+                        } else {
+                            I2C_SMBUS_WORD_DATA
+                        },
+                        data: Some(data),
+                    })
+                }
+            }
+
+            // The guest controls the number of requests, which can be 0 or more than 2 here.
+            _ => Err(Error::SMBusTransferInvalid(
+                reqs.len(),
+                reqs.first().map_or(0, |req| req.len),
+                reqs.get(1).map_or(0, |req| req.len),
+            )),
+        }
+    }
+
+    /// Returns true if the message reads data from the device.
+    pub fn is_read(&self) -> bool {
+        self.read_write == I2C_SMBUS_READ
+    }
+
+    /// Returns the number of data bytes transferred by the message.
+    pub fn data_len(&self) -> usize {
+        match self.size {
+            I2C_SMBUS_BYTE | I2C_SMBUS_BYTE_DATA => 1,
+            I2C_SMBUS_WORD_DATA => 2,
+            I2C_SMBUS_I2C_BLOCK_DATA => {
+                self.data.as_ref().map_or(0, |data| data.block()[0]) as usize
+            }
+            _ => 0,
+        }
+    }
+
+    /// Flips a single bit of the message's data, `bit` must be smaller than
+    /// `data_len() * 8`.
+    pub fn flip_data_bit(&mut self, bit: usize) {
+        // The data of the blocks follows their length.
+        let offset = match self.size {
+            I2C_SMBUS_I2C_BLOCK_DATA => 1,
+            _ => 0,
+        };
+
+        if let Some(data) = &mut self.data {
+            data.block_mut()[offset + bit / 8] ^= 1 << (bit % 8);
+        }
+    }
+}
+
+/// Returns the read or write marker, the register, and the buffer holding the data from the
+/// given offset, of a transfer accessing a block of registers too long for the basic SMBus modes:
+/// the write of the register number followed by the read of more than a word, or the write of the
+/// register number and of more than a word.
+fn register_block(reqs: &mut [I2cReq]) -> Option<(u8, u8, &mut I2cBuf, usize)> {
+    match reqs {
+        [write, read]
+            if write.flags & I2C_M_RD == 0
+                && write.len == 1
+                && read.flags & I2C_M_RD != 0
+                && read.len > 2 =>
+        {
+            Some((I2C_SMBUS_READ, write.buf.read_byte(0), &mut read.buf, 0))
+        }
+        [write] if write.flags & I2C_M_RD == 0 && write.len > 3 => {
+            let register = write.buf.read_byte(0);
+            Some((I2C_SMBUS_WRITE, register, &mut write.buf, 1))
+        }
+        _ => None,
+    }
+}
+
+/// I2C definitions
+pub struct I2cReq {
+    pub addr: u16,
+    pub flags: u16,
+    pub len: u16,
+    pub buf: I2cBuf,
+}
+
+enum BufInner {
+    Owned(Vec<u8>),
+    Guest(*mut u8, usize),
+}
+
+/// The data buffer of an I2C message. It either owns its memory, or points straight at the
+/// buffer of the request in the guest memory, so the RDWR transfers don't need to copy the data
+/// to and from the guest. The guest memory is never exposed as a Rust slice, as the guest may
+/// access it concurrently: it's only reached through a raw pointer, or volatile copies.
+pub struct I2cBuf {
+    inner: BufInner,
+}
+
+impl I2cBuf {
+    /// A zeroed buffer of `len` bytes.
+    pub fn new(len: usize) -> Self {
+        vec![0; len].into()
+    }
+
+    /// Wraps `len` bytes of guest memory, mapped at `ptr`, without copying them.
+    ///
+    /// # Safety
+    ///
+    /// The memory must stay mapped for as long as the buffer is alive. Only meant for the
+    /// buffers of read messages, which the guest may still update under our feet: the data
+    /// there is never checked, it's overwritten by the transfer.
+    pub unsafe fn from_guest(ptr: *mut u8, len: usize) -> Self {
+        I2cBuf {
+            inner: BufInner::Guest(ptr, len),
+        }
+    }
+
+    /// Whether the buffer points at the guest memory, which then already holds the data read.
+    pub fn is_guest(&self) -> bool {
+        matches!(self.inner, BufInner::Guest(..))
+    }
+
+    /// Raw pointer to the data, for the transfers.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        match &mut self.inner {
+            BufInner::Owned(buf) => buf.as_mut_ptr(),
+            BufInner::Guest(ptr, _) => *ptr,
+        }
+    }
+
+    /// Length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        match &self.inner {
+            BufInner::Owned(buf) => buf.len(),
+            BufInner::Guest(_, len) => *len,
+        }
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the byte at `index`, panics if it's out of bounds.
+    pub fn read_byte(&self, index: usize) -> u8 {
+        match &self.inner {
+            BufInner::Owned(buf) => buf[index],
+            BufInner::Guest(ptr, len) => {
+                assert!(index < *len, "index {} out of bounds of {}", index, len);
+                // Safe as the index was checked against the length of the guest
+                // memory, which is mapped for as long as the buffer is alive.
+                unsafe { ptr::read_volatile(ptr.add(index)) }
+            }
+        }
+    }
+
+    /// Writes `byte` at `index`, panics if it's out of bounds.
+    pub fn write_byte(&mut self, index: usize, byte: u8) {
+        match &mut self.inner {
+            BufInner::Owned(buf) => buf[index] = byte,
+            BufInner::Guest(ptr, len) => {
+                assert!(index < *len, "index {} out of bounds of {}", index, len);
+                // Safe as the index was checked against the length of the guest
+                // memory, which is mapped for as long as the buffer is alive.
+                unsafe { ptr::write_volatile(ptr.add(index), byte) }
+            }
+        }
+    }
+
+    /// Copies the bytes starting at `offset` to `data`, panics if they are out of bounds.
+    pub fn read_slice(&self, offset: usize, data: &mut [u8]) {
+        let end = offset + data.len();
+
+        match &self.inner {
+            BufInner::Owned(buf) => data.copy_from_slice(&buf[offset..end]),
+            BufInner::Guest(ptr, len) => {
+                assert!(
+                    end <= *len,
+                    "range {}..{} out of bounds of {}",
+                    offset,
+                    end,
+                    len
+                );
+                // Safe as the range was checked against the length of the guest
+                // memory, which is mapped for as long as the buffer is alive. The guest may
+                // access it concurrently, the copy is volatile.
+                unsafe { VolatileSlice::new(ptr.add(offset), data.len()) }.copy_to(data);
+            }
+        }
+    }
+
+    /// Copies `data` to the bytes starting at `offset`, panics if they are out of bounds.
+    pub fn write_slice(&mut self, offset: usize, data: &[u8]) {
+        let end = offset + data.len();
+
+        match &mut self.inner {
+            BufInner::Owned(buf) => buf[offset..end].copy_from_slice(data),
+            BufInner::Guest(ptr, len) => {
+                assert!(
+                    end <= *len,
+                    "range {}..{} out of bounds of {}",
+                    offset,
+                    end,
+                    len
+                );
+                // Safe as the range was checked against the length of the guest
+                // memory, which is mapped for as long as the buffer is alive. The guest may
+                // access it concurrently, the copy is volatile.
+                unsafe { VolatileSlice::new(ptr.add(offset), data.len()) }.copy_from(data);
+            }
+        }
+    }
+
+    /// Copies the whole buffer.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut data = vec![0; self.len()];
+        self.read_slice(0, &mut data);
+        data
+    }
+}
+
+impl From<Vec<u8>> for I2cBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        I2cBuf {
+            inner: BufInner::Owned(buf),
+        }
+    }
+}
+
+impl fmt::Debug for I2cBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_vec().fmt(f)
+    }
+}
+
+impl PartialEq<Vec<u8>> for I2cBuf {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.to_vec() == *other
+    }
+}
+
+/// Trait that represents an I2C Device, i.e. a host adapter, with the operations of the Linux
+/// i2c-dev interface.
+///
+/// [`PhysDevice`] implements it for the /dev/i2c-N nodes. Other implementations can emulate an
+/// adapter or wrap one, e.g. to inject faults, and are driven by [`I2cMap`] the same way:
+/// `funcs()` is called once the device is opened, and the transfers only use the operations it
+/// reported, `rdwr()` if I2C_FUNC_I2C is set, `slave()` and `smbus()` otherwise. A device is
+/// shared by the guests of all the sockets, the calls may come from several threads at once.
+/// Failures are reported to the guest as VIRTIO_I2C_MSG_ERR, and don't affect the other
+/// transfers.
+pub trait I2cDevice {
+    /// Opens the device at `device_path`, for the adapter number.
+    fn open(device_path: &str, adapter_no: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Takes over the device already opened for the adapter number, e.g. by a privileged helper.
+    fn from_file(file: File, adapter_no: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Returns the functionality of the adapter, the I2C_FUNC_* bits, as the I2C_FUNCS ioctl.
+    fn funcs(&mut self) -> Result<u64>;
+
+    /// Transfers the messages in a single combined transaction, as the I2C_RDWR ioctl. The
+    /// buffers of the read messages are filled with the data read.
+    fn rdwr(&self, reqs: &mut [I2cReq]) -> Result<()>;
+
+    /// Runs a single SMBus transaction, as the I2C_SMBUS ioctl. The data read is stored in
+    /// `msg`.
+    fn smbus(&self, msg: &mut SmbusMsg) -> Result<()>;
+
+    /// Selects the client addressed by the next SMBus transactions, as the I2C_SLAVE ioctl.
+    fn slave(&self, addr: u64) -> Result<()>;
+
+    /// Returns the adapter number corresponding to this device.
+    fn adapter_no(&self) -> u32;
+}
+
+/// A physical I2C device. This structure can only be initialized on hosts
+/// where `/dev/i2c-XX` is available.
+#[derive(Debug)]
+pub struct PhysDevice {
+    file: File,
+    adapter_no: u32,
+}
+
+impl AsRawFd for PhysDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl I2cDevice for PhysDevice {
+    fn open(device_path: &str, adapter_no: u32) -> Result<Self> {
+        Ok(PhysDevice {
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(device_path)
+                .map_err(|_| Error::DeviceOpenFailed(adapter_no))?,
+            adapter_no,
+        })
+    }
+
+    fn from_file(file: File, adapter_no: u32) -> Result<Self> {
+        Ok(PhysDevice { file, adapter_no })
+    }
+
+    fn funcs(&mut self) -> Result<u64> {
+        let mut func: u64 = 0;
+
+        // Safe as the file is a valid I2C adapter, the kernel will only
+        // update the correct amount of memory in func.
+        let ret = unsafe { ioctl(self.file.as_raw_fd(), I2C_FUNCS, &mut func) };
+
+        if ret == -1 {
+            Err(Error::IoctlFailure("funcs", IoError::last()))
+        } else {
+            Ok(func)
+        }
+    }
+
+    fn rdwr(&self, reqs: &mut [I2cReq]) -> Result<()> {
+        let mut msgs: Vec<I2cMsg> = Vec::with_capacity(reqs.len());
+        let len = reqs.len();
+
+        for req in reqs {
+            if req.len == 0 {
+                return Err(Error::I2cTransferInvalid(0));
+            }
+
+            msgs.push(I2cMsg {
+                addr: req.addr,
+                flags: req.flags,
+                len: req.len,
+                buf: req.buf.as_mut_ptr(),
+            });
+        }
+
+        let mut data = I2cRdwrIoctlData {
+            msgs: msgs.as_mut_ptr(),
+            nmsgs: len as u32,
+        };
+
+        // Safe as the file is a valid I2C adapter, the kernel will only
+        // update the correct amount of memory in data.
+        let ret = unsafe { ioctl(self.file.as_raw_fd(), I2C_RDWR, &mut data) };
+
+        if ret == -1 {
+            Err(Error::IoctlFailure("rdwr", IoError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn smbus(&self, msg: &mut SmbusMsg) -> Result<()> {
+        let mut smbus_data = I2cSmbusIoctlData {
+            read_write: msg.read_write,
+            command: msg.command,
+            size: msg.size,
+            data: match &mut msg.data {
+                Some(data) => data,
+                _ => std::ptr::null_mut(),
+            },
+        };
+
+        // Safe as the file is a valid I2C adapter, the kernel will only
+        // update the correct amount of memory in data.
+        let ret = unsafe { ioctl(self.file.as_raw_fd(), I2C_SMBUS, &mut smbus_data) };
+
+        if ret == -1 {
+            Err(Error::IoctlFailure("smbus", IoError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn slave(&self, addr: u64) -> Result<()> {
+        // Safe as the file is a valid I2C adapter.
+        let ret = unsafe { ioctl(self.file.as_raw_fd(), I2C_SLAVE, addr as c_ulong) };
+
+        if ret == -1 {
+            Err(Error::IoctlFailure("slave", IoError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn adapter_no(&self) -> u32 {
+        self.adapter_no
+    }
+}
+
+#[derive(Debug)]
+pub struct I2cAdapter<D: I2cDevice> {
+    // Gone while the adapter is absent, e.g. unplugged, until it's reopened.
+    device: RwLock<Option<D>>,
+    adapter_no: u32,
+    smbus: bool,
+    // Shared by the guests, limits the transfers on the bus.
+    limiter: Option<Arc<RateLimiter>>,
+    // Limits of the RDWR transfers, the batches of messages are split to fit them.
+    quirks: Quirks,
+    // Functionality of the adapter, as reported by I2C_FUNCS.
+    funcs: u64,
+}
+
+impl<D: I2cDevice> I2cAdapter<D> {
+    // Creates a new adapter corresponding to `device`.
+    fn new(mut device: D) -> Result<I2cAdapter<D>> {
+        let smbus = Self::is_smbus_only(&mut device)?;
+
+        Ok(I2cAdapter {
+            adapter_no: device.adapter_no(),
+            funcs: device.funcs()?,
+            device: RwLock::new(Some(device)),
+            smbus,
+            limiter: None,
+            quirks: Quirks::default(),
+        })
+    }
+
+    // Checks the functionality of `device`, and returns whether it only supports SMBus.
+    fn is_smbus_only(device: &mut D) -> Result<bool> {
+        let func = device.funcs()?;
+
+        if (func & I2C_FUNC_I2C) != 0 {
+            Ok(false)
+        } else if (func & I2C_FUNC_SMBUS_ALL) != 0 {
+            Ok(true)
+        } else {
+            Err(Error::AdapterFunctionInvalid(func))
+        }
+    }
+
+    // Runs `f` with the device, which fails while the adapter is absent.
+    fn with_device<T, F: FnOnce(&D) -> Result<T>>(&self, f: F) -> Result<T> {
+        match self.device.read().unwrap().as_ref() {
+            Some(device) => f(device),
+            None => Err(Error::AdapterUnavailable(self.adapter_no)),
+        }
+    }
+
+    /// Perform I2C_RDWR transfers, as many as needed to fit the limits of the adapter.
+    fn i2c_transfer(&self, reqs: &mut [I2cReq]) -> Result<()> {
+        for transfer in self.quirks.split(reqs)? {
+            self.with_device(|device| device.rdwr(&mut reqs[transfer]))?;
+        }
+        Ok(())
+    }
+
+    /// Perform I2C_SMBUS transfer
+    fn smbus_transfer(
+        &self,
+        reqs: &mut [I2cReq],
+        auto_increment: bool,
+        page_size: Option<usize>,
+    ) -> Result<()> {
+        if auto_increment {
+            if let Some((read_write, register, buf, offset)) = register_block(reqs) {
+                // The writes are only emulated when the pages of the client are known.
+                if read_write == I2C_SMBUS_READ || page_size.is_some() {
+                    return self.smbus_block_transfer(read_write, register, buf, offset, page_size);
+                }
+            }
+        }
+
+        let mut msg = SmbusMsg::new(reqs)?;
+        self.with_device(|device| device.smbus(&mut msg))?;
+
+        if msg.read_write == I2C_SMBUS_READ {
+            match msg.size {
+                I2C_SMBUS_QUICK => {}
+                I2C_SMBUS_BYTE => reqs[0].buf.write_byte(0, msg.data.unwrap().read_byte()),
+                I2C_SMBUS_BYTE_DATA => reqs[1].buf.write_byte(0, msg.data.unwrap().read_byte()),
+                I2C_SMBUS_WORD_DATA => {
+                    let word = msg.data.unwrap().read_word();
+
+                    reqs[1].buf.write_byte(0, (word & 0xff) as u8);
+                    reqs[1].buf.write_byte(1, (word >> 8) as u8);
+                }
+
+                _ => {
+                    return Err(Error::SMBusCommandInvalid(msg.size));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Emulates the access to a block of registers, too long for a single SMBus transfer, with
+    /// a sequence of I2C block transfers, or of byte data ones if the adapter doesn't support
+    /// the former. Each of them starts at the register following the last one of the previous
+    /// transfer, which is only valid for clients auto-incrementing the register address, e.g.
+    /// EEPROMs. A failure may leave the first registers of the block written.
+    ///
+    /// The writes don't cross the boundaries of the pages of `page_size` bytes, where the
+    /// address of EEPROMs wraps around, and are retried for up to `WRITE_CYCLE_TIMEOUT` while
+    /// the client doesn't acknowledge them, busy programming the previous one.
+    fn smbus_block_transfer(
+        &self,
+        read_write: u8,
+        register: u8,
+        buf: &mut I2cBuf,
+        offset: usize,
+        page_size: Option<usize>,
+    ) -> Result<()> {
+        let len = buf.len() - offset;
+
+        let (name, block_func, byte_func) = match read_write {
+            I2C_SMBUS_READ => (
+                "read",
+                I2C_FUNC_SMBUS_READ_I2C_BLOCK,
+                I2C_FUNC_SMBUS_READ_BYTE_DATA,
+            ),
+            _ => (
+                "write",
+                I2C_FUNC_SMBUS_WRITE_I2C_BLOCK,
+                I2C_FUNC_SMBUS_WRITE_BYTE_DATA,
+            ),
+        };
+
+        let (size, chunk_len) = if self.funcs & block_func != 0 {
+            (I2C_SMBUS_I2C_BLOCK_DATA, I2C_SMBUS_BLOCK_MAX)
+        } else if self.funcs & byte_func != 0 {
+            (I2C_SMBUS_BYTE_DATA, 1)
+        } else {
+            return Err(Error::MessageLengthInvalid(name, len));
+        };
+
+        // The register address is 8 bits wide.
+        if register as usize + len > u8::MAX as usize + 1 {
+            return Err(Error::RegisterBlockInvalid(register, len));
+        }
+
+        let mut start = 0;
+        while start < len {
+            let command = register + start as u8;
+            let mut count = chunk_len.min(len - start);
+            if let (I2C_SMBUS_WRITE, Some(page_size)) = (read_write, page_size) {
+                count = count.min(page_size - command as usize % page_size);
+            }
+
+            let mut data = I2cSmbusData {
+                block: [0; I2C_SMBUS_BLOCK_MAX + 2],
+            };
+
+            // The length of the block is followed by its data.
+            if size == I2C_SMBUS_I2C_BLOCK_DATA {
+                data.block_mut()[0] = count as u8;
+                if read_write == I2C_SMBUS_WRITE {
+                    buf.read_slice(offset + start, &mut data.block_mut()[1..=count]);
+                }
+            } else if read_write == I2C_SMBUS_WRITE {
+                data.byte = buf.read_byte(offset + start);
+            }
+
+            let mut msg = SmbusMsg {
+                read_write,
+                command,
+                size,
+                data: Some(data),
+            };
+
+            // Acknowledge polling, the way Linux's at24 driver waits for the write cycles.
+            let deadline = Instant::now() + WRITE_CYCLE_TIMEOUT;
+            loop {
+                match self.with_device(|device| device.smbus(&mut msg)) {
+                    Err(_)
+                        if read_write == I2C_SMBUS_WRITE
+                            && start != 0
+                            && Instant::now() < deadline =>
+                    {
+                        sleep(WRITE_CYCLE_POLL)
+                    }
+                    result => break result?,
+                }
+            }
+
+            if read_write == I2C_SMBUS_READ {
+                let data = msg.data.unwrap();
+                match size {
+                    I2C_SMBUS_I2C_BLOCK_DATA => {
+                        buf.write_slice(offset + start, &data.block()[1..=count])
+                    }
+                    _ => buf.write_byte(offset + start, data.read_byte()),
+                }
+            }
+            start += count;
+        }
+        Ok(())
+    }
+
+    fn adapter_no(&self) -> u32 {
+        self.adapter_no
+    }
+
+    fn is_smbus(&self) -> bool {
+        self.smbus
+    }
+
+    /// Sets device's address for an I2C adapter.
+    fn set_device_addr(&self, addr: usize) -> Result<()> {
+        self.with_device(|device| device.slave(addr as u64))
+    }
+
+    fn transfer(
+        &self,
+        reqs: &mut [I2cReq],
+        auto_increment: bool,
+        page_size: Option<usize>,
+    ) -> Result<()> {
+        if self.is_smbus() {
+            self.smbus_transfer(reqs, auto_increment, page_size)
+        } else {
+            self.i2c_transfer(reqs)
+        }
+    }
+}
+
+/// I2C map and helpers
+pub const MAX_I2C_VDEV: usize = 1 << 7;
+
+/// A client device on one of the adapters of the I2C map.
+#[derive(Debug)]
+struct I2cClient {
+    // Index of the client's adapter in the map.
+    index: usize,
+    enabled: bool,
+    // Whether the long register accesses can be emulated on SMBus-only adapters.
+    auto_increment: bool,
+    // Size of the pages of the client, without which the long writes aren't emulated.
+    page_size: Option<usize>,
+    transfers: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl I2cClient {
+    fn new(index: usize) -> Self {
+        I2cClient {
+            index,
+            enabled: true,
+            auto_increment: false,
+            page_size: None,
+            transfers: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Information about a client device, as reported by `I2cMap::clients()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct I2cClientInfo {
+    pub addr: u16,
+    pub adapter_no: u32,
+    pub enabled: bool,
+    pub transfers: u64,
+    pub errors: u64,
+}
+
+/// Outcome of checking an adapter and its clients, as reported by `I2cMap::check()`.
+#[derive(Debug, PartialEq)]
+pub struct AdapterCheck {
+    pub adapter_no: u32,
+    // SMBus-only status, once the adapter is open.
+    pub smbus: Option<bool>,
+    pub error: Option<Error>,
+    // The clients are only checked once the adapter is open.
+    pub clients: Vec<(u16, Option<Error>)>,
+}
+
+impl AdapterCheck {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none() && self.clients.iter().all(|(_, error)| error.is_none())
+    }
+}
+
+/// Takes over the file of the adapter from the ones opened by the privileged helper.
+fn take_file<D: I2cDevice>(files: &mut Vec<(u32, File)>, adapter_no: u32) -> Result<D> {
+    let index = files
+        .iter()
+        .position(|(no, _)| *no == adapter_no)
+        .ok_or(Error::DeviceOpenFailed(adapter_no))?;
+
+    D::from_file(files.swap_remove(index).1, adapter_no)
+}
+
+pub struct I2cMap<D: I2cDevice> {
+    adapters: Vec<I2cAdapter<D>>,
+    // Clients can be added, removed or disabled at runtime.
+    device_map: RwLock<HashMap<u16, I2cClient>>,
+    // Register level access control, evaluated for every transfer.
+    policy: Policy,
+}
+
+impl<D: I2cDevice> I2cMap<D> {
+    pub fn new(device_config: &AdapterConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let prefix = "/dev/i2c-";
+
+        Self::new_with(device_config, |adapter_no| {
+            D::open(&format!("{}{}", prefix, adapter_no), adapter_no)
+        })
+    }
+
+    /// Creates the map from the adapters already opened, e.g. by a privileged helper.
+    pub fn from_files(device_config: &AdapterConfig, mut files: Vec<(u32, File)>) -> Result<Self> {
+        Self::new_with(device_config, |adapter_no| {
+            take_file(&mut files, adapter_no)
+        })
+    }
+
+    /// Opens the adapters, or takes over the `files` opened by the privileged helper, and
+    /// checks them along with their clients like `new()` does. All the problems are reported,
+    /// instead of stopping at the first one.
+    pub fn check(
+        device_config: &AdapterConfig,
+        mut files: Option<Vec<(u32, File)>>,
+    ) -> Vec<AdapterCheck> {
+        let prefix = "/dev/i2c-";
+
+        Self::check_with(device_config, |adapter_no| match files.as_mut() {
+            Some(files) => take_file(files, adapter_no),
+            None => D::open(&format!("{}{}", prefix, adapter_no), adapter_no),
+        })
+    }
+
+    fn check_with<F>(device_config: &AdapterConfig, mut open: F) -> Vec<AdapterCheck>
+    where
+        F: FnMut(u32) -> Result<D>,
+    {
+        let mut checks = Vec::new();
+
+        for device_cfg in device_config.inner.iter() {
+            let mut check = AdapterCheck {
+                adapter_no: device_cfg.adapter_no,
+                smbus: None,
+                error: None,
+                clients: Vec::new(),
+            };
+
+            match open(device_cfg.adapter_no).and_then(I2cAdapter::new) {
+                Ok(adapter) => {
+                    check.smbus = Some(adapter.is_smbus());
+
+                    for addr in device_cfg.addr.iter() {
+                        check
+                            .clients
+                            .push((*addr, adapter.set_device_addr(*addr as usize).err()));
+                    }
+                }
+                Err(e) => check.error = Some(e),
+            }
+
+            checks.push(check);
+        }
+
+        checks
+    }
+
+    fn new_with<F>(device_config: &AdapterConfig, mut open: F) -> Result<Self>
+    where
+        F: FnMut(u32) -> Result<D>,
+    {
+        let mut device_map = HashMap::new();
+        let mut adapters: Vec<I2cAdapter<D>> = Vec::new();
+
+        for (i, device_cfg) in device_config.inner.iter().enumerate() {
+            let device = open(device_cfg.adapter_no)?;
+            let adapter = I2cAdapter::new(device)?;
+
+            // Check that all addresses corresponding to the adapter are valid.
+            for addr in &device_cfg.addr {
+                adapter.set_device_addr(*addr as usize)?;
+                device_map.insert(*addr, I2cClient::new(i));
+            }
+
+            info!(
+                "Added I2C master with bus id: {:x} for devices",
+                adapter.adapter_no(),
+            );
+
+            adapters.push(adapter);
+        }
+
+        Ok(I2cMap {
+            adapters,
+            device_map: RwLock::new(device_map),
+            policy: Policy::default(),
+        })
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    pub fn set_rate_limiter(&mut self, adapter_no: u32, limiter: Arc<RateLimiter>) -> Result<()> {
+        let adapter = self
+            .adapters
+            .iter_mut()
+            .find(|adapter| adapter.adapter_no == adapter_no)
+            .ok_or(Error::AdapterNotFound(adapter_no))?;
+
+        adapter.limiter = Some(limiter);
+        Ok(())
+    }
+
+    pub fn set_quirks(&mut self, adapter_no: u32, quirks: Quirks) -> Result<()> {
+        let adapter = self
+            .adapters
+            .iter_mut()
+            .find(|adapter| adapter.adapter_no == adapter_no)
+            .ok_or(Error::AdapterNotFound(adapter_no))?;
+
+        adapter.quirks = quirks;
+        Ok(())
+    }
+
+    /// Returns whether the adapter is present, or None if it isn't in the map.
+    pub fn adapter_present(&self, adapter_no: u32) -> Option<bool> {
+        self.adapters
+            .iter()
+            .find(|adapter| adapter.adapter_no() == adapter_no)
+            .map(|adapter| adapter.device.read().unwrap().is_some())
+    }
+
+    /// Marks the adapter absent, e.g. once unplugged. The transfers to its clients fail until
+    /// it's reopened.
+    pub fn remove_adapter(&self, adapter_no: u32) -> Result<()> {
+        let adapter = self
+            .adapters
+            .iter()
+            .find(|adapter| adapter.adapter_no() == adapter_no)
+            .ok_or(Error::AdapterNotFound(adapter_no))?;
+
+        if adapter.device.write().unwrap().take().is_some() {
+            warn!("I2C master {} removed", adapter_no);
+        }
+        Ok(())
+    }
+
+    /// Reopens the adapter at `device_path`, e.g. once plugged back, with the same checks as
+    /// the initial ones for the adapter and its clients.
+    pub fn reopen_adapter(&self, adapter_no: u32, device_path: &str) -> Result<()> {
+        let index = self
+            .adapters
+            .iter()
+            .position(|adapter| adapter.adapter_no() == adapter_no)
+            .ok_or(Error::AdapterNotFound(adapter_no))?;
+        let adapter = &self.adapters[index];
+
+        // The guests rely on the transfers supported by the adapter.
+        let mut device = D::open(device_path, adapter_no)?;
+        if I2cAdapter::is_smbus_only(&mut device)? != adapter.smbus {
+            return Err(Error::AdapterFunctionChanged(adapter_no, device.funcs()?));
+        }
+
+        for (addr, client) in self.device_map.read().unwrap().iter() {
+            if client.index == index {
+                device.slave(*addr as u64)?;
+            }
+        }
+
+        *adapter.device.write().unwrap() = Some(device);
+        info!("I2C master {} reopened", adapter_no);
+        Ok(())
+    }
+
+    /// Returns the adapter numbers and their file descriptors, to pass them to another process.
+    pub fn adapter_fds(&self) -> Vec<(u32, RawFd)>
+    where
+        D: AsRawFd,
+    {
+        self.adapters
+            .iter()
+            .filter_map(|adapter| {
+                let device = adapter.device.read().unwrap();
+
+                device
+                    .as_ref()
+                    .map(|device| (adapter.adapter_no(), device.as_raw_fd()))
+            })
+            .collect()
+    }
+
+    /// Returns the adapter numbers and their SMBus-only status.
+    pub fn adapters(&self) -> Vec<(u32, bool)> {
+        self.adapters
+            .iter()
+            .map(|adapter| (adapter.adapter_no(), adapter.is_smbus()))
+            .collect()
+    }
+
+    /// Returns the clients of all the adapters, sorted by address.
+    pub fn clients(&self) -> Vec<I2cClientInfo> {
+        let mut clients: Vec<I2cClientInfo> = self
+            .device_map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(addr, client)| I2cClientInfo {
+                addr: *addr,
+                adapter_no: self.adapters[client.index].adapter_no(),
+                enabled: client.enabled,
+                transfers: client.transfers.load(Ordering::Relaxed),
+                errors: client.errors.load(Ordering::Relaxed),
+            })
+            .collect();
+
+        clients.sort_by_key(|client| client.addr);
+        clients
+    }
+
+    /// Returns the number of the adapter `addr` is attached to.
+    pub fn client_adapter(&self, addr: u16) -> Option<u32> {
+        self.device_map
+            .read()
+            .unwrap()
+            .get(&addr)
+            .map(|client| self.adapters[client.index].adapter_no())
+    }
+
+    /// Adds a client to an existing adapter at runtime, with the same checks
+    /// as the ones done for the clients of the initial configuration.
+    pub fn add_client(&self, adapter_no: u32, addr: u16) -> Result<()> {
+        if addr as usize > MAX_I2C_VDEV {
+            return Err(Error::ClientAddressInvalid);
+        }
+
+        let index = self
+            .adapters
+            .iter()
+            .position(|adapter| adapter.adapter_no() == adapter_no)
+            .ok_or(Error::AdapterNotFound(adapter_no))?;
+
+        let mut device_map = self.device_map.write().unwrap();
+        if device_map.contains_key(&addr) {
+            return Err(Error::ClientAddressDuplicate(addr));
+        }
+
+        self.adapters[index].set_device_addr(addr as usize)?;
+        device_map.insert(addr, I2cClient::new(index));
+
+        info!("Added client {} to I2C master {}", addr, adapter_no);
+        Ok(())
+    }
+
+    /// Removes a client at runtime.
+    pub fn remove_client(&self, addr: u16) -> Result<()> {
+        match self.device_map.write().unwrap().remove(&addr) {
+            Some(_) => {
+                info!("Removed client {}", addr);
+                Ok(())
+            }
+            None => Err(Error::ClientAddressNotFound(addr)),
+        }
+    }
+
+    /// Enables or disables a client at runtime, transfers to disabled clients
+    /// fail.
+    pub fn enable_client(&self, addr: u16, enable: bool) -> Result<()> {
+        match self.device_map.write().unwrap().get_mut(&addr) {
+            Some(client) => {
+                client.enabled = enable;
+                Ok(())
+            }
+            None => Err(Error::ClientAddressNotFound(addr)),
+        }
+    }
+
+    /// Marks the client as auto-incrementing the register address over the reads and writes of
+    /// a block, so that the long reads of its registers are emulated on SMBus-only adapters. The
+    /// long writes are too if the client has pages, e.g. an EEPROM, of `page_size` bytes.
+    pub fn set_auto_increment(
+        &self,
+        addr: u16,
+        auto_increment: bool,
+        page_size: Option<usize>,
+    ) -> Result<()> {
+        if let Some(size) = page_size {
+            if !size.is_power_of_two() || size > u8::MAX as usize + 1 {
+                return Err(Error::PageSizeInvalid(size));
+            }
+        }
+
+        match self.device_map.write().unwrap().get_mut(&addr) {
+            Some(client) => {
+                client.auto_increment = auto_increment;
+                client.page_size = page_size;
+                Ok(())
+            }
+            None => Err(Error::ClientAddressNotFound(addr)),
+        }
+    }
+
+    pub fn transfer(&self, reqs: &mut [I2cReq]) -> Result<()> {
+        let device = reqs[0].addr;
+        let device_map = self.device_map.read().unwrap();
+
+        // identify the device in the device_map
+        let client = match device_map.get(&device) {
+            Some(client) => client,
+
+            // This can happen a lot while scanning the bus, don't print any errors.
+            None => return Err(Error::ClientAddressInvalid),
+        };
+
+        // The whole transfer goes to the adapter of the first client, the other messages must
+        // address clients of the same adapter.
+        for req in reqs.iter() {
+            match device_map.get(&req.addr) {
+                Some(other) if other.index == client.index => {
+                    if !other.enabled {
+                        return Err(Error::ClientDisabled(req.addr));
+                    }
+                }
+                _ => return Err(Error::ClientAddressInvalid),
+            }
+        }
+
+        for access in Access::from_reqs(reqs) {
+            if !self.policy.allows(&access) {
+                warn!(
+                    target: "audit",
+                    "Denied transfer to client {}: read {}, write {}, registers {:?}",
+                    access.client,
+                    access.read,
+                    access.write,
+                    access.registers
+                );
+                return Err(Error::TransferDenied(access.client));
+            }
+        }
+
+        // get the corresponding adapter based on the device config.
+        let adapter = &self.adapters[client.index];
+
+        if let Some(limiter) = &adapter.limiter {
+            if !limiter.acquire(reqs) {
+                return Err(Error::RateLimited);
+            }
+        }
+
+        // Set device's address
+        let result = adapter
+            .set_device_addr(device as usize)
+            .and_then(|_| adapter.transfer(reqs, client.auto_increment, client.page_size));
+
+        client.transfers.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            client.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Test double of the adapters, for the tests of the crate and of its consumers.
+#[cfg(any(test, feature = "test-utils"))]
+pub mod mock {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    // Update read-buffer of each write-buffer with index + 1 value.
+    pub fn update_rdwr_buf(buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+    }
+
+    // Verify the write-buffer passed to us
+    pub fn verify_rdwr_buf(buf: &[u8]) {
+        for (i, byte) in buf.iter().enumerate() {
+            assert_eq!(*byte, i as u8 + 1);
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct DummyDevice {
+        pub(super) funcs_result: Result<u64>,
+        pub(super) rdwr_result: Result<()>,
+        pub(super) smbus_result: Result<()>,
+        pub(super) slave_result: Result<()>,
+        pub(super) adapter_no: u32,
+        pub(super) file: Option<File>,
+        // Number of messages of each RDWR transfer.
+        pub(super) rdwr_msgs: Mutex<Vec<usize>>,
+        // Command and size of each SMBus transfer.
+        pub(super) smbus_msgs: Mutex<Vec<(u8, u32)>>,
+        // Number of SMBus transfers left unacknowledged after each write, as by an EEPROM busy
+        // with its write cycle, and the number still to go.
+        pub(super) write_cycle: usize,
+        pub(super) busy: Mutex<usize>,
+    }
+
+    impl Default for DummyDevice {
+        fn default() -> Self {
+            Self {
+                funcs_result: Ok(I2C_FUNC_I2C),
+                rdwr_result: Ok(()),
+                smbus_result: Ok(()),
+                slave_result: Ok(()),
+                adapter_no: 0,
+                file: None,
+                rdwr_msgs: Mutex::new(Vec::new()),
+                smbus_msgs: Mutex::new(Vec::new()),
+                write_cycle: 0,
+                busy: Mutex::new(0),
+            }
+        }
+    }
+
+    // Open and funcs results of the adapters, toggled to unplug them or to change their
+    // functionality. The tests run in parallel, and use their own adapter numbers.
+    static TOGGLES: Mutex<BTreeMap<u32, (Result<()>, u64)>> = Mutex::new(BTreeMap::new());
+
+    pub fn toggle_adapter(adapter_no: u32, open_result: Result<()>, funcs: u64) {
+        TOGGLES
+            .lock()
+            .unwrap()
+            .insert(adapter_no, (open_result, funcs));
+    }
+
+    impl I2cDevice for DummyDevice {
+        fn open(_path: &str, adapter_no: u32) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            let mut funcs_result = Ok(I2C_FUNC_I2C);
+
+            if let Some((open_result, funcs)) = TOGGLES.lock().unwrap().get(&adapter_no) {
+                (*open_result)?;
+                funcs_result = Ok(*funcs);
+            }
+
+            Ok(DummyDevice {
+                adapter_no,
+                funcs_result,
+                // Something to pass to other processes.
+                file: File::open("/dev/null").ok(),
+                ..Default::default()
+            })
+        }
+
+        fn from_file(file: File, adapter_no: u32) -> Result<Self> {
+            Ok(DummyDevice {
+                adapter_no,
+                file: Some(file),
+                ..Default::default()
+            })
+        }
+
+        fn funcs(&mut self) -> Result<u64> {
+            self.funcs_result
+        }
+
+        fn rdwr(&self, reqs: &mut [I2cReq]) -> Result<()> {
+            self.rdwr_msgs.lock().unwrap().push(reqs.len());
+
+            for req in reqs {
+                if req.len == 0 {
+                    return Err(Error::I2cTransferInvalid(0));
+                }
+
+                if (req.flags & I2C_M_RD) != 0 {
+                    let mut data = vec![0; req.buf.len()];
+                    update_rdwr_buf(&mut data);
+                    req.buf.write_slice(0, &data);
+                } else {
+                    verify_rdwr_buf(&req.buf.to_vec());
+                }
+            }
+
+            self.rdwr_result
+        }
+
+        fn smbus(&self, msg: &mut SmbusMsg) -> Result<()> {
+            let mut busy = self.busy.lock().unwrap();
+            if *busy != 0 {
+                *busy -= 1;
+                return Err(Error::IoctlFailure("smbus", IoError::new(libc::ENXIO)));
+            }
+            if msg.read_write == I2C_SMBUS_WRITE {
+                *busy = self.write_cycle;
+            }
+
+            self.smbus_msgs
+                .lock()
+                .unwrap()
+                .push((msg.command, msg.size));
+
+            if let Some(data) = &mut msg.data {
+                if msg.size == I2C_SMBUS_I2C_BLOCK_DATA {
+                    // The registers read hold their own address.
+                    if msg.read_write == I2C_SMBUS_READ {
+                        let len = data.block()[0] as usize;
+                        for (i, byte) in data.block_mut()[1..=len].iter_mut().enumerate() {
+                            *byte = msg.command + i as u8;
+                        }
+                    }
+                } else {
+                    // Update data unconditionally to 1 and 2.
+                    data.word = 0x0201;
+                }
+            }
+            self.smbus_result
+        }
+
+        fn slave(&self, _addr: u64) -> Result<()> {
+            self.slave_result
+        }
+
+        fn adapter_no(&self) -> u32 {
+            self.adapter_no
+        }
+    }
+
+    impl AsRawFd for DummyDevice {
+        fn as_raw_fd(&self) -> RawFd {
+            self.file.as_ref().map_or(-1, |file| file.as_raw_fd())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{toggle_adapter, update_rdwr_buf, verify_rdwr_buf, DummyDevice};
+    use super::*;
+    use std::convert::TryFrom;
+
+    use crate::ratelimit::RateLimitConfig;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn verify_rdwr_data(reqs: &[I2cReq]) {
+        // Match what's done by DummyDevice::rdwr()
+        for req in reqs {
+            if (req.flags & I2C_M_RD) != 0 {
+                verify_rdwr_buf(&req.buf.to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn test_funcs() {
+        let i2c_device = DummyDevice {
+            funcs_result: Ok(I2C_FUNC_SMBUS_ALL),
+            ..Default::default()
+        };
+        let adapter = I2cAdapter::new(i2c_device).unwrap();
+        assert!(adapter.smbus);
+
+        let i2c_device = DummyDevice {
+            funcs_result: Ok(I2C_FUNC_I2C),
+            ..Default::default()
+        };
+        let adapter = I2cAdapter::new(i2c_device).unwrap();
+        assert!(!adapter.smbus);
+
+        let i2c_device = DummyDevice {
+            funcs_result: Ok(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            I2cAdapter::new(i2c_device).unwrap_err(),
+            Error::AdapterFunctionInvalid(0)
+        );
+    }
+
+    #[test]
+    fn test_i2c_map() {
+        let adapter_config = AdapterConfig::try_from("1:4,2:32:21,5:10:23").unwrap();
+        let i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+
+        assert_eq!(i2c_map.adapters.len(), 3);
+        assert_eq!(i2c_map.adapters[0].adapter_no(), 1);
+        assert_eq!(i2c_map.adapters[1].adapter_no(), 2);
+        assert_eq!(i2c_map.adapters[2].adapter_no(), 5);
+
+        let device_map = i2c_map.device_map.read().unwrap();
+        assert_eq!(device_map.get(&4).unwrap().index, 0);
+        assert_eq!(device_map.get(&32).unwrap().index, 1);
+        assert_eq!(device_map.get(&21).unwrap().index, 1);
+        assert_eq!(device_map.get(&10).unwrap().index, 2);
+        assert_eq!(device_map.get(&23).unwrap().index, 2);
+    }
+
+    #[test]
+    fn test_i2c_map_from_files() {
+        let adapter_config = AdapterConfig::try_from("1:4,2:32:21").unwrap();
+        let files = vec![
+            (2, File::open("/dev/null").unwrap()),
+            (1, File::open("/dev/null").unwrap()),
+        ];
+        let i2c_map: I2cMap<DummyDevice> = I2cMap::from_files(&adapter_config, files).unwrap();
+
+        assert_eq!(i2c_map.adapters.len(), 2);
+        assert_eq!(i2c_map.adapters[0].adapter_no(), 1);
+        assert_eq!(i2c_map.adapters[1].adapter_no(), 2);
+        assert_eq!(i2c_map.adapter_fds().len(), 2);
+        assert!(i2c_map.adapter_fds().iter().all(|(_, fd)| *fd >= 0));
+
+        // Adapter not opened
+        let files = vec![(1, File::open("/dev/null").unwrap())];
+        assert_eq!(
+            I2cMap::<DummyDevice>::from_files(&adapter_config, files).err(),
+            Some(Error::DeviceOpenFailed(2))
+        );
+    }
+
+    #[test]
+    fn test_i2c_map_hotplug() {
+        let adapter_config = AdapterConfig::try_from("40:4:5,41:6").unwrap();
+        let i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+        let mut reqs = vec![I2cReq {
+            addr: 0x4,
+            flags: I2C_M_RD,
+            len: 1,
+            buf: vec![0].into(),
+        }];
+
+        assert_eq!(i2c_map.adapter_present(40), Some(true));
+        assert_eq!(i2c_map.adapter_present(42), None);
+
+        // Unplugged
+        i2c_map.remove_adapter(40).unwrap();
+        assert_eq!(i2c_map.adapter_present(40), Some(false));
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::AdapterUnavailable(40)
+        );
+        assert_eq!(i2c_map.adapter_fds().len(), 1);
+        assert_eq!(
+            i2c_map.add_client(40, 7).unwrap_err(),
+            Error::AdapterUnavailable(40)
+        );
+        assert_eq!(
+            i2c_map.remove_adapter(42).unwrap_err(),
+            Error::AdapterNotFound(42)
+        );
+
+        // The node is back, but can't be opened yet.
+        toggle_adapter(40, Err(Error::DeviceOpenFailed(40)), I2C_FUNC_I2C);
+        assert_eq!(
+            i2c_map.reopen_adapter(40, "/dev/i2c-40").unwrap_err(),
+            Error::DeviceOpenFailed(40)
+        );
+
+        // A different adapter, with SMBus only
+        toggle_adapter(40, Ok(()), I2C_FUNC_SMBUS_ALL);
+        assert_eq!(
+            i2c_map.reopen_adapter(40, "/dev/i2c-40").unwrap_err(),
+            Error::AdapterFunctionChanged(40, I2C_FUNC_SMBUS_ALL)
+        );
+
+        toggle_adapter(40, Ok(()), 0);
+        assert_eq!(
+            i2c_map.reopen_adapter(40, "/dev/i2c-40").unwrap_err(),
+            Error::AdapterFunctionInvalid(0)
+        );
+        assert_eq!(i2c_map.adapter_present(40), Some(false));
+
+        toggle_adapter(40, Ok(()), I2C_FUNC_I2C);
+        i2c_map.reopen_adapter(40, "/dev/i2c-40").unwrap();
+        assert_eq!(i2c_map.adapter_present(40), Some(true));
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(i2c_map.adapter_fds().len(), 2);
+    }
+
+    #[test]
+    fn test_i2c_map_check() {
+        let adapter_config = AdapterConfig::try_from("1:4,2:32:21,3:5").unwrap();
+        let checks = I2cMap::<DummyDevice>::check_with(&adapter_config, |adapter_no| {
+            let mut device = DummyDevice::open("", adapter_no)?;
+
+            match adapter_no {
+                2 => device.slave_result = Err(Error::ClientAddressInvalid),
+                3 => device.funcs_result = Ok(0),
+                _ => (),
+            }
+            Ok(device)
+        });
+
+        // All the adapters and clients are checked.
+        assert_eq!(
+            checks,
+            vec![
+                AdapterCheck {
+                    adapter_no: 1,
+                    smbus: Some(false),
+                    error: None,
+                    clients: vec![(4, None)],
+                },
+                AdapterCheck {
+                    adapter_no: 2,
+                    smbus: Some(false),
+                    error: None,
+                    clients: vec![
+                        (32, Some(Error::ClientAddressInvalid)),
+                        (21, Some(Error::ClientAddressInvalid))
+                    ],
+                },
+                AdapterCheck {
+                    adapter_no: 3,
+                    smbus: None,
+                    error: Some(Error::AdapterFunctionInvalid(0)),
+                    clients: vec![],
+                },
+            ]
+        );
+        assert!(checks[0].is_ok());
+        assert!(!checks[1].is_ok());
+        assert!(!checks[2].is_ok());
+
+        // Adapter not opened by the helper
+        let files = vec![(1, File::open("/dev/null").unwrap())];
+        let checks = I2cMap::<DummyDevice>::check(&adapter_config, Some(files));
+        assert!(checks[0].is_ok());
+        assert_eq!(checks[1].error, Some(Error::DeviceOpenFailed(2)));
+        assert_eq!(checks[2].error, Some(Error::DeviceOpenFailed(3)));
+    }
+
+    #[test]
+    fn test_i2c_map_runtime_clients() {
+        let adapter_config = AdapterConfig::try_from("1:4,2:32").unwrap();
+        let i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+
+        assert_eq!(i2c_map.adapters(), vec![(1, false), (2, false)]);
+
+        i2c_map.add_client(2, 21).unwrap();
+        assert_eq!(
+            i2c_map.add_client(1, 21).unwrap_err(),
+            Error::ClientAddressDuplicate(21)
+        );
+        assert_eq!(
+            i2c_map.add_client(3, 22).unwrap_err(),
+            Error::AdapterNotFound(3)
+        );
+        assert_eq!(
+            i2c_map
+                .add_client(1, (MAX_I2C_VDEV + 1) as u16)
+                .unwrap_err(),
+            Error::ClientAddressInvalid
+        );
+
+        let mut reqs = vec![I2cReq {
+            addr: 21,
+            flags: I2C_M_RD,
+            len: 1,
+            buf: vec![0].into(),
+        }];
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        i2c_map.enable_client(21, false).unwrap();
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::ClientDisabled(21)
+        );
+        i2c_map.enable_client(21, true).unwrap();
+
+        let clients = i2c_map.clients();
+        assert_eq!(clients.len(), 3);
+        assert_eq!(
+            clients[1],
+            I2cClientInfo {
+                addr: 21,
+                adapter_no: 2,
+                enabled: true,
+                transfers: 1,
+                errors: 0,
+            }
+        );
+
+        assert_eq!(i2c_map.client_adapter(21), Some(2));
+        i2c_map.remove_client(21).unwrap();
+        assert_eq!(i2c_map.client_adapter(21), None);
+        assert_eq!(
+            i2c_map.remove_client(21).unwrap_err(),
+            Error::ClientAddressNotFound(21)
+        );
+        assert_eq!(
+            i2c_map.enable_client(21, true).unwrap_err(),
+            Error::ClientAddressNotFound(21)
+        );
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::ClientAddressInvalid
+        );
+    }
+
+    #[test]
+    fn test_i2c_buf() {
+        let mut memory = vec![0u8; 4];
+        let mut guest = unsafe { I2cBuf::from_guest(memory.as_mut_ptr(), memory.len()) };
+        let mut owned = I2cBuf::new(4);
+
+        for buf in [&mut guest, &mut owned] {
+            assert_eq!(buf.len(), 4);
+            buf.write_byte(0, 1);
+            buf.write_slice(1, &[2, 3]);
+            assert_eq!(buf.read_byte(2), 3);
+
+            let mut data = [0; 2];
+            buf.read_slice(1, &mut data);
+            assert_eq!(data, [2, 3]);
+            assert_eq!(*buf, vec![1, 2, 3, 0]);
+        }
+        assert!(guest.is_guest() && !owned.is_guest());
+        drop(guest);
+        assert_eq!(memory, vec![1, 2, 3, 0]);
+
+        let result = std::panic::catch_unwind(|| owned.read_byte(4));
+        assert!(result.is_err());
+        let mut data = [0; 2];
+        let result = std::panic::catch_unwind(move || {
+            let guest = unsafe { I2cBuf::from_guest(data.as_mut_ptr(), 2) };
+            let mut out = [0; 2];
+            guest.read_slice(1, &mut out);
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_i2c_transfer() {
+        let adapter_config = AdapterConfig::try_from("1:3").unwrap();
+        let mut i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+
+        i2c_map.adapters[0].smbus = false;
+
+        // Read-Write-Read-Write-Read block
+        let mut reqs: Vec<I2cReq> = vec![
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                len: 20,
+                buf: vec![0; 20].into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 10,
+                buf: vec![0; 10].into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                len: 25,
+                buf: vec![0; 25].into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 11,
+                buf: vec![0; 11].into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                len: 30,
+                buf: vec![0; 30].into(),
+            },
+        ];
+
+        for req in &mut reqs {
+            if (req.flags & I2C_M_RD) == 0 {
+                let mut data = vec![0; req.buf.len()];
+                update_rdwr_buf(&mut data);
+                req.buf = data.into();
+            }
+        }
+
+        i2c_map.transfer(&mut reqs).unwrap();
+        verify_rdwr_data(&reqs);
+    }
+
+    #[test]
+    fn test_transfer_quirks() {
+        let adapter_config = AdapterConfig::try_from("1:3").unwrap();
+        let mut i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+        let rdwr_msgs = |i2c_map: &I2cMap<DummyDevice>| {
+            let device = i2c_map.adapters[0].device.read().unwrap();
+            let msgs = device.as_ref().unwrap().rdwr_msgs.lock().unwrap().clone();
+            msgs
+        };
+
+        i2c_map.adapters[0].smbus = false;
+
+        // Register reads, split at the kernel limit.
+        let mut reqs: Vec<I2cReq> = (0..50)
+            .map(|i| I2cReq {
+                addr: 3,
+                flags: if i % 2 == 0 { 0 } else { I2C_M_RD },
+                len: 2,
+                buf: vec![1, 2].into(),
+            })
+            .collect();
+        i2c_map.transfer(&mut reqs).unwrap();
+        verify_rdwr_data(&reqs);
+        assert_eq!(rdwr_msgs(&i2c_map), vec![42, 8]);
+
+        let quirks = Quirks {
+            max_msgs: 4,
+            max_len: Some(2),
+            combined: true,
+        };
+        i2c_map.set_quirks(1, quirks).unwrap();
+        assert_eq!(
+            i2c_map.set_quirks(3, quirks).unwrap_err(),
+            Error::AdapterNotFound(3)
+        );
+
+        i2c_map.transfer(&mut reqs[..10]).unwrap();
+        assert_eq!(rdwr_msgs(&i2c_map), vec![42, 8, 4, 4, 2]);
+
+        // Nothing is transferred from a batch which doesn't fit.
+        reqs[9].len = 3;
+        reqs[9].buf = vec![0; 3].into();
+        assert_eq!(
+            i2c_map.transfer(&mut reqs[..10]).unwrap_err(),
+            Error::MessageTooLong(3, 2)
+        );
+        assert_eq!(rdwr_msgs(&i2c_map), vec![42, 8, 4, 4, 2]);
+    }
+
+    #[test]
+    fn test_transfer_rate_limit() {
+        let adapter_config = AdapterConfig::try_from("1:3,2:4").unwrap();
+        let mut i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+        let config = RateLimitConfig::try_from("adapter=1,ops=1,mode=reject").unwrap();
+
+        i2c_map.adapters[0].smbus = false;
+        i2c_map.adapters[1].smbus = false;
+        i2c_map
+            .set_rate_limiter(1, Arc::new(RateLimiter::new(&config, config.scope)))
+            .unwrap();
+        assert_eq!(
+            i2c_map
+                .set_rate_limiter(3, Arc::new(RateLimiter::new(&config, config.scope)))
+                .unwrap_err(),
+            Error::AdapterNotFound(3)
+        );
+
+        let mut reqs = vec![I2cReq {
+            addr: 3,
+            flags: I2C_M_RD,
+            len: 1,
+            buf: vec![0].into(),
+        }];
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(i2c_map.transfer(&mut reqs).unwrap_err(), Error::RateLimited);
+
+        // Other adapters aren't limited.
+        reqs[0].addr = 4;
+        i2c_map.transfer(&mut reqs).unwrap();
+        i2c_map.transfer(&mut reqs).unwrap();
+    }
+
+    #[test]
+    fn test_transfer_policy() {
+        let adapter_config = AdapterConfig::try_from("1:3:80").unwrap();
+        let mut i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+
+        i2c_map.adapters[0].smbus = false;
+        i2c_map.set_policy(
+            Policy::try_from(
+                r#"{"rules": [{"client": 80, "direction": "write", "action": "deny"}]}"#,
+            )
+            .unwrap(),
+        );
+
+        let mut reqs = vec![
+            I2cReq {
+                addr: 80,
+                flags: 0,
+                len: 1,
+                buf: vec![1].into(),
+            },
+            I2cReq {
+                addr: 80,
+                flags: I2C_M_RD,
+                len: 4,
+                buf: vec![0; 4].into(),
+            },
+        ];
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        let mut reqs = vec![I2cReq {
+            addr: 80,
+            flags: 0,
+            len: 2,
+            buf: vec![1, 2].into(),
+        }];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::TransferDenied(80)
+        );
+
+        reqs[0].addr = 3;
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        // A write to the read-only client later in the batch.
+        let mut reqs = vec![
+            I2cReq {
+                addr: 3,
+                flags: 0,
+                len: 2,
+                buf: vec![1, 2].into(),
+            },
+            I2cReq {
+                addr: 80,
+                flags: 0,
+                len: 2,
+                buf: vec![1, 2].into(),
+            },
+        ];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::TransferDenied(80)
+        );
+    }
+
+    #[test]
+    fn test_verify_smbus_data() {
+        let data = I2cSmbusData { word: 0x050A };
+
+        assert_eq!(data.read_byte(), 0x0A);
+        assert_eq!(data.read_word(), 0x050A);
+    }
+
+    #[test]
+    fn test_smbus_transfer() {
+        let adapter_config = AdapterConfig::try_from("1:3").unwrap();
+        let mut i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+
+        i2c_map.adapters[0].smbus = true;
+
+        // I2C_SMBUS_WRITE (I2C_SMBUS_QUICK) operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            flags: 0,
+            len: 0,
+            buf: Vec::<u8>::new().into(),
+        }];
+
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        // I2C_SMBUS_READ (I2C_SMBUS_QUICK) operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            flags: I2C_M_RD,
+            len: 0,
+            buf: vec![0].into(),
+        }];
+
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        // I2C_SMBUS_WRITE (I2C_SMBUS_BYTE) operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            flags: 0,
+            len: 1,
+            buf: vec![0].into(),
+        }];
+
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        // I2C_SMBUS_READ (I2C_SMBUS_BYTE) operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            flags: I2C_M_RD,
+            len: 1,
+            buf: vec![0].into(),
+        }];
+
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(reqs[0].buf.read_byte(0), 1);
+
+        // I2C_SMBUS_WRITE (I2C_SMBUS_BYTE_DATA) operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            flags: 0,
+            len: 2,
+            buf: [7, 4].to_vec().into(),
+        }];
+
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        // I2C_SMBUS_READ (I2C_SMBUS_BYTE_DATA) operation
+        let mut reqs = vec![
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 1,
+                buf: vec![0].into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                len: 1,
+                buf: vec![0].into(),
+            },
+        ];
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(reqs[1].buf.read_byte(0), 1);
+
+        // I2C_SMBUS_WRITE (I2C_SMBUS_WORD_DATA) operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            flags: 0,
+            len: 3,
+            buf: [7, 4, 3].to_vec().into(),
+        }];
+
+        i2c_map.transfer(&mut reqs).unwrap();
+
+        // I2C_SMBUS_READ (I2C_SMBUS_WORD_DATA) operation
+        let mut reqs = vec![
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 1,
+                buf: vec![0].into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                len: 2,
+                buf: vec![0; 2].into(),
+            },
+        ];
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(reqs[1].buf.read_byte(0), 1);
+        assert_eq!(reqs[1].buf.read_byte(1), 2);
+    }
+
+    #[test]
+    fn test_transfer_failure() {
+        let adapter_config = AdapterConfig::try_from("1:3").unwrap();
+        let mut i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+
+        i2c_map.adapters[0].smbus = false;
+
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            // Will cause failure
+            addr: 0x4,
+            flags: 0,
+            len: 2,
+            buf: vec![7, 4].into(),
+        }];
+
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::ClientAddressInvalid
+        );
+    }
+
+    #[test]
+    fn test_smbus_block_transfer() {
+        let adapter_config = AdapterConfig::try_from("1:3").unwrap();
+        let mut i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+        let smbus_msgs = |i2c_map: &I2cMap<DummyDevice>| {
+            let device = i2c_map.adapters[0].device.read().unwrap();
+            let mut msgs = device.as_ref().unwrap().smbus_msgs.lock().unwrap();
+            msgs.drain(..).collect::<Vec<_>>()
+        };
+        let set_write_cycle = |i2c_map: &I2cMap<DummyDevice>, write_cycle: usize| {
+            let mut device = i2c_map.adapters[0].device.write().unwrap();
+            let device = device.as_mut().unwrap();
+            device.write_cycle = write_cycle;
+            *device.busy.lock().unwrap() = 0;
+        };
+        let register_read = |register: u8, len: u16| {
+            vec![
+                I2cReq {
+                    addr: 3,
+                    flags: 0,
+                    len: 1,
+                    buf: vec![register].into(),
+                },
+                I2cReq {
+                    addr: 3,
+                    flags: I2C_M_RD,
+                    len,
+                    buf: vec![0; len as usize].into(),
+                },
+            ]
+        };
+
+        i2c_map.adapters[0].smbus = true;
+        i2c_map.adapters[0].funcs =
+            I2C_FUNC_SMBUS_ALL | I2C_FUNC_SMBUS_READ_I2C_BLOCK | I2C_FUNC_SMBUS_WRITE_I2C_BLOCK;
+
+        // Not emulated unless the client auto-increments the register address.
+        let mut reqs = register_read(0x10, 40);
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::SMBusTransferInvalid(2, 1, 40)
+        );
+
+        i2c_map.set_auto_increment(3, true, None).unwrap();
+        assert_eq!(
+            i2c_map.set_auto_increment(4, true, None).unwrap_err(),
+            Error::ClientAddressNotFound(4)
+        );
+        smbus_msgs(&i2c_map);
+
+        // I2C block reads, the registers of DummyDevice hold their own address.
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(reqs[1].buf, (0x10..0x38).collect::<Vec<u8>>());
+        assert_eq!(
+            smbus_msgs(&i2c_map),
+            vec![
+                (0x10, I2C_SMBUS_I2C_BLOCK_DATA),
+                (0x30, I2C_SMBUS_I2C_BLOCK_DATA)
+            ]
+        );
+
+        // I2C block writes, of the register number followed by the data, only emulated when the
+        // pages of the client are known.
+        let mut buf = vec![0xaa; 41];
+        buf[0] = 0x1c;
+        let mut reqs = vec![I2cReq {
+            addr: 3,
+            flags: 0,
+            len: 41,
+            buf: buf.into(),
+        }];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::MessageLengthInvalid("write", 41)
+        );
+        assert_eq!(
+            i2c_map.set_auto_increment(3, true, Some(24)).unwrap_err(),
+            Error::PageSizeInvalid(24)
+        );
+        i2c_map.set_auto_increment(3, true, Some(16)).unwrap();
+
+        // They don't cross the page boundaries, and wait for the write cycles.
+        set_write_cycle(&i2c_map, 3);
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(
+            smbus_msgs(&i2c_map),
+            vec![
+                (0x1c, I2C_SMBUS_I2C_BLOCK_DATA),
+                (0x20, I2C_SMBUS_I2C_BLOCK_DATA),
+                (0x30, I2C_SMBUS_I2C_BLOCK_DATA),
+                (0x40, I2C_SMBUS_I2C_BLOCK_DATA)
+            ]
+        );
+
+        // Until the write cycle times out.
+        set_write_cycle(&i2c_map, usize::MAX);
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::IoctlFailure("smbus", IoError::new(libc::ENXIO))
+        );
+        assert_eq!(smbus_msgs(&i2c_map), vec![(0x1c, I2C_SMBUS_I2C_BLOCK_DATA)]);
+        set_write_cycle(&i2c_map, 0);
+
+        // Short transfers still use the basic modes.
+        let mut reqs = register_read(0x10, 2);
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(smbus_msgs(&i2c_map), vec![(0x10, I2C_SMBUS_WORD_DATA)]);
+
+        // Byte data transfers, without I2C block support.
+        i2c_map.adapters[0].funcs = I2C_FUNC_SMBUS_ALL;
+        let mut reqs = register_read(0x10, 3);
+        i2c_map.transfer(&mut reqs).unwrap();
+        assert_eq!(reqs[1].buf, vec![1; 3]);
+        assert_eq!(
+            smbus_msgs(&i2c_map),
+            vec![
+                (0x10, I2C_SMBUS_BYTE_DATA),
+                (0x11, I2C_SMBUS_BYTE_DATA),
+                (0x12, I2C_SMBUS_BYTE_DATA)
+            ]
+        );
+
+        // The register address doesn't wrap around.
+        let mut reqs = register_read(0xfe, 3);
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::RegisterBlockInvalid(0xfe, 3)
+        );
+
+        i2c_map.adapters[0].funcs = I2C_FUNC_SMBUS_BYTE;
+        let mut reqs = register_read(0x10, 3);
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::MessageLengthInvalid("read", 3)
+        );
+    }
+
+    #[test]
+    fn test_smbus_transfer_failure() {
+        let adapter_config = AdapterConfig::try_from("1:3").unwrap();
+        let mut i2c_map: I2cMap<DummyDevice> = I2cMap::new(&adapter_config).unwrap();
+        i2c_map.adapters[0].smbus = true;
+
+        // I2C_SMBUS_READ (Invalid size) failure operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            flags: I2C_M_RD,
+            // Will cause failure
+            len: 2,
+            buf: [34].to_vec().into(),
+        }];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::MessageLengthInvalid("read", 2)
+        );
+
+        // I2C_SMBUS_WRITE (Invalid size) failure operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            flags: 0,
+            // Will cause failure
+            len: 4,
+            buf: [34].to_vec().into(),
+        }];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::MessageLengthInvalid("write", 4)
+        );
+
+        // I2C_SMBUS_WRITE (I2C_SMBUS_WORD_DATA) failure operation
+        let mut reqs: Vec<I2cReq> = vec![I2cReq {
+            addr: 0x3,
+            // Will cause failure
+            flags: I2C_M_RD,
+            len: 3,
+            buf: [7, 4, 3].to_vec().into(),
+        }];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::MessageLengthInvalid("read", 3)
+        );
+
+        // I2C_SMBUS_READ (I2C_SMBUS_WORD_DATA) failure operation
+        let mut reqs: Vec<I2cReq> = vec![
+            I2cReq {
+                addr: 0x3,
+                // Will cause failure
+                flags: I2C_M_RD,
+                len: 1,
+                buf: [34].to_vec().into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                len: 2,
+                buf: [3, 4].to_vec().into(),
+            },
+        ];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::SMBusTransferInvalid(2, 1, 2)
+        );
+
+        // I2C_SMBUS_READ (I2C_SMBUS_WORD_DATA) failure operation
+        let mut reqs = vec![
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 1,
+                buf: [34].to_vec().into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                // Will cause failure
+                flags: 0,
+                len: 2,
+                buf: [3, 4].to_vec().into(),
+            },
+        ];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::SMBusTransferInvalid(2, 1, 2)
+        );
+
+        // I2C_SMBUS_READ (I2C_SMBUS_WORD_DATA) failure operation
+        let mut reqs = vec![
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                // Will cause failure
+                len: 2,
+                buf: [3, 4].to_vec().into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                len: 2,
+                buf: [3, 4].to_vec().into(),
+            },
+        ];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::SMBusTransferInvalid(2, 2, 2)
+        );
+
+        // I2C_SMBUS_READ (I2C_SMBUS_WORD_DATA) failure operation
+        let mut reqs = vec![
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 1,
+                buf: [34].to_vec().into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                // Will cause failure
+                len: 3,
+                buf: [3, 4, 5].to_vec().into(),
+            },
+        ];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::SMBusTransferInvalid(2, 1, 3)
+        );
+
+        // I2C_SMBUS_READ (Invalid request count) failure operation
+        let mut reqs = vec![
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 1,
+                buf: [34].to_vec().into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                len: 2,
+                buf: [3, 4].to_vec().into(),
+            },
+            // Will cause failure
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 0,
+                buf: [0].to_vec().into(),
+            },
+        ];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::SMBusTransferInvalid(3, 1, 2)
+        );
+
+        // I2C_SMBUS_READ (Zero-length read buffer) failure operation
+        let mut reqs = vec![
+            I2cReq {
+                addr: 0x3,
+                flags: 0,
+                len: 1,
+                buf: [34].to_vec().into(),
+            },
+            I2cReq {
+                addr: 0x3,
+                flags: I2C_M_RD,
+                // Will cause failure
+                len: 0,
+                buf: Vec::new().into(),
+            },
+        ];
+        assert_eq!(
+            i2c_map.transfer(&mut reqs).unwrap_err(),
+            Error::SMBusTransferInvalid(2, 1, 0)
+        );
+
+        // No request at all
+        assert_eq!(
+            SmbusMsg::new(&mut []).err(),
+            Some(Error::SMBusTransferInvalid(0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_phys_device_failure() {
+        // Open failure
+        assert_eq!(
+            PhysDevice::open("/dev/i2c-invalid-path", 0).unwrap_err(),
+            Error::DeviceOpenFailed(0)
+        );
+
+        let file = TempFile::new().unwrap();
+        let mut dev = PhysDevice::open(file.as_path().to_str().unwrap(), 1).unwrap();
+
+        // Match adapter number
+        assert_eq!(dev.adapter_no(), 1);
+
+        // funcs failure
+        assert_eq!(
+            dev.funcs().unwrap_err(),
+            Error::IoctlFailure("funcs", IoError::last())
+        );
+
+        // rdwr failure
+        let mut reqs = [I2cReq {
+            addr: 0x4,
+            flags: 0,
+            len: 2,
+            buf: vec![7, 4].into(),
+        }];
+        assert_eq!(
+            dev.rdwr(&mut reqs).unwrap_err(),
+            Error::IoctlFailure("rdwr", IoError::last())
+        );
+
+        // rdwr failure - missing buffer
+        let mut reqs = [I2cReq {
+            addr: 0x4,
+            flags: 0,
+            len: 0,
+            buf: Vec::<u8>::new().into(),
+        }];
+        assert_eq!(
+            dev.rdwr(&mut reqs).unwrap_err(),
+            Error::I2cTransferInvalid(0)
+        );
+
+        // smbus failure
+        let mut data = SmbusMsg {
+            read_write: 0,
+            command: 0,
+            size: 0,
+            data: None,
+        };
+        assert_eq!(
+            dev.smbus(&mut data).unwrap_err(),
+            Error::IoctlFailure("smbus", IoError::last())
+        );
+
+        // slave failure
+        assert_eq!(
+            dev.slave(0).unwrap_err(),
+            Error::IoctlFailure("slave", IoError::last())
+        );
+    }
+}
@@ -0,0 +1,21 @@
+// virtio i2c request handling
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decoding of the virtio-i2c requests, and their transfer to the host adapters.
+//!
+//! This crate doesn't depend on vhost-user, the vhost-device-i2c daemon is one of its
+//! consumers, and a VMM can service the request queue of the guests with it directly:
+//! [`request::parse_request`] turns the descriptor chains into I2C messages, which are
+//! transferred by [`i2c::I2cMap::transfer`], and completed with [`request::complete_request`].
+//! The chains refused by the parser are completed with [`request::fail_request`].
+
+pub mod config;
+pub mod i2c;
+pub mod policy;
+pub mod quirks;
+pub mod ratelimit;
+pub mod request;
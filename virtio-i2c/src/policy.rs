@@ -0,0 +1,374 @@
+// Register level access control policies
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::convert::TryFrom;
+use std::fs;
+
+use serde::Deserialize;
+use thiserror::Error as ThisError;
+
+use crate::i2c::{I2cReq, I2C_M_RD, MAX_I2C_VDEV};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the access control policies
+pub enum Error {
+    #[error("Failed to read policy file {0}: {1}")]
+    FileReadFailed(String, String),
+    #[error("Invalid policy: {0}")]
+    PolicyInvalid(String),
+    #[error("Invalid client address in policy: {0}")]
+    ClientAddressInvalid(u16),
+    #[error("Invalid register range in policy: {0}-{1}")]
+    RegisterRangeInvalid(u8, u8),
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Read,
+    Write,
+    #[default]
+    Any,
+}
+
+/// A rule matches the messages to the client, if any, in the direction, and accessing any
+/// register or command in the inclusive range, if any.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(default)]
+    client: Option<u16>,
+    #[serde(default)]
+    direction: Direction,
+    #[serde(default)]
+    registers: Option<(u8, u8)>,
+    action: Action,
+}
+
+/// Access control policy, evaluated for every message of a transfer, which is denied if any of
+/// them is. The first matching rule applies, or the default action otherwise. The policy is
+/// loaded from a JSON file, e.g. for a read-only EEPROM and a PMIC with write protected
+/// voltage registers:
+///
+///   {
+///     "default": "allow",
+///     "rules": [
+///       {"client": 80, "direction": "write", "action": "deny"},
+///       {"client": 45, "direction": "write", "registers": [16, 31], "action": "deny"}
+///     ]
+///   }
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    default: Action,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+/// Access of a message, as seen by the policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Access {
+    pub client: u16,
+    pub read: bool,
+    pub write: bool,
+    // Inclusive range of the registers accessed, from the register or command written first.
+    pub registers: Option<(u8, u8)>,
+}
+
+/// Returns the inclusive range of `count` registers from `first`. The devices auto-increment
+/// the register address over the bytes of a message, and usually wrap around past the last
+/// one, all of them may be accessed then.
+fn register_span(first: u8, count: usize) -> (u8, u8) {
+    match first as usize + count.max(1) - 1 {
+        last if last <= u8::MAX as usize => (first, last as u8),
+        _ => (0, u8::MAX),
+    }
+}
+
+// A single byte write, the register number, followed by a read of the same client.
+fn selects_register(reqs: &[I2cReq], i: usize) -> bool {
+    let req = &reqs[i];
+
+    req.flags & I2C_M_RD == 0
+        && req.len == 1
+        && matches!(reqs.get(i + 1), Some(next) if next.flags & I2C_M_RD != 0 && next.addr == req.addr)
+}
+
+impl Access {
+    /// Classifies every message of a transfer, with its own client. A register read, a single
+    /// byte write followed by a read, counts as a read of the registers from the one written.
+    /// A write accesses the registers from the first byte written, one per byte that follows.
+    pub fn from_reqs(reqs: &[I2cReq]) -> Vec<Self> {
+        let mut accesses = Vec::new();
+
+        for (i, req) in reqs.iter().enumerate() {
+            if req.flags & I2C_M_RD != 0 {
+                let register = match i.checked_sub(1) {
+                    Some(prev) if selects_register(reqs, prev) && !reqs[prev].buf.is_empty() => {
+                        Some(reqs[prev].buf.read_byte(0))
+                    }
+                    _ => None,
+                };
+
+                accesses.push(Access {
+                    client: req.addr,
+                    read: true,
+                    write: false,
+                    registers: register.map(|first| register_span(first, req.len as usize)),
+                });
+            } else if !selects_register(reqs, i) {
+                accesses.push(Access {
+                    client: req.addr,
+                    read: false,
+                    write: true,
+                    registers: (!req.buf.is_empty()).then(|| {
+                        register_span(req.buf.read_byte(0), (req.len as usize).saturating_sub(1))
+                    }),
+                });
+            }
+        }
+
+        accesses
+    }
+}
+
+impl Rule {
+    fn matches(&self, access: &Access) -> bool {
+        if matches!(self.client, Some(client) if client != access.client) {
+            return false;
+        }
+
+        let direction = match self.direction {
+            Direction::Read => access.read,
+            Direction::Write => access.write,
+            Direction::Any => true,
+        };
+
+        let register = match (self.registers, access.registers) {
+            (None, _) => true,
+            (Some((first, last)), Some((start, end))) => first <= end && start <= last,
+            (Some(_), None) => false,
+        };
+
+        direction && register
+    }
+}
+
+impl Policy {
+    /// Loads the policy from a JSON file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let policy = fs::read_to_string(path)
+            .map_err(|e| Error::FileReadFailed(path.to_string(), e.to_string()))?;
+
+        Self::try_from(policy.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default == Action::Allow && self.rules.is_empty()
+    }
+
+    pub fn allows(&self, access: &Access) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(access))
+            .map_or(self.default, |rule| rule.action)
+            == Action::Allow
+    }
+}
+
+impl TryFrom<&str> for Policy {
+    type Error = Error;
+
+    fn try_from(policy: &str) -> Result<Self> {
+        let policy: Policy =
+            serde_json::from_str(policy).map_err(|e| Error::PolicyInvalid(e.to_string()))?;
+
+        for rule in policy.rules.iter() {
+            if let Some(client) = rule.client {
+                if client as usize > MAX_I2C_VDEV {
+                    return Err(Error::ClientAddressInvalid(client));
+                }
+            }
+
+            if let Some((first, last)) = rule.registers {
+                if first > last {
+                    return Err(Error::RegisterRangeInvalid(first, last));
+                }
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn req(addr: u16, flags: u16, buf: Vec<u8>) -> I2cReq {
+        I2cReq {
+            addr,
+            flags,
+            len: buf.len() as u16,
+            buf: buf.into(),
+        }
+    }
+
+    // Access of a single message transfer.
+    fn access(req: I2cReq) -> Access {
+        Access::from_reqs(&[req])[0]
+    }
+
+    #[test]
+    fn test_access() {
+        // Register read
+        let accesses =
+            Access::from_reqs(&[req(0x50, 0, vec![0x10]), req(0x50, I2C_M_RD, vec![0; 2])]);
+        assert_eq!(
+            accesses,
+            vec![Access {
+                client: 0x50,
+                read: true,
+                write: false,
+                registers: Some((0x10, 0x11)),
+            }]
+        );
+
+        // Register write
+        let write = access(req(0x50, 0, vec![0x10, 0xaa, 0xbb]));
+        assert!(write.write && !write.read);
+        assert_eq!(write.registers, Some((0x10, 0x11)));
+
+        // Command, SMBus send byte
+        let write = access(req(0x50, 0, vec![0x10]));
+        assert!(write.write && !write.read);
+        assert_eq!(write.registers, Some((0x10, 0x10)));
+
+        // Wrapping around the last register
+        let write = access(req(0x50, 0, vec![0xfe, 1, 2, 3]));
+        assert_eq!(write.registers, Some((0, 0xff)));
+
+        // Current address read
+        let read = access(req(0x50, I2C_M_RD, vec![0; 4]));
+        assert!(read.read && !read.write);
+        assert_eq!(read.registers, None);
+
+        // Every message, with its own client
+        let accesses = Access::from_reqs(&[
+            req(0x50, 0, vec![0x10]),
+            req(0x50, I2C_M_RD, vec![0; 1]),
+            req(0x51, 0, vec![0x20, 0xaa]),
+            req(0x52, 0, vec![0x30]),
+            req(0x53, I2C_M_RD, vec![0; 1]),
+        ]);
+        assert_eq!(
+            accesses
+                .iter()
+                .map(|access| (access.client, access.write, access.registers))
+                .collect::<Vec<_>>(),
+            vec![
+                (0x50, false, Some((0x10, 0x10))),
+                (0x51, true, Some((0x20, 0x20))),
+                (0x52, true, Some((0x30, 0x30))),
+                (0x53, false, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policy_allows() {
+        let policy = Policy::try_from(
+            r#"{
+                "rules": [
+                    {"client": 80, "direction": "write", "action": "deny"},
+                    {"client": 45, "direction": "write", "registers": [16, 31], "action": "deny"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        // Read-only EEPROM
+        let read = Access::from_reqs(&[req(80, 0, vec![0]), req(80, I2C_M_RD, vec![0; 8])]);
+        let write = access(req(80, 0, vec![0, 1, 2]));
+        assert!(policy.allows(&read[0]));
+        assert!(!policy.allows(&write));
+
+        // PMIC voltage registers
+        assert!(!policy.allows(&access(req(45, 0, vec![16, 0x20]))));
+        assert!(!policy.allows(&access(req(45, 0, vec![31, 0x20]))));
+        assert!(policy.allows(&access(req(45, 0, vec![32, 0x20]))));
+        assert!(policy.allows(&access(req(45, 0, vec![14, 0x20, 0x20]))));
+        let read = Access::from_reqs(&[req(45, 0, vec![16]), req(45, I2C_M_RD, vec![0])]);
+        assert!(policy.allows(&read[0]));
+
+        // A write starting below the range, and spanning it.
+        assert!(!policy.allows(&access(req(45, 0, vec![14, 0x20, 0x20, 0x20]))));
+        assert!(!policy.allows(&access(req(45, 0, (0..18).collect()))));
+
+        // Other clients
+        assert!(policy.allows(&access(req(20, 0, vec![16, 0x20]))));
+
+        // Deny by default
+        let policy = Policy::try_from(
+            r#"{
+                "default": "deny",
+                "rules": [{"client": 20, "direction": "read", "action": "allow"}]
+            }"#,
+        )
+        .unwrap();
+        assert!(!policy.is_empty());
+        assert!(policy.allows(&access(req(20, I2C_M_RD, vec![0]))));
+        assert!(!policy.allows(&access(req(20, 0, vec![0, 1]))));
+        assert!(!policy.allows(&access(req(21, I2C_M_RD, vec![0]))));
+
+        assert!(Policy::default().is_empty());
+        assert!(Policy::default().allows(&write));
+    }
+
+    #[test]
+    fn test_policy_parse_failure() {
+        assert_eq!(
+            Policy::try_from(r#"{"rules": [{"client": 200, "action": "deny"}]}"#).unwrap_err(),
+            Error::ClientAddressInvalid(200)
+        );
+        assert_eq!(
+            Policy::try_from(r#"{"rules": [{"registers": [4, 2], "action": "deny"}]}"#)
+                .unwrap_err(),
+            Error::RegisterRangeInvalid(4, 2)
+        );
+        assert!(matches!(
+            Policy::try_from(r#"{"rules": [{"client": 20}]}"#).unwrap_err(),
+            Error::PolicyInvalid(_)
+        ));
+        assert!(matches!(
+            Policy::try_from(r#"{"rules": [], "mode": "strict"}"#).unwrap_err(),
+            Error::PolicyInvalid(_)
+        ));
+
+        let file = TempFile::new().unwrap();
+        std::fs::write(file.as_path(), r#"{"default": "deny"}"#).unwrap();
+        let policy = Policy::from_file(file.as_path().to_str().unwrap()).unwrap();
+        assert!(!policy.is_empty());
+
+        assert!(matches!(
+            Policy::from_file("/path/not/present/policy.json").unwrap_err(),
+            Error::FileReadFailed(..)
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+// Limits of the adapters, and splitting of the transfers to fit them
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use thiserror::Error as ThisError;
+
+use crate::i2c::{self, I2cReq, I2C_M_RD};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Maximum number of messages of an I2C_RDWR call, I2C_RDWR_IOCTL_MAX_MSGS in Linux.
+pub const I2C_RDWR_MAX_MSGS: usize = 42;
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the adapter quirks
+pub enum Error {
+    #[error("Invalid adapter quirk: {0}")]
+    OptionInvalid(String),
+    #[error("Missing adapter of the quirks, adapter=<bus>: {0}")]
+    AdapterMissing(String),
+}
+
+/// Limits of the transfers of an adapter, on top of the ones of the kernel. Linux doesn't
+/// report the quirks of the adapter drivers to user space, they have to be configured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    // Maximum number of messages per transfer.
+    pub max_msgs: usize,
+    // Maximum length of a message.
+    pub max_len: Option<u16>,
+    // Whether the adapter can issue a repeated START, between the messages of a transfer.
+    pub combined: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            max_msgs: I2C_RDWR_MAX_MSGS,
+            max_len: None,
+            combined: true,
+        }
+    }
+}
+
+impl Quirks {
+    /// Splits a batch of messages into RDWR transfers fitting the limits, and returns their
+    /// ranges. The batch is only split between transactions, never in the middle of one: a read
+    /// continues the transaction of the message before it, when addressed to the same client,
+    /// e.g. the write of a register number followed by the read of its value.
+    ///
+    /// Nothing is returned unless the whole batch fits, so that none of it is transferred.
+    pub fn split(&self, reqs: &[I2cReq]) -> std::result::Result<Vec<Range<usize>>, i2c::Error> {
+        let max_msgs = if self.combined { self.max_msgs } else { 1 };
+        let mut transfers: Vec<Range<usize>> = Vec::new();
+        let mut start = 0;
+
+        for (i, req) in reqs.iter().enumerate() {
+            if let Some(max_len) = self.max_len {
+                if req.len > max_len {
+                    return Err(i2c::Error::MessageTooLong(req.len, max_len));
+                }
+            }
+
+            let last = i + 1 == reqs.len();
+            if !last && reqs[i + 1].flags & I2C_M_RD != 0 && reqs[i + 1].addr == req.addr {
+                continue;
+            }
+
+            // The transaction ends with this message.
+            let transaction = start..i + 1;
+            if transaction.len() > max_msgs {
+                return Err(if self.combined {
+                    i2c::Error::TransactionTooLong(transaction.len(), max_msgs)
+                } else {
+                    i2c::Error::CombinedTransferUnsupported(transaction.len())
+                });
+            }
+
+            match transfers.last_mut() {
+                Some(transfer) if transfer.len() + transaction.len() <= max_msgs => {
+                    transfer.end = transaction.end
+                }
+                _ => transfers.push(transaction),
+            }
+            start = i + 1;
+        }
+
+        Ok(transfers)
+    }
+}
+
+/// Quirks of an adapter, in the format:
+///
+///   adapter=<bus>[,max-msgs=<count>][,max-len=<bytes>][,no-combined]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuirksConfig {
+    pub adapter_no: u32,
+    pub quirks: Quirks,
+}
+
+impl TryFrom<&str> for QuirksConfig {
+    type Error = Error;
+
+    fn try_from(options: &str) -> Result<Self> {
+        let mut adapter_no = None;
+        let mut quirks = Quirks::default();
+
+        for option in options.split(',') {
+            let (key, value) = match option.find('=') {
+                Some(pos) => (&option[..pos], Some(&option[pos + 1..])),
+                None => (option, None),
+            };
+            let invalid = || Error::OptionInvalid(option.to_string());
+
+            match (key, value) {
+                ("adapter", Some(value)) => {
+                    adapter_no = Some(value.parse::<u32>().map_err(|_| invalid())?);
+                }
+                // The kernel limit can't be raised.
+                ("max-msgs", Some(value)) => {
+                    quirks.max_msgs = value
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|max| *max > 0 && *max <= I2C_RDWR_MAX_MSGS)
+                        .ok_or_else(invalid)?;
+                }
+                ("max-len", Some(value)) => {
+                    quirks.max_len = Some(
+                        value
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|max| *max > 0)
+                            .ok_or_else(invalid)?,
+                    );
+                }
+                ("no-combined", None) => quirks.combined = false,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(QuirksConfig {
+            adapter_no: adapter_no.ok_or_else(|| Error::AdapterMissing(options.to_string()))?,
+            quirks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(addr: u16, flags: u16, len: u16) -> I2cReq {
+        I2cReq {
+            addr,
+            flags,
+            len,
+            buf: vec![0; len as usize].into(),
+        }
+    }
+
+    // A register read: the write of the register number, and the read of its value.
+    fn register_read(addr: u16) -> Vec<I2cReq> {
+        vec![req(addr, 0, 1), req(addr, I2C_M_RD, 2)]
+    }
+
+    #[test]
+    fn test_quirks_config() {
+        assert_eq!(
+            QuirksConfig::try_from("adapter=1").unwrap(),
+            QuirksConfig {
+                adapter_no: 1,
+                quirks: Quirks::default(),
+            }
+        );
+        assert_eq!(
+            QuirksConfig::try_from("adapter=3,max-msgs=2,max-len=255,no-combined").unwrap(),
+            QuirksConfig {
+                adapter_no: 3,
+                quirks: Quirks {
+                    max_msgs: 2,
+                    max_len: Some(255),
+                    combined: false,
+                },
+            }
+        );
+
+        assert_eq!(
+            QuirksConfig::try_from("max-len=32").unwrap_err(),
+            Error::AdapterMissing("max-len=32".to_string())
+        );
+        for options in [
+            "adapter=1,max-msgs=43",
+            "adapter=1,max-msgs=0",
+            "adapter=1,max-len=0",
+            "adapter=1,no-combined=1",
+            "adapter=x",
+            "adapter=1,speed=100",
+        ]
+        .iter()
+        {
+            let option = options.rsplit(',').next().unwrap();
+            assert_eq!(
+                QuirksConfig::try_from(*options).unwrap_err(),
+                Error::OptionInvalid(option.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_kernel_limit() {
+        let quirks = Quirks::default();
+
+        assert_eq!(quirks.split(&[]).unwrap(), vec![]);
+
+        let reqs: Vec<I2cReq> = (0..50).map(|_| req(4, 0, 1)).collect();
+        assert_eq!(quirks.split(&reqs).unwrap(), vec![0..42, 42..50]);
+
+        // A write, and 21 register reads: the last one doesn't fit in the first transfer.
+        let mut reqs = vec![req(4, 0, 1)];
+        for _ in 0..21 {
+            reqs.extend(register_read(4));
+        }
+        assert_eq!(quirks.split(&reqs).unwrap(), vec![0..41, 41..43]);
+
+        // Reads from another client start a new transaction.
+        let mut reqs: Vec<I2cReq> = (0..41).map(|_| req(4, 0, 1)).collect();
+        reqs.push(req(5, I2C_M_RD, 1));
+        reqs.push(req(5, I2C_M_RD, 1));
+        assert_eq!(quirks.split(&reqs).unwrap(), vec![0..41, 41..43]);
+
+        let reqs: Vec<I2cReq> = (0..43).map(|_| req(4, I2C_M_RD, 1)).collect();
+        assert_eq!(
+            quirks.split(&reqs).unwrap_err(),
+            i2c::Error::TransactionTooLong(43, 42)
+        );
+    }
+
+    #[test]
+    fn test_split_adapter_quirks() {
+        let quirks = Quirks {
+            max_msgs: 2,
+            max_len: Some(4),
+            combined: true,
+        };
+
+        let mut reqs = register_read(4);
+        reqs.push(req(4, 0, 4));
+        reqs.extend(register_read(5));
+        assert_eq!(quirks.split(&reqs).unwrap(), vec![0..2, 2..3, 3..5]);
+
+        reqs.push(req(5, 0, 5));
+        assert_eq!(
+            quirks.split(&reqs).unwrap_err(),
+            i2c::Error::MessageTooLong(5, 4)
+        );
+
+        let mut reqs = register_read(4);
+        reqs.push(req(4, I2C_M_RD, 1));
+        assert_eq!(
+            quirks.split(&reqs).unwrap_err(),
+            i2c::Error::TransactionTooLong(3, 2)
+        );
+
+        // Without repeated START, the messages are transferred one by one.
+        let quirks = Quirks {
+            combined: false,
+            ..Default::default()
+        };
+        let reqs = vec![req(4, 0, 1), req(4, 0, 1), req(5, I2C_M_RD, 1)];
+        assert_eq!(quirks.split(&reqs).unwrap(), vec![0..1, 1..2, 2..3]);
+        assert_eq!(
+            quirks.split(&register_read(4)).unwrap_err(),
+            i2c::Error::CombinedTransferUnsupported(2)
+        );
+    }
+}
@@ -0,0 +1,394 @@
+// Rate limiting of transfers, per socket and per adapter
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use log::warn;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use thiserror::Error as ThisError;
+
+use crate::i2c::I2cReq;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Minimum interval between two logs of the same limit being exceeded.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the rate limits
+pub enum Error {
+    #[error("Invalid rate limit option: {0}")]
+    OptionInvalid(String),
+    #[error("Missing rate limit scope, socket=<index> or adapter=<bus>: {0}")]
+    ScopeMissing(String),
+    #[error("Missing rate limit, ops=<rate> or bytes=<rate>: {0}")]
+    LimitMissing(String),
+    #[error("Invalid rate: {0}")]
+    RateInvalid(String),
+}
+
+/// Transfers or guests the limit applies to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    // Every socket, each with its own limit, if not set.
+    Socket(Option<usize>),
+    Adapter(u32),
+}
+
+impl Scope {
+    /// Returns the kind and the index of the scope, used in logs and metrics.
+    pub fn labels(&self) -> (&'static str, String) {
+        match self {
+            Scope::Socket(Some(index)) => ("socket", index.to_string()),
+            Scope::Socket(None) => ("socket", "all".to_string()),
+            Scope::Adapter(adapter_no) => ("adapter", adapter_no.to_string()),
+        }
+    }
+}
+
+/// What to do with the transfers over the limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Delay,
+    Reject,
+}
+
+/// Rate limit configuration, in the format:
+///
+///   socket=<index>|all|adapter=<bus>[,ops=<rate>][,bytes=<rate>][,mode=delay|reject]
+///
+/// The rates are per second, and up to a second worth of transfers can be issued at once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    pub scope: Scope,
+    pub ops: Option<u64>,
+    pub bytes: Option<u64>,
+    pub mode: Mode,
+}
+
+impl TryFrom<&str> for RateLimitConfig {
+    type Error = Error;
+
+    fn try_from(options: &str) -> Result<Self> {
+        let mut scope = None;
+        let mut ops = None;
+        let mut bytes = None;
+        let mut mode = Mode::Delay;
+
+        let rate = |value: &str| {
+            value
+                .parse::<u64>()
+                .ok()
+                .filter(|rate| *rate > 0)
+                .ok_or_else(|| Error::RateInvalid(value.to_string()))
+        };
+
+        for option in options.split(',') {
+            let (key, value) = match option.find('=') {
+                Some(pos) => (&option[..pos], &option[pos + 1..]),
+                None => return Err(Error::OptionInvalid(option.to_string())),
+            };
+
+            match (key, value) {
+                ("socket", "all") => scope = Some(Scope::Socket(None)),
+                ("socket", _) => {
+                    let index = value
+                        .parse::<usize>()
+                        .map_err(|_| Error::OptionInvalid(option.to_string()))?;
+                    scope = Some(Scope::Socket(Some(index)));
+                }
+                ("adapter", _) => {
+                    let adapter_no = value
+                        .parse::<u32>()
+                        .map_err(|_| Error::OptionInvalid(option.to_string()))?;
+                    scope = Some(Scope::Adapter(adapter_no));
+                }
+                ("ops", _) => ops = Some(rate(value)?),
+                ("bytes", _) => bytes = Some(rate(value)?),
+                ("mode", "delay") => mode = Mode::Delay,
+                ("mode", "reject") => mode = Mode::Reject,
+                _ => return Err(Error::OptionInvalid(option.to_string())),
+            }
+        }
+
+        let scope = scope.ok_or_else(|| Error::ScopeMissing(options.to_string()))?;
+
+        if ops.is_none() && bytes.is_none() {
+            return Err(Error::LimitMissing(options.to_string()));
+        }
+
+        Ok(RateLimitConfig {
+            scope,
+            ops,
+            bytes,
+            mode,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+    }
+
+    // Time to wait for `cost` tokens, capped to the size of the bucket so that larger
+    // transfers still go through.
+    fn wait(&self, cost: f64) -> Duration {
+        let missing = cost.min(self.rate) - self.tokens;
+
+        if missing > 0.0 {
+            Duration::from_secs_f64(missing / self.rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.tokens -= cost.min(self.rate);
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    last_refill: Instant,
+    last_log: Option<Instant>,
+}
+
+/// Token bucket rate limiter, on the number of transfers and bytes per second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    scope: Scope,
+    mode: Mode,
+    inner: Mutex<Buckets>,
+    delayed: AtomicU64,
+    rejected: AtomicU64,
+    delay_us: AtomicU64,
+}
+
+impl RateLimiter {
+    /// Creates the limiter of `config` for `scope`, the socket index is required for the
+    /// socket limits.
+    pub fn new(config: &RateLimitConfig, scope: Scope) -> Self {
+        RateLimiter {
+            scope,
+            mode: config.mode,
+            inner: Mutex::new(Buckets {
+                ops: config.ops.map(TokenBucket::new),
+                bytes: config.bytes.map(TokenBucket::new),
+                last_refill: Instant::now(),
+                last_log: None,
+            }),
+            delayed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            delay_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn scope(&self) -> Scope {
+        self.scope
+    }
+
+    /// Accounts for a transfer of `reqs`, which is delayed as long as required in the delay
+    /// mode. Returns false if the transfer is rejected instead.
+    pub fn acquire(&self, reqs: &[I2cReq]) -> bool {
+        let bytes = reqs.iter().map(|req| req.len as f64).sum();
+
+        let wait = {
+            let mut guard = self.inner.lock().unwrap();
+            let inner = &mut *guard;
+            let now = Instant::now();
+            let elapsed = now.duration_since(inner.last_refill);
+            let mut wait = Duration::from_secs(0);
+
+            inner.last_refill = now;
+
+            if let Some(bucket) = inner.ops.as_mut() {
+                bucket.refill(elapsed);
+                wait = wait.max(bucket.wait(1.0));
+            }
+            if let Some(bucket) = inner.bytes.as_mut() {
+                bucket.refill(elapsed);
+                wait = wait.max(bucket.wait(bytes));
+            }
+
+            if wait > Duration::from_secs(0) {
+                if inner
+                    .last_log
+                    .is_none_or(|last| last.elapsed() >= LOG_INTERVAL)
+                {
+                    let (kind, index) = self.scope.labels();
+
+                    inner.last_log = Some(now);
+                    warn!(
+                        "Rate limit of {} {} exceeded, {} transfers",
+                        kind,
+                        index,
+                        match self.mode {
+                            Mode::Delay => "delaying",
+                            Mode::Reject => "rejecting",
+                        }
+                    );
+                }
+
+                if self.mode == Mode::Reject {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+            }
+
+            // The tokens are reserved, the transfers delayed concurrently are serialized.
+            if let Some(bucket) = inner.ops.as_mut() {
+                bucket.take(1.0);
+            }
+            if let Some(bucket) = inner.bytes.as_mut() {
+                bucket.take(bytes);
+            }
+            wait
+        };
+
+        if wait > Duration::from_secs(0) {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+            self.delay_us
+                .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+            sleep(wait);
+        }
+        true
+    }
+
+    /// Returns the number of delayed and rejected transfers, and the total delay.
+    pub fn counters(&self) -> (u64, u64, Duration) {
+        (
+            self.delayed.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            Duration::from_micros(self.delay_us.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reqs(len: u16) -> Vec<I2cReq> {
+        vec![I2cReq {
+            addr: 4,
+            flags: 0,
+            len,
+            buf: vec![0; len as usize].into(),
+        }]
+    }
+
+    #[test]
+    fn test_rate_limit_parse() {
+        assert_eq!(
+            RateLimitConfig::try_from("socket=1,ops=100").unwrap(),
+            RateLimitConfig {
+                scope: Scope::Socket(Some(1)),
+                ops: Some(100),
+                bytes: None,
+                mode: Mode::Delay,
+            }
+        );
+        assert_eq!(
+            RateLimitConfig::try_from("adapter=3,bytes=4096,ops=10,mode=reject").unwrap(),
+            RateLimitConfig {
+                scope: Scope::Adapter(3),
+                ops: Some(10),
+                bytes: Some(4096),
+                mode: Mode::Reject,
+            }
+        );
+        assert_eq!(
+            RateLimitConfig::try_from("socket=all,bytes=100")
+                .unwrap()
+                .scope,
+            Scope::Socket(None)
+        );
+
+        assert_eq!(
+            RateLimitConfig::try_from("ops=100").unwrap_err(),
+            Error::ScopeMissing("ops=100".to_string())
+        );
+        assert_eq!(
+            RateLimitConfig::try_from("socket=0,mode=reject").unwrap_err(),
+            Error::LimitMissing("socket=0,mode=reject".to_string())
+        );
+        assert_eq!(
+            RateLimitConfig::try_from("socket=0,ops=0").unwrap_err(),
+            Error::RateInvalid("0".to_string())
+        );
+        assert_eq!(
+            RateLimitConfig::try_from("socket=x,ops=1").unwrap_err(),
+            Error::OptionInvalid("socket=x".to_string())
+        );
+        assert_eq!(
+            RateLimitConfig::try_from("socket=0,ops=1,mode=drop").unwrap_err(),
+            Error::OptionInvalid("mode=drop".to_string())
+        );
+        assert_eq!(
+            RateLimitConfig::try_from("socket=0,ops").unwrap_err(),
+            Error::OptionInvalid("ops".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_reject() {
+        let config = RateLimitConfig::try_from("adapter=1,ops=2,bytes=100,mode=reject").unwrap();
+        let limiter = RateLimiter::new(&config, config.scope);
+
+        // A second worth of transfers go through at once.
+        assert!(limiter.acquire(&reqs(10)));
+        assert!(limiter.acquire(&reqs(10)));
+        assert!(!limiter.acquire(&reqs(10)));
+
+        let limiter = RateLimiter::new(&config, config.scope);
+        assert!(limiter.acquire(&reqs(80)));
+        assert!(!limiter.acquire(&reqs(80)));
+
+        let (delayed, rejected, delay) = limiter.counters();
+        assert_eq!((delayed, rejected), (0, 1));
+        assert_eq!(delay, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_rate_limiter_delay() {
+        let config = RateLimitConfig::try_from("socket=0,ops=100").unwrap();
+        let limiter = RateLimiter::new(&config, Scope::Socket(Some(0)));
+        let start = Instant::now();
+
+        for _ in 0..110 {
+            assert!(limiter.acquire(&reqs(1)));
+        }
+
+        // The last 10 transfers are delayed by 10ms each.
+        assert!(start.elapsed() >= Duration::from_millis(90));
+
+        let (delayed, rejected, delay) = limiter.counters();
+        assert!(delayed >= 9);
+        assert_eq!(rejected, 0);
+        assert!(delay >= Duration::from_millis(80));
+        assert_eq!(limiter.scope().labels(), ("socket", "0".to_string()));
+    }
+}
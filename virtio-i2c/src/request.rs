@@ -0,0 +1,537 @@
+// Decoding and completion of the virtio-i2c requests
+//
+// Copyright 2021 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::mem::size_of;
+use std::ops::Deref;
+
+use thiserror::Error as ThisError;
+use virtio_queue::DescriptorChain;
+use vm_memory::{ByteValued, Bytes, GuestMemory, Le16, Le32};
+
+use crate::i2c::{self, I2cBuf, I2cReq, I2C_M_RD};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Copy, Clone, Debug, PartialEq, ThisError)]
+/// Errors related to the virtio-i2c requests
+pub enum Error {
+    #[error("Received unexpected write only descriptor at index {0}")]
+    UnexpectedWriteOnlyDescriptor(usize),
+    #[error("Received unexpected readable descriptor at index {0}")]
+    UnexpectedReadableDescriptor(usize),
+    #[error("Invalid descriptor count {0}")]
+    UnexpectedDescriptorCount(usize),
+    #[error("Invalid descriptor size, expected: {0}, found: {1}")]
+    UnexpectedDescriptorSize(usize, u32),
+    #[error("Descriptor read failed")]
+    DescriptorReadFailed,
+    #[error("Descriptor write failed")]
+    DescriptorWriteFailed,
+}
+
+// I2C definitions from Virtio Spec
+
+/// The final status written by the device
+pub const VIRTIO_I2C_MSG_OK: u8 = 0;
+pub const VIRTIO_I2C_MSG_ERR: u8 = 1;
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct VirtioI2cOutHdr {
+    pub addr: Le16,
+    pub padding: Le16,
+    pub flags: Le32,
+}
+unsafe impl ByteValued for VirtioI2cOutHdr {}
+
+/// VirtioI2cOutHdr Flags
+pub const VIRTIO_I2C_FLAGS_M_RD: u32 = 1 << 1;
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct VirtioI2cInHdr {
+    pub status: u8,
+}
+unsafe impl ByteValued for VirtioI2cInHdr {}
+
+/// Decodes the I2C message of a descriptor chain: an out header, an optional buffer, and an in
+/// header for the status. The buffer of write messages is read from the guest memory.
+pub fn parse_request<M>(desc_chain: &DescriptorChain<M>) -> Result<I2cReq>
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    decode_request(desc_chain, false)
+}
+
+/// Same as [`parse_request`], except that the buffer of a read message points straight at the
+/// guest memory when it's contiguous there, for the RDWR transfers. The data read is then written
+/// to the guest by the transfer itself. The buffer of a write message is always copied, so that
+/// the data checked against the access policy is the data transferred.
+///
+/// # Safety
+///
+/// The guest memory of the descriptor chain must stay mapped for as long as the message is
+/// alive, e.g. by keeping the descriptor chain, and with it the guard of the memory, around.
+pub unsafe fn parse_request_zero_copy<M>(desc_chain: &DescriptorChain<M>) -> Result<I2cReq>
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    decode_request(desc_chain, true)
+}
+
+fn decode_request<M>(desc_chain: &DescriptorChain<M>, zero_copy: bool) -> Result<I2cReq>
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    let descriptors: Vec<_> = desc_chain.clone().collect();
+
+    if (descriptors.len() != 2) && (descriptors.len() != 3) {
+        return Err(Error::UnexpectedDescriptorCount(descriptors.len()));
+    }
+
+    let desc_out_hdr = descriptors[0];
+
+    if desc_out_hdr.is_write_only() {
+        return Err(Error::UnexpectedWriteOnlyDescriptor(0));
+    }
+
+    if desc_out_hdr.len() as usize != size_of::<VirtioI2cOutHdr>() {
+        return Err(Error::UnexpectedDescriptorSize(
+            size_of::<VirtioI2cOutHdr>(),
+            desc_out_hdr.len(),
+        ));
+    }
+
+    let out_hdr = desc_chain
+        .memory()
+        .read_obj::<VirtioI2cOutHdr>(desc_out_hdr.addr())
+        .map_err(|_| Error::DescriptorReadFailed)?;
+
+    let flags = match out_hdr.flags.to_native() & VIRTIO_I2C_FLAGS_M_RD {
+        VIRTIO_I2C_FLAGS_M_RD => I2C_M_RD,
+        _ => 0,
+    };
+
+    let desc_in_hdr = descriptors[descriptors.len() - 1];
+    if !desc_in_hdr.is_write_only() {
+        return Err(Error::UnexpectedReadableDescriptor(descriptors.len() - 1));
+    }
+
+    if desc_in_hdr.len() as usize != size_of::<u8>() {
+        return Err(Error::UnexpectedDescriptorSize(
+            size_of::<u8>(),
+            desc_in_hdr.len(),
+        ));
+    }
+
+    let (buf, len) = match descriptors.len() {
+        // Buffer is available
+        3 => {
+            let desc_buf = descriptors[1];
+            let len = desc_buf.len();
+
+            if len == 0 {
+                return Err(Error::UnexpectedDescriptorSize(1, len));
+            }
+
+            // The length of the messages is 16 bits wide, don't truncate it, nor
+            // allocate more than that for the guest.
+            if len > u16::MAX as u32 {
+                return Err(Error::UnexpectedDescriptorSize(u16::MAX as usize, len));
+            }
+
+            if flags != I2C_M_RD {
+                if desc_buf.is_write_only() {
+                    return Err(Error::UnexpectedWriteOnlyDescriptor(1));
+                }
+            } else if !desc_buf.is_write_only() {
+                return Err(Error::UnexpectedReadableDescriptor(1));
+            }
+
+            // Only the buffers of read messages are used in place. The data written is copied,
+            // the guest could otherwise change it after the access policy checked it. The slice
+            // is only available when the buffer doesn't cross the end of a memory region, fall
+            // back to a copy otherwise.
+            let slice = if zero_copy && flags == I2C_M_RD {
+                desc_chain
+                    .memory()
+                    .get_slice(desc_buf.addr(), len as usize)
+                    .ok()
+            } else {
+                None
+            };
+
+            let buf = match slice {
+                // Safe as the caller of parse_request_zero_copy() keeps the memory mapped.
+                Some(slice) => unsafe { I2cBuf::from_guest(slice.as_ptr(), slice.len()) },
+                None => {
+                    let mut buf = vec![0; len as usize];
+
+                    if flags != I2C_M_RD {
+                        desc_chain
+                            .memory()
+                            .read(&mut buf, desc_buf.addr())
+                            .map_err(|_| Error::DescriptorReadFailed)?;
+                    }
+                    buf.into()
+                }
+            };
+
+            (buf, len)
+        }
+
+        _ => (I2cBuf::new(0), 0),
+    };
+
+    Ok(I2cReq {
+        addr: out_hdr.addr.to_native() >> 1,
+        flags,
+        len: len as u16,
+        buf,
+    })
+}
+
+/// Decodes the messages of a transfer, one per descriptor chain.
+pub fn parse_requests<M>(desc_chains: &[DescriptorChain<M>]) -> Result<Vec<I2cReq>>
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    desc_chains.iter().map(parse_request).collect()
+}
+
+/// Same as [`parse_requests`], with the buffers of [`parse_request_zero_copy`].
+///
+/// # Safety
+///
+/// The descriptor chains must outlive the messages, see [`parse_request_zero_copy`].
+pub unsafe fn parse_requests_zero_copy<M>(desc_chains: &[DescriptorChain<M>]) -> Result<Vec<I2cReq>>
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    desc_chains
+        .iter()
+        .map(|desc_chain| parse_request_zero_copy(desc_chain))
+        .collect()
+}
+
+/// Completes a message decoded by [`parse_request`] with the result of its transfer: the data
+/// read is written back to the guest memory along with the status. Returns the number of bytes
+/// written, for the used ring.
+pub fn complete_request<M>(
+    desc_chain: &DescriptorChain<M>,
+    req: &I2cReq,
+    result: &std::result::Result<(), i2c::Error>,
+) -> Result<u32>
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    let descriptors: Vec<_> = desc_chain.clone().collect();
+    let desc_in_hdr = descriptors[descriptors.len() - 1];
+    let mut len = size_of::<VirtioI2cInHdr>() as u32;
+    let in_hdr = VirtioI2cInHdr {
+        status: match result {
+            Ok(()) => VIRTIO_I2C_MSG_OK,
+            Err(_) => VIRTIO_I2C_MSG_ERR,
+        },
+    };
+
+    if descriptors.len() == 3 {
+        let desc_buf = descriptors[1];
+
+        // Write the data read from the I2C device, unless it was read in place.
+        if req.flags == I2C_M_RD && !req.buf.is_guest() {
+            desc_chain
+                .memory()
+                .write(&req.buf.to_vec(), desc_buf.addr())
+                .map_err(|_| Error::DescriptorWriteFailed)?;
+        }
+
+        if in_hdr.status == VIRTIO_I2C_MSG_OK {
+            len += desc_buf.len();
+        }
+    }
+
+    // Write the transfer status
+    desc_chain
+        .memory()
+        .write_obj::<VirtioI2cInHdr>(in_hdr, desc_in_hdr.addr())
+        .map_err(|_| Error::DescriptorWriteFailed)?;
+
+    Ok(len)
+}
+
+/// Completes a descriptor chain that [`parse_request`] refused with VIRTIO_I2C_MSG_ERR, when
+/// its last descriptor can hold the status. Returns the number of bytes written, for the used
+/// ring, none if the status couldn't be written.
+pub fn fail_request<M>(desc_chain: &DescriptorChain<M>) -> u32
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    let desc_in_hdr = match desc_chain.clone().last() {
+        Some(desc) => desc,
+        None => return 0,
+    };
+
+    if !desc_in_hdr.is_write_only() || (desc_in_hdr.len() as usize) < size_of::<VirtioI2cInHdr>() {
+        return 0;
+    }
+
+    let in_hdr = VirtioI2cInHdr {
+        status: VIRTIO_I2C_MSG_ERR,
+    };
+
+    match desc_chain
+        .memory()
+        .write_obj::<VirtioI2cInHdr>(in_hdr, desc_in_hdr.addr())
+    {
+        Ok(()) => size_of::<VirtioI2cInHdr>() as u32,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    use virtio_queue::defs::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use virtio_queue::{mock::MockSplitQueue, Descriptor};
+    use vm_memory::{
+        Address, GuestAddress, GuestMemoryAtomic, GuestMemoryLoadGuard, GuestMemoryMmap,
+    };
+
+    use crate::config::AdapterConfig;
+    use crate::i2c::mock::{update_rdwr_buf, verify_rdwr_buf, DummyDevice};
+    use crate::i2c::{Error as I2cError, I2cMap};
+
+    type I2cDescriptorChain = DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap<()>>>;
+
+    // Prepares a chain with the descriptors at 0x400, 0x500 and so on, after the queue, once
+    // their data is stored.
+    fn prepare_desc_chain(descs: &[(&[u8], u32, u16)]) -> I2cDescriptorChain {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let vq = MockSplitQueue::new(&mem, 16);
+
+        for (i, (data, len, flags)) in descs.iter().enumerate() {
+            let addr = 0x400 + 0x100 * i as u64;
+            let next = if i == descs.len() - 1 {
+                0
+            } else {
+                VIRTQ_DESC_F_NEXT
+            };
+
+            mem.write_slice(data, GuestAddress(addr)).unwrap();
+            vq.desc_table().store(
+                i as u16,
+                Descriptor::new(addr, *len, flags | next, i as u16 + 1),
+            );
+        }
+
+        // Put the descriptor index 0 in the first available ring position.
+        mem.write_obj(0u16, vq.avail_addr().unchecked_add(4))
+            .unwrap();
+
+        // Set `avail_idx` to 1.
+        mem.write_obj(1u16, vq.avail_addr().unchecked_add(2))
+            .unwrap();
+
+        vq.create_queue(GuestMemoryAtomic::<GuestMemoryMmap>::new(mem.clone()))
+            .iter()
+            .unwrap()
+            .next()
+            .unwrap()
+    }
+
+    fn out_hdr(addr: u16, flags: u32) -> VirtioI2cOutHdr {
+        VirtioI2cOutHdr {
+            addr: From::from(addr << 1),
+            padding: From::from(0),
+            flags: From::from(flags),
+        }
+    }
+
+    #[test]
+    fn test_parse_complete_request() {
+        let adapter_config = AdapterConfig::try_from("1:4").unwrap();
+        let i2c_map = I2cMap::<DummyDevice>::new(&adapter_config).unwrap();
+        let hdr_len = size_of::<VirtioI2cOutHdr>() as u32;
+
+        let status: &[u8] = &[0xff];
+        let mut data = vec![0; 8];
+
+        // Write
+        update_rdwr_buf(&mut data);
+        let write = prepare_desc_chain(&[
+            (out_hdr(4, 0).as_slice(), hdr_len, 0),
+            (data.as_slice(), 8, 0),
+            (status, 1, VIRTQ_DESC_F_WRITE),
+        ]);
+
+        // Read
+        let read = prepare_desc_chain(&[
+            (out_hdr(4, VIRTIO_I2C_FLAGS_M_RD).as_slice(), hdr_len, 0),
+            (&[0; 8][..], 8, VIRTQ_DESC_F_WRITE),
+            (status, 1, VIRTQ_DESC_F_WRITE),
+        ]);
+
+        let chains = vec![write, read];
+        let mut reqs = parse_requests(&chains).unwrap();
+        assert_eq!(reqs[0].addr, 4);
+        assert_eq!(reqs[0].flags, 0);
+        assert_eq!(reqs[0].buf, data);
+        assert_eq!(reqs[1].flags, I2C_M_RD);
+        assert_eq!(reqs[1].len, 8);
+
+        let result = i2c_map.transfer(&mut reqs);
+        for (chain, req) in chains.iter().zip(reqs.iter()) {
+            assert_eq!(complete_request(chain, req, &result).unwrap(), 9);
+        }
+
+        let mut buf = vec![0; 8];
+        chains[1]
+            .memory()
+            .read_slice(&mut buf, GuestAddress(0x500))
+            .unwrap();
+        verify_rdwr_buf(&buf);
+        assert_eq!(
+            chains[1]
+                .memory()
+                .read_obj::<u8>(GuestAddress(0x600))
+                .unwrap(),
+            VIRTIO_I2C_MSG_OK
+        );
+
+        // Failed transfers only report the status.
+        let result = Err(I2cError::ClientAddressInvalid);
+        assert_eq!(complete_request(&chains[0], &reqs[0], &result).unwrap(), 1);
+        assert_eq!(
+            chains[0]
+                .memory()
+                .read_obj::<u8>(GuestAddress(0x600))
+                .unwrap(),
+            VIRTIO_I2C_MSG_ERR
+        );
+    }
+
+    #[test]
+    fn test_parse_request_zero_copy() {
+        let adapter_config = AdapterConfig::try_from("1:4").unwrap();
+        let i2c_map = I2cMap::<DummyDevice>::new(&adapter_config).unwrap();
+        let hdr_len = size_of::<VirtioI2cOutHdr>() as u32;
+
+        let status: &[u8] = &[0xff];
+        let mut data = vec![0; 8];
+
+        update_rdwr_buf(&mut data);
+        let write = prepare_desc_chain(&[
+            (out_hdr(4, 0).as_slice(), hdr_len, 0),
+            (data.as_slice(), 8, 0),
+            (status, 1, VIRTQ_DESC_F_WRITE),
+        ]);
+        let read = prepare_desc_chain(&[
+            (out_hdr(4, VIRTIO_I2C_FLAGS_M_RD).as_slice(), hdr_len, 0),
+            (&[0; 8][..], 8, VIRTQ_DESC_F_WRITE),
+            (status, 1, VIRTQ_DESC_F_WRITE),
+        ]);
+
+        let chains = vec![write, read];
+        // Safe as the chains outlive the messages.
+        let mut reqs = unsafe { parse_requests_zero_copy(&chains) }.unwrap();
+        assert!(!reqs[0].buf.is_guest());
+        assert!(reqs[1].buf.is_guest());
+        assert_eq!(reqs[0].buf, data);
+
+        // The data read lands in the guest memory with the transfer itself.
+        let result = i2c_map.transfer(&mut reqs);
+        let mut buf = vec![0; 8];
+        chains[1]
+            .memory()
+            .read_slice(&mut buf, GuestAddress(0x500))
+            .unwrap();
+        verify_rdwr_buf(&buf);
+
+        for (chain, req) in chains.iter().zip(reqs.iter()) {
+            assert_eq!(complete_request(chain, req, &result).unwrap(), 9);
+        }
+        verify_rdwr_buf(&reqs[1].buf.to_vec());
+
+        // A buffer going past the end of the memory region is copied instead.
+        let chain = prepare_desc_chain(&[
+            (out_hdr(4, VIRTIO_I2C_FLAGS_M_RD).as_slice(), hdr_len, 0),
+            (&[0; 8][..], 0xc00, VIRTQ_DESC_F_WRITE),
+            (status, 1, VIRTQ_DESC_F_WRITE),
+        ]);
+        // Safe as the chain outlives the message.
+        let req = unsafe { parse_request_zero_copy(&chain) }.unwrap();
+        assert!(!req.buf.is_guest());
+        assert_eq!(req.buf.len(), 0xc00);
+    }
+
+    #[test]
+    fn test_parse_request_failure() {
+        let hdr_len = size_of::<VirtioI2cOutHdr>() as u32;
+        let hdr = out_hdr(4, 0);
+        let byte: &[u8] = &[0];
+
+        let chain = prepare_desc_chain(&[(hdr.as_slice(), hdr_len, 0)]);
+        assert_eq!(
+            parse_request(&chain).err(),
+            Some(Error::UnexpectedDescriptorCount(1))
+        );
+        // No room for the status.
+        assert_eq!(fail_request(&chain), 0);
+
+        let chain = prepare_desc_chain(&[
+            (hdr.as_slice(), hdr_len, VIRTQ_DESC_F_WRITE),
+            (byte, 1, VIRTQ_DESC_F_WRITE),
+        ]);
+        assert_eq!(
+            parse_request(&chain).err(),
+            Some(Error::UnexpectedWriteOnlyDescriptor(0))
+        );
+
+        let chain = prepare_desc_chain(&[(hdr.as_slice(), hdr_len, 0), (byte, 1, 0)]);
+        assert_eq!(
+            parse_request(&chain).err(),
+            Some(Error::UnexpectedReadableDescriptor(1))
+        );
+
+        let chain = prepare_desc_chain(&[
+            (hdr.as_slice(), hdr_len, 0),
+            (&[][..], 0x10000, 0),
+            (byte, 1, VIRTQ_DESC_F_WRITE),
+        ]);
+        assert_eq!(
+            parse_request(&chain).err(),
+            Some(Error::UnexpectedDescriptorSize(u16::MAX as usize, 0x10000))
+        );
+
+        // Readable buffer for a read
+        let hdr = out_hdr(4, VIRTIO_I2C_FLAGS_M_RD);
+        let chain = prepare_desc_chain(&[
+            (hdr.as_slice(), hdr_len, 0),
+            (byte, 1, 0),
+            (byte, 1, VIRTQ_DESC_F_WRITE),
+        ]);
+        assert_eq!(
+            parse_request(&chain).err(),
+            Some(Error::UnexpectedReadableDescriptor(1))
+        );
+        assert_eq!(fail_request(&chain), 1);
+        assert_eq!(
+            chain.memory().read_obj::<u8>(GuestAddress(0x600)).unwrap(),
+            VIRTIO_I2C_MSG_ERR
+        );
+    }
+}